@@ -0,0 +1,659 @@
+use std::error::Error;
+use std::ffi::OsStr;
+use std::path::{Component, Path, PathBuf};
+use std::process::ExitCode;
+
+use clap::{Parser, Subcommand, ValueEnum};
+use serde::Serialize;
+use stormlib::name::{file_name_to_name, name_to_file_name};
+use stormlib::*;
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+/// Inspect and modify MPQ archives
+#[derive(Parser)]
+#[command(name = "mpq", version)]
+struct Cli {
+  /// Print results as JSON
+  #[arg(long, global = true)]
+  json: bool,
+
+  /// Windows codepage of the file names stored in the archive, e.g. 936 for GBK
+  #[arg(long, global = true, default_value = "65001", value_parser = parse_codepage)]
+  codepage: Codepage,
+
+  #[command(subcommand)]
+  command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+  /// List files with their sizes, flags and locale
+  List {
+    archive: PathBuf,
+    /// Wildcard mask, e.g. `*.j`
+    #[arg(short, long)]
+    filter: Option<String>,
+  },
+  /// Extract files to a directory, all files if no names are given
+  Extract {
+    archive: PathBuf,
+    names: Vec<String>,
+    /// Output directory
+    #[arg(short, long, default_value = ".")]
+    output: PathBuf,
+  },
+  /// Add local files or directories to the archive
+  Add {
+    archive: PathBuf,
+    #[arg(required = true)]
+    files: Vec<PathBuf>,
+    /// Archive directory the files are added to
+    #[arg(long)]
+    prefix: Option<String>,
+    #[arg(long, value_enum, default_value = "zlib")]
+    compression: Compression,
+    /// Encrypt the files, with the key adjusted by the file position
+    #[arg(long)]
+    encrypt: bool,
+    /// Replace files that already exist
+    #[arg(long)]
+    replace: bool,
+  },
+  /// Remove files from the archive
+  Remove {
+    archive: PathBuf,
+    #[arg(required = true)]
+    names: Vec<String>,
+  },
+  /// Rename a file within the archive
+  Rename {
+    archive: PathBuf,
+    old_name: String,
+    new_name: String,
+  },
+  /// Verify file checksums and the archive signature, all files if no names are given
+  Verify {
+    archive: PathBuf,
+    names: Vec<String>,
+  },
+  /// Print archive header and table information
+  Info { archive: PathBuf },
+  /// Rebuild the archive, removing unused space
+  Compact { archive: PathBuf },
+  /// Create a new archive, optionally adding files to it
+  Create {
+    archive: PathBuf,
+    files: Vec<PathBuf>,
+    #[arg(long, value_enum, default_value = "v1")]
+    format: Format,
+    /// Maximum number of files
+    #[arg(long, default_value = "1024")]
+    max_files: u32,
+    /// Create the `(attributes)` file
+    #[arg(long)]
+    attributes: bool,
+    #[arg(long, value_enum, default_value = "zlib")]
+    compression: Compression,
+  },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Compression {
+  None,
+  Zlib,
+  Bzip2,
+  Pkware,
+  Lzma,
+}
+
+impl Compression {
+  fn flags(self) -> CompressionFlags {
+    match self {
+      Compression::None => CompressionFlags::empty(),
+      Compression::Zlib => CompressionFlags::MPQ_COMPRESSION_ZLIB,
+      Compression::Bzip2 => CompressionFlags::MPQ_COMPRESSION_BZIP2,
+      Compression::Pkware => CompressionFlags::MPQ_COMPRESSION_PKWARE,
+      Compression::Lzma => CompressionFlags::MPQ_COMPRESSION_LZMA,
+    }
+  }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+  V1,
+  V2,
+  V3,
+  V4,
+}
+
+impl Format {
+  fn flags(self) -> CreateArchiveFlags {
+    match self {
+      Format::V1 => CreateArchiveFlags::MPQ_CREATE_ARCHIVE_V1,
+      Format::V2 => CreateArchiveFlags::MPQ_CREATE_ARCHIVE_V2,
+      Format::V3 => CreateArchiveFlags::MPQ_CREATE_ARCHIVE_V3,
+      Format::V4 => CreateArchiveFlags::MPQ_CREATE_ARCHIVE_V4,
+    }
+  }
+}
+
+fn parse_codepage(s: &str) -> std::result::Result<Codepage, String> {
+  s.parse()
+    .ok()
+    .and_then(Codepage::from_windows_codepage)
+    .ok_or_else(|| format!("unsupported codepage `{}`", s))
+}
+
+fn main() -> ExitCode {
+  let cli = Cli::parse();
+  match run(cli) {
+    Ok(true) => ExitCode::SUCCESS,
+    Ok(false) => ExitCode::FAILURE,
+    Err(err) => {
+      eprintln!("error: {}", err);
+      ExitCode::FAILURE
+    }
+  }
+}
+
+/// Runs the command, returns `false` if it completed but found problems
+fn run(cli: Cli) -> Result<bool> {
+  let json = cli.json;
+  let codepage = cli.codepage;
+
+  match cli.command {
+    Command::List { archive, filter } => {
+      let archive = Archive::open_read_only(&archive, OpenArchiveFlags::empty())?;
+      let entries = list(&archive, filter.as_deref(), codepage)?;
+      if json {
+        print_json(&entries)?;
+      } else {
+        for e in &entries {
+          println!(
+            "{:>10} {:>10} {:<7} {:>5} {}",
+            e.size, e.compressed_size, e.flags, e.locale, e.name
+          );
+        }
+      }
+      archive.close()?;
+    }
+    Command::Extract {
+      archive,
+      names,
+      output,
+    } => {
+      let archive = Archive::open_read_only(&archive, OpenArchiveFlags::empty())?;
+      let names = if names.is_empty() {
+        list(&archive, None, codepage)?
+          .into_iter()
+          .map(|e| MpqPathBuf::from(e.raw_name))
+          .collect()
+      } else {
+        encode_names(&names, codepage)?
+      };
+
+      let mut extracted = vec![];
+      for name in &names {
+        let path = local_path(&output, name, codepage)?;
+        if let Some(parent) = path.parent() {
+          std::fs::create_dir_all(parent)?;
+        }
+        archive.extract_file(name, &path)?;
+        if !json {
+          println!("{}", path.display());
+        }
+        extracted.push(Extracted {
+          name: name.decode(codepage).into_owned(),
+          path: path.to_string_lossy().into_owned(),
+        });
+      }
+      if json {
+        print_json(&extracted)?;
+      }
+      archive.close()?;
+    }
+    Command::Add {
+      archive,
+      files,
+      prefix,
+      compression,
+      encrypt,
+      replace,
+    } => {
+      let mut archive = Archive::open(&archive, OpenArchiveFlags::empty())?;
+      let prefix = match prefix {
+        Some(prefix) => MpqPathBuf::from(codepage.encode(&prefix)?.into_owned()),
+        None => MpqPathBuf::new(),
+      };
+      let added = add(
+        &mut archive,
+        &files,
+        &prefix,
+        codepage,
+        file_flags(compression, encrypt, replace),
+        compression.flags(),
+      )?;
+      print_names(&added, codepage, json)?;
+      archive.close()?;
+    }
+    Command::Remove { archive, names } => {
+      let mut archive = Archive::open(&archive, OpenArchiveFlags::empty())?;
+      let mut report = RemoveReport {
+        removed: vec![],
+        missing: vec![],
+        ok: true,
+      };
+      for name in encode_names(&names, codepage)? {
+        let decoded = name.decode(codepage).into_owned();
+        if archive.remove_file(&name)? {
+          report.removed.push(decoded);
+        } else {
+          report.missing.push(decoded);
+        }
+      }
+      archive.close()?;
+      report.ok = report.missing.is_empty();
+      for name in &report.missing {
+        eprintln!("error: file not found: {}", name);
+      }
+      if json {
+        print_json(&report)?;
+      }
+      return Ok(report.ok);
+    }
+    Command::Rename {
+      archive,
+      old_name,
+      new_name,
+    } => {
+      let mut archive = Archive::open(&archive, OpenArchiveFlags::empty())?;
+      archive.rename_file(
+        MpqPath::new(&*codepage.encode(&old_name)?),
+        MpqPath::new(&*codepage.encode(&new_name)?),
+      )?;
+      archive.close()?;
+      if json {
+        print_json(&RenameReport { old_name, new_name })?;
+      }
+    }
+    Command::Verify { archive, names } => {
+      let archive = Archive::open_read_only(&archive, OpenArchiveFlags::empty())?;
+      let names = if names.is_empty() {
+        list(&archive, None, codepage)?
+          .into_iter()
+          .map(|e| MpqPathBuf::from(e.raw_name))
+          .collect()
+      } else {
+        encode_names(&names, codepage)?
+      };
+
+      let signature = archive.verify_signature()?;
+      let mut report = VerifyReport {
+        signature: signature_name(signature),
+        files: vec![],
+        ok: !signature.is_error(),
+      };
+      for name in &names {
+        let result = archive.verify_file(name, VerifyFileFlags::SFILE_VERIFY_ALL)?;
+        let errors = verify_errors(result);
+        report.ok &= errors.is_empty();
+        report.files.push(VerifiedFile {
+          name: name.decode(codepage).into_owned(),
+          ok: errors.is_empty(),
+          errors,
+        });
+      }
+      archive.close()?;
+
+      if json {
+        print_json(&report)?;
+      } else {
+        println!("signature: {}", report.signature);
+        for f in &report.files {
+          if f.ok {
+            println!("ok     {}", f.name);
+          } else {
+            println!("FAILED {} ({})", f.name, f.errors.join(", "));
+          }
+        }
+      }
+      return Ok(report.ok);
+    }
+    Command::Info { archive } => {
+      let archive = Archive::open_read_only(&archive, OpenArchiveFlags::empty())?;
+      let info = archive.info()?;
+      let report = InfoReport {
+        format_version: info.format_version + 1,
+        header_offset: info.header_offset,
+        archive_size: info.archive_size,
+        sector_size: info.sector_size,
+        file_count: info.file_count,
+        max_file_count: info.max_file_count,
+        hash_table_size: info.hash_table_size,
+        block_table_size: info.block_table_size,
+        has_listfile: archive.has_file("(listfile)")?,
+        has_attributes: archive.has_file("(attributes)")?,
+        signature: signature_name(archive.verify_signature()?),
+      };
+      archive.close()?;
+
+      if json {
+        print_json(&report)?;
+      } else {
+        println!("format version:   {}", report.format_version);
+        println!("header offset:    {:#x}", report.header_offset);
+        println!("archive size:     {}", report.archive_size);
+        println!("sector size:      {}", report.sector_size);
+        println!("files:            {}", report.file_count);
+        println!("max files:        {}", report.max_file_count);
+        println!("hash table size:  {}", report.hash_table_size);
+        println!("block table size: {}", report.block_table_size);
+        println!("listfile:         {}", report.has_listfile);
+        println!("attributes:       {}", report.has_attributes);
+        println!("signature:        {}", report.signature);
+      }
+    }
+    Command::Compact { archive } => {
+      let mut archive = Archive::open(&archive, OpenArchiveFlags::empty())?;
+      let size_before = archive.info()?.archive_size;
+      archive.compact()?;
+      let size_after = archive.info()?.archive_size;
+      archive.close()?;
+      if json {
+        print_json(&CompactReport {
+          size_before,
+          size_after,
+        })?;
+      }
+    }
+    Command::Create {
+      archive,
+      files,
+      format,
+      max_files,
+      attributes,
+      compression,
+    } => {
+      let mut flags = format.flags() | CreateArchiveFlags::MPQ_CREATE_LISTFILE;
+      if attributes {
+        flags |= CreateArchiveFlags::MPQ_CREATE_ATTRIBUTES;
+      }
+      let mut archive = Archive::create(&archive, flags, max_files)?;
+      let added = add(
+        &mut archive,
+        &files,
+        &MpqPathBuf::new(),
+        codepage,
+        file_flags(compression, false, false),
+        compression.flags(),
+      )?;
+      print_names(&added, codepage, json)?;
+      archive.close()?;
+    }
+  }
+
+  Ok(true)
+}
+
+/// File entry printed by `list`
+#[derive(Serialize)]
+struct Entry {
+  name: String,
+  size: u32,
+  compressed_size: u32,
+  flags: String,
+  raw_flags: u32,
+  locale: u32,
+  #[serde(skip)]
+  raw_name: Vec<u8>,
+}
+
+#[derive(Serialize)]
+struct Extracted {
+  name: String,
+  path: String,
+}
+
+#[derive(Serialize)]
+struct RemoveReport {
+  removed: Vec<String>,
+  missing: Vec<String>,
+  ok: bool,
+}
+
+#[derive(Serialize)]
+struct RenameReport {
+  old_name: String,
+  new_name: String,
+}
+
+#[derive(Serialize)]
+struct CompactReport {
+  size_before: u64,
+  size_after: u64,
+}
+
+#[derive(Serialize)]
+struct VerifyReport {
+  signature: &'static str,
+  files: Vec<VerifiedFile>,
+  ok: bool,
+}
+
+#[derive(Serialize)]
+struct VerifiedFile {
+  name: String,
+  ok: bool,
+  errors: Vec<&'static str>,
+}
+
+#[derive(Serialize)]
+struct InfoReport {
+  format_version: u16,
+  header_offset: u64,
+  archive_size: u64,
+  sector_size: u32,
+  file_count: u32,
+  max_file_count: u32,
+  hash_table_size: u32,
+  block_table_size: u32,
+  has_listfile: bool,
+  has_attributes: bool,
+  signature: &'static str,
+}
+
+fn list<M: ArchiveMode>(
+  archive: &Archive<M>,
+  filter: Option<&str>,
+  codepage: Codepage,
+) -> Result<Vec<Entry>> {
+  Ok(
+    archive
+      .search(filter)?
+      .map(|data| Entry {
+        name: data.name(codepage).into_owned(),
+        size: data.dwFileSize,
+        compressed_size: data.dwCompSize,
+        flags: flag_letters(data.dwFileFlags),
+        raw_flags: data.dwFileFlags,
+        locale: data.lcLocale,
+        raw_name: data.name_bytes().to_vec(),
+      })
+      .collect(),
+  )
+}
+
+/// Short form of the file flags, one letter per flag
+fn flag_letters(bits: u32) -> String {
+  let flags = CreateFileFlags::from_bits_truncate(bits);
+  [
+    (CreateFileFlags::MPQ_FILE_IMPLODE, 'i'),
+    (CreateFileFlags::MPQ_FILE_COMPRESS, 'c'),
+    (CreateFileFlags::MPQ_FILE_ENCRYPTED, 'e'),
+    (CreateFileFlags::MPQ_FILE_FIX_KEY, 'k'),
+    (CreateFileFlags::MPQ_FILE_SINGLE_UNIT, 's'),
+    (CreateFileFlags::MPQ_FILE_DELETE_MARKER, 'd'),
+    (CreateFileFlags::MPQ_FILE_SECTOR_CRC, 'r'),
+  ]
+  .iter()
+  .map(|&(flag, c)| if flags.contains(flag) { c } else { '-' })
+  .collect()
+}
+
+fn file_flags(compression: Compression, encrypt: bool, replace: bool) -> CreateFileFlags {
+  let mut flags = CreateFileFlags::empty();
+  if !matches!(compression, Compression::None) {
+    flags |= CreateFileFlags::MPQ_FILE_COMPRESS;
+  }
+  if encrypt {
+    flags |= CreateFileFlags::MPQ_FILE_ENCRYPTED | CreateFileFlags::MPQ_FILE_FIX_KEY;
+  }
+  if replace {
+    flags |= CreateFileFlags::MPQ_FILE_REPLACEEXISTING;
+  }
+  flags
+}
+
+fn encode_names(names: &[String], codepage: Codepage) -> Result<Vec<MpqPathBuf>> {
+  names
+    .iter()
+    .map(|name| Ok(MpqPathBuf::from(codepage.encode(name)?.into_owned())))
+    .collect()
+}
+
+/// Local path a file is extracted to, archive directories become local directories
+fn local_path(root: &Path, name: &MpqPath, codepage: Codepage) -> Result<PathBuf> {
+  let mut path = root.to_path_buf();
+  for component in name.components() {
+    let file_name = decode_file_name(component.as_bytes(), codepage)?;
+    match Path::new(&file_name).components().collect::<Vec<_>>()[..] {
+      [Component::Normal(_)] => path.push(file_name),
+      _ => return Err(format!("refusing to extract `{}`", name.to_string_lossy()).into()),
+    }
+  }
+  Ok(path)
+}
+
+/// Adds files and directories recursively, returns the archived names
+fn add(
+  archive: &mut Archive,
+  files: &[PathBuf],
+  prefix: &MpqPath,
+  codepage: Codepage,
+  flags: CreateFileFlags,
+  compression: CompressionFlags,
+) -> Result<Vec<MpqPathBuf>> {
+  let mut pending = vec![];
+  for path in files {
+    let name = path
+      .file_name()
+      .ok_or_else(|| format!("invalid file name `{}`", path.display()))?;
+    let name = prefix.join(encode_file_name(name, codepage)?);
+    collect_files(path, name, codepage, &mut pending)?;
+  }
+
+  let info = archive.info()?;
+  let needed = info.file_count + pending.len() as u32;
+  if needed > info.max_file_count {
+    archive.set_max_file_count(needed)?;
+  }
+
+  for (path, name) in &pending {
+    archive.add_file(path, name, flags, compression)?;
+  }
+  Ok(pending.into_iter().map(|(_, name)| name).collect())
+}
+
+fn collect_files(
+  path: &Path,
+  name: MpqPathBuf,
+  codepage: Codepage,
+  out: &mut Vec<(PathBuf, MpqPathBuf)>,
+) -> Result<()> {
+  if path.is_dir() {
+    let mut entries = std::fs::read_dir(path)?.collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+      let child = name.join(encode_file_name(&entry.file_name(), codepage)?);
+      collect_files(&entry.path(), child, codepage, out)?;
+    }
+  } else {
+    out.push((path.to_path_buf(), name));
+  }
+  Ok(())
+}
+
+/// Archive name of a local file name. UTF-8 names keep StormLib's escaping of invalid
+/// sequences, so they match the names `extract` writes.
+fn encode_file_name(name: &OsStr, codepage: Codepage) -> Result<Vec<u8>> {
+  if codepage == Codepage::Utf8 {
+    return Ok(file_name_to_name(name)?);
+  }
+  let name = name.to_str().ok_or_else(|| {
+    format!(
+      "file name `{}` is not valid Unicode",
+      name.to_string_lossy()
+    )
+  })?;
+  Ok(codepage.encode(name)?.into_owned())
+}
+
+/// Local file name of an archive name component, the reverse of [`encode_file_name`]
+fn decode_file_name(name: &[u8], codepage: Codepage) -> Result<PathBuf> {
+  if codepage == Codepage::Utf8 {
+    return Ok(name_to_file_name(name)?);
+  }
+  Ok(PathBuf::from(codepage.decode(name).into_owned()))
+}
+
+fn verify_errors(result: VerifyFileResult) -> Vec<&'static str> {
+  [
+    (VerifyFileResult::VERIFY_OPEN_ERROR, "open error"),
+    (VerifyFileResult::VERIFY_READ_ERROR, "read error"),
+    (
+      VerifyFileResult::VERIFY_FILE_SECTOR_CRC_ERROR,
+      "sector CRC mismatch",
+    ),
+    (
+      VerifyFileResult::VERIFY_FILE_CHECKSUM_ERROR,
+      "CRC32 mismatch",
+    ),
+    (VerifyFileResult::VERIFY_FILE_MD5_ERROR, "MD5 mismatch"),
+    (
+      VerifyFileResult::VERIFY_FILE_RAW_MD5_ERROR,
+      "raw MD5 mismatch",
+    ),
+  ]
+  .iter()
+  .filter(|(flag, _)| result.contains(*flag))
+  .map(|&(_, message)| message)
+  .collect()
+}
+
+fn signature_name(status: SignatureStatus) -> &'static str {
+  match status {
+    SignatureStatus::None => "none",
+    SignatureStatus::WeakOk => "weak, ok",
+    SignatureStatus::WeakError => "weak, invalid",
+    SignatureStatus::StrongOk => "strong, ok",
+    SignatureStatus::StrongError => "strong, invalid",
+  }
+}
+
+fn print_names(names: &[MpqPathBuf], codepage: Codepage, json: bool) -> Result<()> {
+  let names: Vec<_> = names.iter().map(|n| n.decode(codepage)).collect();
+  if json {
+    print_json(&names)?;
+  } else {
+    for name in names {
+      println!("{}", name);
+    }
+  }
+  Ok(())
+}
+
+fn print_json<T: Serialize>(value: &T) -> Result<()> {
+  println!("{}", serde_json::to_string_pretty(value)?);
+  Ok(())
+}
@@ -0,0 +1,159 @@
+use std::process::{Command, Output};
+
+fn mpq(args: &[&str]) -> Output {
+  Command::new(env!("CARGO_BIN_EXE_mpq"))
+    .args(args)
+    .output()
+    .unwrap()
+}
+
+fn mpq_json(args: &[&str]) -> serde_json::Value {
+  let output = mpq(args);
+  assert!(
+    output.status.success(),
+    "{}",
+    String::from_utf8_lossy(&output.stderr)
+  );
+  serde_json::from_slice(&output.stdout).unwrap()
+}
+
+#[test]
+fn test_list() {
+  let entries = mpq_json(&["--json", "list", "../../samples/test_tft.w3x"]);
+  let entry = entries
+    .as_array()
+    .unwrap()
+    .iter()
+    .find(|e| e["name"] == "war3map.j")
+    .unwrap();
+  assert_eq!(entry["size"], 14115);
+  assert_eq!(entry["locale"], 0);
+
+  let entries = mpq_json(&[
+    "--json",
+    "list",
+    "../../samples/test_tft.w3x",
+    "--filter",
+    "*.j",
+  ]);
+  assert_eq!(entries.as_array().unwrap().len(), 1);
+}
+
+#[test]
+fn test_info() {
+  let info = mpq_json(&["info", "../../samples/test_tft.w3x", "--json"]);
+  assert_eq!(info["format_version"], 1);
+  assert_eq!(info["header_offset"], 0x200);
+  assert_eq!(info["hash_table_size"], 0x40);
+  assert_eq!(info["signature"], "none");
+}
+
+#[test]
+fn test_extract_and_verify() {
+  let output_dir = "../../samples/test_cli_extract";
+
+  let result = std::panic::catch_unwind(|| {
+    mpq_json(&[
+      "--json",
+      "extract",
+      "../../samples/test_tft.w3x",
+      "war3map.j",
+      "-o",
+      output_dir,
+    ]);
+    assert_eq!(
+      std::fs::read(format!("{}/war3map.j", output_dir)).unwrap(),
+      std::fs::read("../../samples/war3map.j").unwrap()
+    );
+
+    let report = mpq_json(&["--json", "verify", "../../samples/test_tft.w3x"]);
+    assert_eq!(report["ok"], true);
+  });
+
+  std::fs::remove_dir_all(output_dir).ok();
+  result.unwrap();
+}
+
+#[test]
+fn test_modify_archive() {
+  let archive_path = "../../samples/test_cli_modify.mpq";
+  let archive = archive_path;
+
+  let result = std::panic::catch_unwind(|| {
+    let added = mpq_json(&["--json", "create", archive, "../../samples/war3map.j"]);
+    assert_eq!(added, serde_json::json!(["war3map.j"]));
+
+    let added = mpq_json(&[
+      "--json",
+      "add",
+      archive,
+      "../../samples/war3map.j",
+      "--prefix",
+      "scripts",
+      "--encrypt",
+    ]);
+    assert_eq!(added, serde_json::json!(["scripts\\war3map.j"]));
+
+    let renamed = mpq_json(&["--json", "rename", archive, "war3map.j", "renamed.j"]);
+    assert_eq!(renamed["new_name"], "renamed.j");
+    let removed = mpq_json(&["--json", "remove", archive, "scripts\\war3map.j"]);
+    assert_eq!(
+      removed["removed"],
+      serde_json::json!(["scripts\\war3map.j"])
+    );
+    let output = mpq(&["--json", "remove", archive, "missing"]);
+    assert!(!output.status.success());
+    let removed: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(removed["missing"], serde_json::json!(["missing"]));
+    let compacted = mpq_json(&["--json", "compact", archive]);
+    assert!(compacted["size_after"].as_u64() <= compacted["size_before"].as_u64());
+
+    let entries = mpq_json(&["--json", "list", archive, "--filter", "*.j"]);
+    let names: Vec<_> = entries
+      .as_array()
+      .unwrap()
+      .iter()
+      .map(|e| e["name"].as_str().unwrap())
+      .collect();
+    assert_eq!(names, ["renamed.j"]);
+  });
+
+  std::fs::remove_file(archive_path).ok();
+  result.unwrap();
+}
+
+#[test]
+fn test_add_codepage() {
+  let archive = "../../samples/test_cli_codepage.mpq";
+  let local_dir = "../../samples/test_cli_codepage";
+
+  let result = std::panic::catch_unwind(|| {
+    std::fs::create_dir_all(local_dir).unwrap();
+    let local_file = format!("{}/单位.j", local_dir);
+    std::fs::copy("../../samples/war3map.j", &local_file).unwrap();
+    mpq_json(&["--json", "create", archive]);
+
+    // The prefix and the local names are both stored as GBK
+    let added = mpq_json(&[
+      "--json",
+      "--codepage",
+      "936",
+      "add",
+      archive,
+      &local_file,
+      "--prefix",
+      "中文",
+    ]);
+    assert_eq!(added, serde_json::json!(["中文\\单位.j"]));
+    let entries = mpq_json(&["--json", "--codepage", "936", "list", archive]);
+    assert!(entries
+      .as_array()
+      .unwrap()
+      .iter()
+      .any(|e| e["name"] == "中文\\单位.j"));
+  });
+
+  std::fs::remove_file(archive).ok();
+  std::fs::remove_dir_all(local_dir).ok();
+  result.unwrap();
+}
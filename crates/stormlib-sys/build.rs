@@ -1,12 +1,121 @@
+#[cfg(feature = "bindgen")]
+extern crate bindgen;
+#[cfg(feature = "bundled")]
 extern crate cmake;
+#[cfg(feature = "system")]
+extern crate pkg_config;
 
 use std::env;
+use std::path::PathBuf;
+
+#[cfg(feature = "bindgen")]
+mod bindgen_config;
 
 fn main() {
-  // Gets StormLib source path from env STORMLIB_DIR
-  let stormlib_path = env::var("STORMLIB_DIR").unwrap_or("../../deps/StormLib".to_string());
+  // A system library takes precedence, packagers enable `system` to avoid the vendored copy
+  #[cfg(feature = "system")]
+  let include_paths = link_system();
+  #[cfg(not(feature = "system"))]
+  let include_paths = vec![stormlib_dir().join("src")];
+
+  #[cfg(all(feature = "bundled", not(feature = "system")))]
+  build_bundled();
+
+  #[cfg(feature = "bindgen")]
+  generate_bindings(&include_paths);
+  #[cfg(not(feature = "bindgen"))]
+  let _ = include_paths;
+}
+
+/// StormLib source path from env STORMLIB_DIR
+#[cfg(not(feature = "system"))]
+fn stormlib_dir() -> PathBuf {
+  println!("cargo:rerun-if-env-changed=STORMLIB_DIR");
+  env::var_os("STORMLIB_DIR")
+    .map(PathBuf::from)
+    .unwrap_or_else(|| PathBuf::from("../../deps/StormLib"))
+}
+
+/// Generates the bindings for the actual target into `$OUT_DIR/bindings.rs`
+#[cfg(feature = "bindgen")]
+fn generate_bindings(include_paths: &[PathBuf]) {
+  println!("cargo:rerun-if-changed=wrapper.hpp");
+  println!("cargo:rerun-if-changed=bindgen_config.rs");
+  let target_os = env::var("CARGO_CFG_TARGET_OS").unwrap();
+  let out_path = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+  bindgen_config::builder("wrapper.hpp", include_paths, &target_os)
+    .generate()
+    .expect("Unable to generate bindings")
+    .write_to_file(out_path.join("bindings.rs"))
+    .expect("Couldn't write bindings!");
+}
+
+/// Links the StormLib found by pkg-config, dynamically unless `STORMLIB_STATIC` is set.
+/// Returns the include paths of its headers.
+#[cfg(feature = "system")]
+fn link_system() -> Vec<PathBuf> {
+  println!("cargo:rerun-if-env-changed=STORMLIB_STATIC");
+  let statik = env::var_os("STORMLIB_STATIC").is_some_and(|v| v != "0");
+
+  let lib = pkg_config::Config::new()
+    .statik(statik)
+    .probe("storm")
+    .unwrap_or_else(|err| panic!("failed to find StormLib with pkg-config: {}", err));
+
+  // The bindings are generated for one StormLib release, the structures they expose change
+  // between minor versions. Bindings generated from the system headers always match.
+  #[cfg(not(feature = "bindgen"))]
+  {
+    let expected = bindings_version();
+    let found = parse_version(&lib.version);
+    if found != Some(expected) {
+      panic!(
+        "system StormLib {} doesn't match the bindings for StormLib {}.{}",
+        lib.version, expected.0, expected.1
+      );
+    }
+  }
+
+  if statik {
+    #[cfg(target_os = "macos")]
+    println!("cargo:rustc-link-lib=dylib=c++");
+    #[cfg(target_os = "linux")]
+    println!("cargo:rustc-link-lib=stdc++");
+  }
+  lib.include_paths
+}
+
+/// Major and minor version of the checked-in bindings, from `STORMLIB_VERSION`
+#[cfg(all(feature = "system", not(feature = "bindgen")))]
+fn bindings_version() -> (u32, u32) {
+  let os = env::var("CARGO_CFG_TARGET_OS").unwrap();
+  let path = format!("src/bindings_{}.rs", os);
+  println!("cargo:rerun-if-changed={}", path);
+  let bindings = std::fs::read_to_string(&path).unwrap_or_else(|_| {
+    panic!("no pre-generated bindings for this target, enable the `bindgen` feature")
+  });
+  let version: u32 = bindings
+    .lines()
+    .find_map(|line| line.strip_prefix("pub const STORMLIB_VERSION: u32 = "))
+    .and_then(|value| value.trim_end_matches(';').parse().ok())
+    .unwrap_or_else(|| panic!("`STORMLIB_VERSION` not found in {}", path));
+  (version >> 8, version & 0xFF)
+}
+
+/// Parses the major and minor version of a `9.30` or `9.30.0` version string
+#[cfg(all(feature = "system", not(feature = "bindgen")))]
+fn parse_version(version: &str) -> Option<(u32, u32)> {
+  let mut parts = version.split('.');
+  let major = parts.next()?.parse().ok()?;
+  let minor = parts.next()?.parse().ok()?;
+  Some((major, minor))
+}
+
+#[cfg(all(feature = "bundled", not(feature = "system")))]
+fn build_bundled() {
+  let stormlib_path = stormlib_dir();
 
-  println!("cargo:rerun-if-changed={}", stormlib_path);
+  println!("cargo:rerun-if-changed={}", stormlib_path.display());
 
   // Builds StormLib using cmake
   let mut cfg = cmake::Config::new(&stormlib_path);
@@ -1,12 +1,67 @@
 #![allow(non_camel_case_types, non_snake_case, non_upper_case_globals)]
+#![cfg_attr(
+  test,
+  allow(
+    clippy::slow_vector_initialization,
+    clippy::missing_transmute_annotations
+  )
+)]
 
-#[cfg(target_os = "windows")]
+#[cfg(feature = "bindgen")]
+include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
+#[cfg(all(
+  not(feature = "bindgen"),
+  target_os = "windows",
+  target_pointer_width = "64"
+))]
 include!("./bindings_windows.rs");
-#[cfg(target_os = "linux")]
+#[cfg(all(
+  not(feature = "bindgen"),
+  target_os = "linux",
+  target_pointer_width = "64"
+))]
 include!("./bindings_linux.rs");
-#[cfg(target_os = "macos")]
+#[cfg(all(
+  not(feature = "bindgen"),
+  target_os = "macos",
+  target_pointer_width = "64"
+))]
 include!("./bindings_macos.rs");
 
+#[cfg(not(any(
+  feature = "bindgen",
+  all(
+    any(target_os = "windows", target_os = "linux", target_os = "macos"),
+    target_pointer_width = "64"
+  )
+)))]
+compile_error!("no pre-generated StormLib bindings for this target, enable the `bindgen` feature");
+
+/// The pre-generated bindings, to compare with the ones generated for the target
+#[cfg(all(
+  test,
+  feature = "bindgen",
+  target_pointer_width = "64",
+  any(target_os = "windows", target_os = "linux", target_os = "macos")
+))]
+mod pregenerated {
+  #![allow(
+    clippy::all,
+    dead_code,
+    non_camel_case_types,
+    non_snake_case,
+    non_upper_case_globals
+  )]
+
+  #[cfg(target_os = "windows")]
+  include!("./bindings_windows.rs");
+  #[cfg(target_os = "linux")]
+  include!("./bindings_linux.rs");
+  #[cfg(target_os = "macos")]
+  include!("./bindings_macos.rs");
+}
+
+#[cfg(any(feature = "bundled", feature = "system"))]
 #[test]
 fn test_w3x() {
   use std::ffi::*;
@@ -69,3 +124,105 @@ fn test_w3x() {
     assert!(SFileCloseArchive(handle));
   }
 }
+
+#[cfg(all(
+  feature = "bindgen",
+  target_pointer_width = "64",
+  any(target_os = "windows", target_os = "linux", target_os = "macos")
+))]
+#[test]
+fn test_bindings_layout() {
+  use std::mem::{align_of, offset_of, size_of};
+
+  macro_rules! assert_layout {
+    ($($ty:ident),*) => {
+      $(
+        assert_eq!(
+          (size_of::<$ty>(), align_of::<$ty>()),
+          (size_of::<pregenerated::$ty>(), align_of::<pregenerated::$ty>()),
+          "layout of {}",
+          stringify!($ty)
+        );
+      )*
+    };
+  }
+
+  macro_rules! assert_offsets {
+    ($ty:ident { $($field:ident),* }) => {
+      $(
+        assert_eq!(
+          offset_of!($ty, $field),
+          offset_of!(pregenerated::$ty, $field),
+          "offset of {}::{}",
+          stringify!($ty),
+          stringify!($field)
+        );
+      )*
+    };
+  }
+
+  assert_layout!(
+    _TMPQUserData,
+    _TMPQHeader,
+    _TMPQHash,
+    _TFileEntry,
+    _TMPQHetTable,
+    _TMPQNamePrefix,
+    _TMPQArchive,
+    _SFILE_FIND_DATA,
+    _SFILE_CREATE_MPQ,
+    _SFILE_MARKERS
+  );
+  assert_offsets!(_TMPQArchive {
+    pStream,
+    UserDataPos,
+    MpqPos,
+    FileSize,
+    FileOffsetMask,
+    haPatch,
+    haBase,
+    pPatchPrefix,
+    pUserData,
+    pHeader,
+    pHashTable,
+    pHetTable,
+    pFileTable,
+    pfnHashString,
+    UserData,
+    HeaderData,
+    dwHETBlockSize,
+    dwBETBlockSize,
+    dwMaxFileCount,
+    dwFileTableSize,
+    dwReservedFiles,
+    dwSectorSize,
+    dwFileFlags1,
+    dwFileFlags2,
+    dwFileFlags3,
+    dwAttrFlags,
+    dwValidFileFlags,
+    dwRealHashTableSize,
+    dwFlags,
+    dwSubType,
+    pfnAddFileCB,
+    pvAddFileUserData,
+    pfnCompactCB,
+    CompactBytesProcessed,
+    CompactTotalBytes,
+    pvCompactUserData
+  });
+  assert_offsets!(_SFILE_FIND_DATA {
+    cFileName,
+    szPlainName,
+    dwHashIndex,
+    dwBlockIndex,
+    dwFileSize,
+    dwFileFlags,
+    dwCompSize,
+    dwFileTimeLo,
+    dwFileTimeHi,
+    lcLocale
+  });
+  assert_eq!(STORMLIB_VERSION, pregenerated::STORMLIB_VERSION);
+  assert_eq!(MPQ_FILE_VALID_FLAGS, pregenerated::MPQ_FILE_VALID_FLAGS);
+}
@@ -0,0 +1,371 @@
+use std::future::{poll_fn, Future};
+use std::io::{self, SeekFrom};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+use tokio::sync::RwLock;
+use tokio::task::{JoinError, JoinHandle};
+
+use crate::error::*;
+use crate::{Archive, CompressionFlags, CreateArchiveFlags, CreateFileFlags, CreateFileOptions};
+use crate::{FindDataExt, MpqPath, MpqPathBuf, OpenArchiveFlags};
+
+/// MPQ archive for async code
+///
+/// Every archive call runs on the blocking thread pool of the tokio runtime. Cloning is cheap
+/// and every clone refers to the same opened archive. Calls that modify the archive wait for
+/// the calls reading it to finish, opened files don't hold it locked in between.
+#[derive(Debug, Clone)]
+pub struct AsyncArchive {
+  archive: Arc<RwLock<Archive>>,
+}
+
+impl AsyncArchive {
+  /// Opens a MPQ archive
+  pub async fn open<P: AsRef<Path>>(path: P, flags: OpenArchiveFlags) -> Result<Self> {
+    let path = path.as_ref().to_path_buf();
+    blocking(move || Archive::open(path, flags))
+      .await
+      .map(Self::from)
+  }
+
+  /// Creates new MPQ archive
+  pub async fn create<P: AsRef<Path>>(
+    path: P,
+    flags: CreateArchiveFlags,
+    max_files_count: u32,
+  ) -> Result<Self> {
+    let path = path.as_ref().to_path_buf();
+    blocking(move || Archive::create(path, flags, max_files_count))
+      .await
+      .map(Self::from)
+  }
+
+  /// Opens a file from MPQ archive. Its data is read when the file is first read, from the
+  /// archive as it is at that time.
+  pub async fn open_file<N: AsRef<MpqPath>>(&self, path: N) -> Result<AsyncFile> {
+    let name = path.as_ref().to_mpq_path_buf();
+    let archive = self.archive.clone().read_owned().await;
+    let (name, size) = blocking(move || {
+      let size = archive.open_file(&name)?.get_size()?;
+      Ok((name, size))
+    })
+    .await?;
+    Ok(AsyncFile {
+      archive: self.archive.clone(),
+      name,
+      size,
+      pos: 0,
+      state: FileState::Idle,
+    })
+  }
+
+  /// Reads all data of a file
+  pub async fn read_all<N: AsRef<MpqPath>>(&self, path: N) -> Result<Vec<u8>> {
+    let name = path.as_ref().to_mpq_path_buf();
+    let archive = self.archive.clone().read_owned().await;
+    blocking(move || {
+      let data = archive.open_file(&name)?.read_all()?;
+      Ok(data)
+    })
+    .await
+  }
+
+  /// Adds a file to the archive
+  pub async fn create_file<N: AsRef<MpqPath>>(
+    &self,
+    path: N,
+    data: Vec<u8>,
+    flags: CreateFileFlags,
+    compression: CompressionFlags,
+  ) -> Result<()> {
+    let name = path.as_ref().to_mpq_path_buf();
+    let mut archive = self.archive.clone().write_owned().await;
+    blocking(move || {
+      archive.create_file(CreateFileOptions {
+        path: &name,
+        data: &data,
+        flags,
+        mtime: 0,
+        compression,
+      })
+    })
+    .await
+  }
+
+  /// Extracts every file found by a search to `dir`, archive directories become local
+  /// directories. Returns the names of the extracted files.
+  pub async fn extract_all<P: AsRef<Path>>(&self, dir: P) -> Result<Vec<MpqPathBuf>> {
+    let dir = dir.as_ref().to_path_buf();
+    let archive = self.archive.clone().read_owned().await;
+    blocking(move || {
+      let names: Vec<MpqPathBuf> = archive
+        .search(None)?
+        .map(|data| data.path().to_mpq_path_buf())
+        .collect();
+      for name in &names {
+        let path = crate::name::local_path(&dir, name)?;
+        if let Some(parent) = path.parent() {
+          std::fs::create_dir_all(parent).map_err(io_error)?;
+        }
+        archive.extract_file(name, &path)?;
+      }
+      Ok(names)
+    })
+    .await
+  }
+
+  /// Rebuilds the archive, dropping the space of removed and replaced files
+  pub async fn compact(&self) -> Result<()> {
+    let mut archive = self.archive.clone().write_owned().await;
+    blocking(move || archive.compact()).await
+  }
+
+  /// Writes the tables, `(listfile)` and `(attributes)` of the archive
+  pub async fn flush(&self) -> Result<()> {
+    let mut archive = self.archive.clone().write_owned().await;
+    blocking(move || archive.flush()).await
+  }
+
+  /// Closes the archive if this is the last clone of it. Otherwise the archive is flushed and
+  /// stays open for the other clones, so errors of writing the changes made so far are still
+  /// returned.
+  pub async fn close(self) -> Result<()> {
+    match Arc::try_unwrap(self.archive) {
+      Ok(archive) => blocking(move || archive.into_inner().close()).await,
+      Err(archive) => AsyncArchive { archive }.flush().await,
+    }
+  }
+}
+
+impl From<Archive> for AsyncArchive {
+  fn from(archive: Archive) -> Self {
+    AsyncArchive {
+      archive: Arc::new(RwLock::new(archive)),
+    }
+  }
+}
+
+/// Opened file of an [`AsyncArchive`]
+///
+/// The whole file is read on the blocking thread pool the first time it's read, after that
+/// reads and seeks are served from memory. The archive is only locked while the data is read,
+/// so it can be changed while the file is open. A file removed in the meantime fails to read
+/// with [`StormError::FileNotFound`].
+#[derive(Debug)]
+pub struct AsyncFile {
+  archive: Arc<RwLock<Archive>>,
+  name: MpqPathBuf,
+  size: u64,
+  pos: u64,
+  state: FileState,
+}
+
+#[derive(Debug)]
+enum FileState {
+  Idle,
+  Reading(JoinHandle<Result<Vec<u8>>>),
+  Read(Vec<u8>),
+}
+
+impl AsyncFile {
+  /// Name of the file within archive
+  pub fn name(&self) -> &MpqPath {
+    &self.name
+  }
+
+  /// Size of the file within archive
+  pub fn size(&self) -> u64 {
+    self.size
+  }
+
+  /// Reads all data from the file
+  pub async fn read_all(&mut self) -> Result<Vec<u8>> {
+    poll_fn(|cx| self.poll_load(cx)).await?;
+    Ok(self.data().to_vec())
+  }
+
+  fn poll_load(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+    loop {
+      match &mut self.state {
+        FileState::Idle => {
+          let archive = self.archive.clone();
+          let name = self.name.clone();
+          self.state = FileState::Reading(tokio::task::spawn_blocking(move || {
+            let archive = archive.blocking_read();
+            let mut file = archive.open_file(&name)?;
+            file.read_all()
+          }));
+        }
+        FileState::Reading(task) => {
+          let data = ready!(Pin::new(task).poll(cx)).map_err(join_error)?;
+          // A failed read is retried the next time
+          match data {
+            Ok(data) => self.state = FileState::Read(data),
+            Err(err) => {
+              self.state = FileState::Idle;
+              return Poll::Ready(Err(err));
+            }
+          }
+        }
+        FileState::Read(_) => return Poll::Ready(Ok(())),
+      }
+    }
+  }
+
+  fn data(&self) -> &[u8] {
+    match &self.state {
+      FileState::Read(data) => data,
+      _ => &[],
+    }
+  }
+}
+
+impl AsyncRead for AsyncFile {
+  fn poll_read(
+    mut self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+    buf: &mut ReadBuf<'_>,
+  ) -> Poll<io::Result<()>> {
+    let file = &mut *self;
+    if file.pos >= file.size || buf.remaining() == 0 {
+      return Poll::Ready(Ok(()));
+    }
+    ready!(file.poll_load(cx)).map_err(io::Error::other)?;
+    let data = file.data();
+    let start = (file.pos as usize).min(data.len());
+    let len = buf.remaining().min(data.len() - start);
+    buf.put_slice(&data[start..start + len]);
+    file.pos += len as u64;
+    Poll::Ready(Ok(()))
+  }
+}
+
+impl AsyncSeek for AsyncFile {
+  fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+    let pos = match position {
+      SeekFrom::Start(pos) => Some(pos),
+      SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+      SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+    };
+    self.pos = pos.ok_or_else(|| {
+      io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "invalid seek to a negative or overflowing position",
+      )
+    })?;
+    Ok(())
+  }
+
+  fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+    Poll::Ready(Ok(self.pos))
+  }
+}
+
+/// Runs `f` on the blocking thread pool
+async fn blocking<T, F>(f: F) -> Result<T>
+where
+  F: FnOnce() -> Result<T> + Send + 'static,
+  T: Send + 'static,
+{
+  tokio::task::spawn_blocking(f).await.map_err(join_error)?
+}
+
+/// Resumes the panic of a blocking task, tasks only get cancelled on runtime shutdown
+fn join_error(err: JoinError) -> StormError {
+  match err.try_into_panic() {
+    Ok(panic) => std::panic::resume_unwind(panic),
+    Err(_) => StormError::CanNotComplete,
+  }
+}
+
+#[tokio::test]
+async fn test_async_read() {
+  use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+  let archive = AsyncArchive::open(
+    "../../samples/test_tft.w3x",
+    OpenArchiveFlags::MPQ_OPEN_NO_LISTFILE | OpenArchiveFlags::MPQ_OPEN_NO_ATTRIBUTES,
+  )
+  .await
+  .unwrap();
+  let expected = std::fs::read("../../samples/war3map.j").unwrap();
+  assert_eq!(archive.read_all("war3map.j").await.unwrap(), expected);
+  assert!(matches!(
+    archive.open_file("missing").await,
+    Err(StormError::FileNotFound)
+  ));
+
+  let mut file = archive.open_file("war3map.j").await.unwrap();
+  assert_eq!(file.size(), expected.len() as u64);
+  let mut data = vec![];
+  file.read_to_end(&mut data).await.unwrap();
+  assert_eq!(data, expected);
+
+  let mut tail = [0u8; 16];
+  assert_eq!(
+    file.seek(SeekFrom::End(-16)).await.unwrap(),
+    expected.len() as u64 - 16
+  );
+  file.read_exact(&mut tail).await.unwrap();
+  assert_eq!(&tail[..], &expected[expected.len() - 16..]);
+  assert!(file.seek(SeekFrom::Current(-100_000)).await.is_err());
+  assert_eq!(file.read_all().await.unwrap(), expected);
+
+  archive.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_async_write() {
+  let archive_path = "../../samples/test_async_write.mpq";
+  let extract_dir = "../../samples/test_async_write";
+  let data = b"Hello, MPQ! ".repeat(64);
+
+  let result = async move {
+    let archive = AsyncArchive::create(archive_path, CreateArchiveFlags::MPQ_CREATE_LISTFILE, 16)
+      .await
+      .unwrap();
+    for name in ["a.txt", "dir\\b.txt"] {
+      archive
+        .create_file(
+          name,
+          data.clone(),
+          CreateFileFlags::MPQ_FILE_COMPRESS,
+          CompressionFlags::MPQ_COMPRESSION_ZLIB,
+        )
+        .await
+        .unwrap();
+    }
+
+    // Closing a clone writes the changes, the archive stays open
+    archive.clone().close().await.unwrap();
+    let written = Archive::open_read_only(archive_path, OpenArchiveFlags::empty()).unwrap();
+    assert!(written.has_file("dir\\b.txt").unwrap());
+    written.close().unwrap();
+
+    // Opened files don't keep the archive locked
+    let mut file = archive.open_file("dir\\b.txt").await.unwrap();
+    archive.compact().await.unwrap();
+    assert_eq!(file.read_all().await.unwrap(), data);
+    drop(file);
+
+    let names = archive.extract_all(extract_dir).await.unwrap();
+    assert!(names.contains(&MpqPath::new("dir\\b.txt").to_mpq_path_buf()));
+    assert_eq!(
+      std::fs::read(Path::new(extract_dir).join("dir").join("b.txt")).unwrap(),
+      data
+    );
+    archive.close().await.unwrap();
+  };
+  let result = tokio::spawn(result).await;
+
+  // Clean up
+  std::fs::remove_file(archive_path).unwrap();
+  let _ = std::fs::remove_dir_all(extract_dir);
+
+  // Propagate any panic that occurred during the test
+  result.unwrap();
+}
@@ -1,5 +1,4 @@
 use bitflags::bitflags;
-use stormlib_sys;
 
 bitflags! {
   pub struct OpenArchiveFlags: u32 {
@@ -36,6 +35,8 @@ bitflags! {
     const MPQ_OPEN_FORCE_MPQ_V1 = stormlib_sys::MPQ_OPEN_FORCE_MPQ_V1;
     /// SFileReadFile will check CRC of each file sector on any file in the archive until the archive is closed.
     const MPQ_OPEN_CHECK_SECTOR_CRC = stormlib_sys::MPQ_OPEN_CHECK_SECTOR_CRC;
+    /// Same as STREAM_FLAG_READ_ONLY. `Archive::open` ignores it, use `Archive::open_read_only` instead.
+    const MPQ_OPEN_READ_ONLY = stormlib_sys::MPQ_OPEN_READ_ONLY;
   }
 }
 
@@ -102,3 +103,45 @@ bitflags! {
     const MPQ_COMPRESSION_LZMA = stormlib_sys::MPQ_COMPRESSION_LZMA;
   }
 }
+
+bitflags! {
+  pub struct VerifyFileFlags: u32 {
+    /// Verify sector checksums (if present).
+    const SFILE_VERIFY_SECTOR_CRC = stormlib_sys::SFILE_VERIFY_SECTOR_CRC;
+    /// Verify file CRC32 (if present).
+    const SFILE_VERIFY_FILE_CRC = stormlib_sys::SFILE_VERIFY_FILE_CRC;
+    /// Verify file MD5 (if present).
+    const SFILE_VERIFY_FILE_MD5 = stormlib_sys::SFILE_VERIFY_FILE_MD5;
+    /// Verify raw file MD5 (if present).
+    const SFILE_VERIFY_RAW_MD5 = stormlib_sys::SFILE_VERIFY_RAW_MD5;
+    /// Verify all of the above.
+    const SFILE_VERIFY_ALL = stormlib_sys::SFILE_VERIFY_ALL;
+  }
+}
+
+bitflags! {
+  pub struct VerifyFileResult: u32 {
+    /// Failed to open the file.
+    const VERIFY_OPEN_ERROR = stormlib_sys::VERIFY_OPEN_ERROR;
+    /// Failed to read all data from the file.
+    const VERIFY_READ_ERROR = stormlib_sys::VERIFY_READ_ERROR;
+    /// File has sector CRC.
+    const VERIFY_FILE_HAS_SECTOR_CRC = stormlib_sys::VERIFY_FILE_HAS_SECTOR_CRC;
+    /// Sector CRC check failed.
+    const VERIFY_FILE_SECTOR_CRC_ERROR = stormlib_sys::VERIFY_FILE_SECTOR_CRC_ERROR;
+    /// File has CRC32.
+    const VERIFY_FILE_HAS_CHECKSUM = stormlib_sys::VERIFY_FILE_HAS_CHECKSUM;
+    /// CRC32 check failed.
+    const VERIFY_FILE_CHECKSUM_ERROR = stormlib_sys::VERIFY_FILE_CHECKSUM_ERROR;
+    /// File has data MD5.
+    const VERIFY_FILE_HAS_MD5 = stormlib_sys::VERIFY_FILE_HAS_MD5;
+    /// MD5 check failed.
+    const VERIFY_FILE_MD5_ERROR = stormlib_sys::VERIFY_FILE_MD5_ERROR;
+    /// File has raw data MD5.
+    const VERIFY_FILE_HAS_RAW_MD5 = stormlib_sys::VERIFY_FILE_HAS_RAW_MD5;
+    /// Raw MD5 check failed.
+    const VERIFY_FILE_RAW_MD5_ERROR = stormlib_sys::VERIFY_FILE_RAW_MD5_ERROR;
+    /// Mask of all error flags.
+    const VERIFY_FILE_ERROR_MASK = stormlib_sys::VERIFY_FILE_ERROR_MASK;
+  }
+}
@@ -0,0 +1,132 @@
+use stormlib_sys::{ERROR_UNKNOWN_FILE_KEY, MPQ_FILE_ENCRYPTED};
+
+use crate::error::*;
+use crate::{Archive, ArchiveMode, FileHandle, FindDataExt, MpqPath, MpqPathBuf};
+
+impl Archive {
+  /// Copies file `name` of `other` to `new_name`, keeping its flags, compression and file time
+  ///
+  /// The data is copied as it is stored and only re-encrypted when the new name or position
+  /// changes the key. Files of archives with a different sector size are compressed again.
+  /// Fails with [`StormError::NotSupported`] if `other` is not a plain local file, see
+  /// [`crate::File::read_raw`].
+  ///
+  /// Fails with [`StormError::AlreadyExists`] if `new_name` exists.
+  pub fn copy_from<M: ArchiveMode, N: AsRef<MpqPath>, T: AsRef<MpqPath>>(
+    &mut self,
+    other: &Archive<M>,
+    name: N,
+    new_name: T,
+  ) -> Result<()> {
+    self.copy_file(other, name.as_ref(), new_name.as_ref(), false)
+  }
+
+  /// Copies every file found by a search of `other` with [`Archive::copy_from`], replacing
+  /// existing files with the same name. Returns the names of the copied files.
+  ///
+  /// The `(listfile)`, `(attributes)` and `(signature)` are not copied. The stored data of
+  /// every file is read before the first one is copied, so a file that can't be read, or an
+  /// encrypted file with an unknown key, fails the merge without changing the archive.
+  pub fn merge<M: ArchiveMode>(&mut self, other: &Archive<M>) -> Result<Vec<MpqPathBuf>> {
+    let names: Vec<MpqPathBuf> = other
+      .search(None)?
+      .map(|data| data.path().to_mpq_path_buf())
+      .filter(|name| !crate::name::is_internal(name))
+      .collect();
+    for name in &names {
+      let raw = FileHandle::open(other, name)?.read_raw()?;
+      if raw.flags() & MPQ_FILE_ENCRYPTED != 0 && raw.key().is_none() {
+        return Err(ErrorCode(ERROR_UNKNOWN_FILE_KEY).into());
+      }
+    }
+    for name in &names {
+      self.copy_file(other, name, name, true)?;
+    }
+    Ok(names)
+  }
+}
+
+#[test]
+fn test_copy_from() {
+  use crate::{CompressionFlags, CreateArchiveFlags, CreateFileFlags, CreateFileOptions};
+  use crate::{DiffMode, OpenArchiveFlags};
+
+  let source_path = "../../samples/test_copy_source.mpq";
+  let target_path = "../../samples/test_copy_target.mpq";
+  let script = std::fs::read("../../samples/war3map.j").unwrap();
+  let compress = CreateFileFlags::MPQ_FILE_COMPRESS;
+  let encrypted = compress | CreateFileFlags::MPQ_FILE_ENCRYPTED;
+  let files = [
+    ("plain.j", compress | CreateFileFlags::MPQ_FILE_SECTOR_CRC),
+    ("encrypted.j", encrypted),
+    ("fix_key.j", encrypted | CreateFileFlags::MPQ_FILE_FIX_KEY),
+    (
+      "single.j",
+      encrypted | CreateFileFlags::MPQ_FILE_SINGLE_UNIT,
+    ),
+  ];
+
+  let result = std::panic::catch_unwind(|| {
+    let flags = CreateArchiveFlags::MPQ_CREATE_LISTFILE | CreateArchiveFlags::MPQ_CREATE_ATTRIBUTES;
+    let mut source = Archive::create(source_path, flags, 16).unwrap();
+    for (i, (name, flags)) in files.iter().enumerate() {
+      source
+        .create_file(CreateFileOptions {
+          path: MpqPath::new(name),
+          data: &script,
+          flags: *flags,
+          mtime: 0x01D0_0000_0000_0000 + i as u64,
+          compression: CompressionFlags::MPQ_COMPRESSION_BZIP2,
+        })
+        .unwrap();
+    }
+    source.flush().unwrap();
+
+    let mut target = Archive::create(target_path, flags, 16).unwrap();
+    target
+      .create_file(CreateFileOptions {
+        path: MpqPath::new("plain.j"),
+        data: &b"replaced".to_vec(),
+        flags: CreateFileFlags::empty(),
+        mtime: 0,
+        compression: CompressionFlags::empty(),
+      })
+      .unwrap();
+    assert!(matches!(
+      target.copy_from(&source, "plain.j", "plain.j"),
+      Err(StormError::AlreadyExists)
+    ));
+    for (name, _) in &files {
+      target
+        .copy_from(&source, name, format!("copy\\{}", name))
+        .unwrap();
+    }
+    let merged = target.merge(&source).unwrap();
+    assert_eq!(merged.len(), files.len());
+    target.close().unwrap();
+
+    let target = Archive::open(target_path, OpenArchiveFlags::MPQ_OPEN_CHECK_SECTOR_CRC).unwrap();
+    for (name, _) in &files {
+      let copy = format!("copy\\{}", name);
+      assert_eq!(target.open_file(&copy).unwrap().read_all().unwrap(), script);
+      assert_eq!(target.open_file(name).unwrap().read_all().unwrap(), script);
+    }
+
+    // Only the file with a key that depends on its position is stored differently
+    let raw = crate::diff_with(&source, &target, DiffMode::Raw).unwrap();
+    assert_eq!(raw.added.len(), files.len());
+    let modified: Vec<_> = raw.modified.iter().map(|c| c.name.clone()).collect();
+    assert_eq!(modified, [MpqPathBuf::from("fix_key.j")]);
+    assert!(raw.metadata_changed.is_empty());
+
+    source.close().unwrap();
+    target.close().unwrap();
+  });
+
+  // Clean up
+  let _ = std::fs::remove_file(source_path);
+  let _ = std::fs::remove_file(target_path);
+
+  // Propagate any panic that occurred during the test
+  result.unwrap();
+}
@@ -0,0 +1,205 @@
+//! MPQ hashing and encryption
+//!
+//! The hash of file names that locates them in the hash table, and the block cipher used for
+//! the tables and for encrypted files. Encrypted files are keyed by their plain name, sector
+//! `i` of a file is encrypted with `file_key + i` and the sector offset table with
+//! `file_key - 1`.
+
+use crate::MpqPath;
+
+/// Key of the hash table, the [`HashType::FileKey`] hash of `(hash table)`
+pub const MPQ_KEY_HASH_TABLE: u32 = stormlib_sys::MPQ_KEY_HASH_TABLE;
+/// Key of the block table, the [`HashType::FileKey`] hash of `(block table)`
+pub const MPQ_KEY_BLOCK_TABLE: u32 = stormlib_sys::MPQ_KEY_BLOCK_TABLE;
+
+/// Kind of hash computed by [`hash_string`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HashType {
+  /// Start of the search in the hash table, modulo the table size
+  TableOffset = 0,
+  /// First hash stored in the hash table entry
+  NameA = 1,
+  /// Second hash stored in the hash table entry
+  NameB = 2,
+  /// Key of an encrypted file, computed from the name without its directory
+  FileKey = 3,
+}
+
+const HASH_KEY2_MIX: usize = 4;
+
+static CRYPT_TABLE: [u32; 0x500] = crypt_table();
+
+const fn crypt_table() -> [u32; 0x500] {
+  let mut table = [0u32; 0x500];
+  let mut seed: u32 = 0x0010_0001;
+  let mut i = 0;
+  while i < 0x100 {
+    let mut index = i;
+    let mut j = 0;
+    while j < 5 {
+      seed = (seed * 125 + 3) % 0x2A_AAAB;
+      let high = (seed & 0xFFFF) << 16;
+      seed = (seed * 125 + 3) % 0x2A_AAAB;
+      let low = seed & 0xFFFF;
+      table[index] = high | low;
+      index += 0x100;
+      j += 1;
+    }
+    i += 1;
+  }
+  table
+}
+
+/// Hashes a file name the way `HashString` does. Names are compared case-insensitively and
+/// `/` is the same as `\`.
+pub fn hash_string<N: AsRef<MpqPath>>(name: N, hash_type: HashType) -> u32 {
+  let offset = (hash_type as usize) << 8;
+  let mut seed1: u32 = 0x7FED_7FED;
+  let mut seed2: u32 = 0xEEEE_EEEE;
+  for &c in name.as_ref().as_bytes() {
+    let c = match c.to_ascii_uppercase() {
+      b'/' => b'\\',
+      c => c,
+    } as u32;
+    seed1 = CRYPT_TABLE[offset + c as usize] ^ seed1.wrapping_add(seed2);
+    seed2 = c
+      .wrapping_add(seed1)
+      .wrapping_add(seed2)
+      .wrapping_add(seed2 << 5)
+      .wrapping_add(3);
+  }
+  seed1
+}
+
+/// Decrypts whole 32-bit words in place, trailing bytes are not encrypted
+pub fn decrypt_block(data: &mut [u8], mut key: u32) {
+  let mut seed: u32 = 0xEEEE_EEEE;
+  for chunk in data.chunks_exact_mut(4) {
+    seed = seed.wrapping_add(CRYPT_TABLE[HASH_KEY2_MIX * 0x100 + (key & 0xFF) as usize]);
+    let value =
+      u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]) ^ key.wrapping_add(seed);
+    chunk.copy_from_slice(&value.to_le_bytes());
+    key = ((!key << 21).wrapping_add(0x1111_1111)) | (key >> 11);
+    seed = value
+      .wrapping_add(seed)
+      .wrapping_add(seed << 5)
+      .wrapping_add(3);
+  }
+}
+
+/// Encrypts whole 32-bit words in place, trailing bytes are left as they are
+pub fn encrypt_block(data: &mut [u8], mut key: u32) {
+  let mut seed: u32 = 0xEEEE_EEEE;
+  for chunk in data.chunks_exact_mut(4) {
+    seed = seed.wrapping_add(CRYPT_TABLE[HASH_KEY2_MIX * 0x100 + (key & 0xFF) as usize]);
+    let value = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+    chunk.copy_from_slice(&(value ^ key.wrapping_add(seed)).to_le_bytes());
+    key = ((!key << 21).wrapping_add(0x1111_1111)) | (key >> 11);
+    seed = value
+      .wrapping_add(seed)
+      .wrapping_add(seed << 5)
+      .wrapping_add(3);
+  }
+}
+
+/// Encryption key of a file. With `MPQ_FILE_FIX_KEY` the key is adjusted by the position of
+/// the file relative to the archive header and its uncompressed size.
+pub fn file_key<N: AsRef<MpqPath>>(name: N, offset: u64, size: u32, fix_key: bool) -> u32 {
+  let name = name.as_ref().as_bytes();
+  let plain_name = match name.iter().rposition(|&c| c == b'\\' || c == b'/') {
+    Some(pos) => &name[pos + 1..],
+    None => name,
+  };
+  let key = hash_string(plain_name, HashType::FileKey);
+  if fix_key {
+    key.wrapping_add(offset as u32) ^ size
+  } else {
+    key
+  }
+}
+
+/// Recovers the key of an encrypted block whose first two 32-bit words are known, like the
+/// `RIFF` header of a WAVE file
+pub fn recover_key(encrypted: &[u8], plain: [u32; 2]) -> Option<u32> {
+  find_key(encrypted, plain[0], |second| second == plain[1])
+}
+
+/// Recovers the key of an encrypted file from its sector offset table, whose first entry is
+/// the size of the table. Returns the file key, which is one more than the key of the table.
+pub fn detect_file_key(encrypted: &[u8], table_size: u32, sector_size: u32) -> Option<u32> {
+  find_key(encrypted, table_size, |second| {
+    second > table_size && second - table_size <= sector_size + 4
+  })
+  .map(|key| key.wrapping_add(1))
+}
+
+/// Tries the 256 keys that decrypt the first word to `first`, checking the second word
+fn find_key(encrypted: &[u8], first: u32, check: impl Fn(u32) -> bool) -> Option<u32> {
+  if encrypted.len() < 8 {
+    return None;
+  }
+  let encrypted_first =
+    u32::from_le_bytes([encrypted[0], encrypted[1], encrypted[2], encrypted[3]]);
+  // `encrypted_first ^ first` is `key + 0xEEEEEEEE + CRYPT_TABLE[0x400 + (key & 0xFF)]`
+  let mix = encrypted_first ^ first;
+  (0..0x100).find_map(|low| {
+    let key = mix
+      .wrapping_sub(0xEEEE_EEEE)
+      .wrapping_sub(CRYPT_TABLE[HASH_KEY2_MIX * 0x100 + low]);
+    if key & 0xFF != low as u32 {
+      return None;
+    }
+    let mut test = [0u8; 8];
+    test.copy_from_slice(&encrypted[..8]);
+    decrypt_block(&mut test, key);
+    check(u32::from_le_bytes([test[4], test[5], test[6], test[7]])).then_some(key)
+  })
+}
+
+#[test]
+fn test_hash_string() {
+  assert_eq!(
+    hash_string("(hash table)", HashType::FileKey),
+    MPQ_KEY_HASH_TABLE
+  );
+  assert_eq!(
+    hash_string("(block table)", HashType::FileKey),
+    MPQ_KEY_BLOCK_TABLE
+  );
+  assert_eq!(
+    hash_string("units/human/footman.mdx", HashType::NameA),
+    hash_string("Units\\Human\\Footman.mdx", HashType::NameA)
+  );
+  assert_eq!(
+    file_key("Scripts\\war3map.j", 0, 0, false),
+    hash_string("war3map.j", HashType::FileKey)
+  );
+}
+
+#[test]
+fn test_recover_key() {
+  let key = file_key("sound\\music.wav", 0x7f1, 14115, true);
+  let plain = b"RIFF\x24\x08\x00\x00WAVEfmt ".to_vec();
+
+  let mut encrypted = plain.clone();
+  encrypt_block(&mut encrypted, key);
+  let mut decrypted = encrypted.clone();
+  decrypt_block(&mut decrypted, key);
+  assert_eq!(decrypted, plain);
+
+  assert_eq!(recover_key(&encrypted, [0x4646_4952, 0x0824]), Some(key));
+  assert_eq!(recover_key(&encrypted, [0x4646_4952, 0x0825]), None);
+}
+
+#[test]
+fn test_detect_file_key() {
+  let key = file_key("war3map.j", 0x7f1, 14115, false);
+  let table: Vec<u8> = [20u32, 1234, 2345, 3456, 4000]
+    .iter()
+    .flat_map(|v| v.to_le_bytes())
+    .collect();
+
+  let mut encrypted = table;
+  encrypt_block(&mut encrypted, key - 1);
+  assert_eq!(detect_file_key(&encrypted, 20, 4096), Some(key));
+}
@@ -0,0 +1,256 @@
+//! Comparing archives
+//!
+//! [`diff`] matches the files of two archives by name and locale and reports which were added,
+//! removed, modified or only stored differently.
+
+use std::collections::BTreeMap;
+
+use crate::error::*;
+use crate::{Archive, ArchiveMode, FileHandle, FindDataExt, MpqPathBuf, RawFile};
+
+/// What [`diff_with`] compares to find modified files
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffMode {
+  /// Decompressed and decrypted content
+  Content,
+  /// Data as it is stored in the archive, so recompressed files count as modified. Fails
+  /// with [`StormError::NotSupported`] unless both archives are plain local files.
+  Raw,
+}
+
+/// Differences between two archives, every list is sorted by name and locale
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ArchiveDiff {
+  /// Names and locales of the files only in the new archive
+  pub added: Vec<(MpqPathBuf, u32)>,
+  /// Names and locales of the files only in the old archive
+  pub removed: Vec<(MpqPathBuf, u32)>,
+  /// Files with different content
+  pub modified: Vec<FileChange>,
+  /// Files with the same content but different metadata
+  pub metadata_changed: Vec<FileChange>,
+}
+
+impl ArchiveDiff {
+  /// Whether the archives have the same files
+  pub fn is_empty(&self) -> bool {
+    self.added.is_empty()
+      && self.removed.is_empty()
+      && self.modified.is_empty()
+      && self.metadata_changed.is_empty()
+  }
+}
+
+/// File in both archives
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileChange {
+  /// Name of the file, the locale is in the metadata
+  pub name: MpqPathBuf,
+  /// Metadata in the old archive
+  pub old: FileMetadata,
+  /// Metadata in the new archive
+  pub new: FileMetadata,
+}
+
+/// Settings a file is stored with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileMetadata {
+  /// `MPQ_FILE_*` flags
+  pub flags: u32,
+  /// `MPQ_COMPRESSION_*` mask of the compressed sectors, `0` if no sector is compressed.
+  /// `None` if the stored data can't be read, see [`crate::File::read_raw`].
+  pub compression: Option<u32>,
+  /// Windows `LCID` of the file, `0` for the neutral locale
+  pub locale: u32,
+  /// Windows `FILETIME` stored in the `(attributes)`, `0` if there is none
+  pub file_time: u64,
+}
+
+/// Compares the decompressed files of archive `a` with the ones of the newer archive `b`
+///
+/// Files are matched by name and locale. Files not named in the `(listfile)` are matched by the
+/// `FileXXXXXXXX.xxx` names StormLib generates for them. The `(listfile)`, `(attributes)` and
+/// `(signature)` are not compared.
+pub fn diff<A: ArchiveMode, B: ArchiveMode>(a: &Archive<A>, b: &Archive<B>) -> Result<ArchiveDiff> {
+  diff_with(a, b, DiffMode::Content)
+}
+
+/// Compares archive `a` with the newer archive `b`, see [`diff`]
+pub fn diff_with<A: ArchiveMode, B: ArchiveMode>(
+  a: &Archive<A>,
+  b: &Archive<B>,
+  mode: DiffMode,
+) -> Result<ArchiveDiff> {
+  let old = list(a)?;
+  let mut new = list(b)?;
+
+  let mut diff = ArchiveDiff::default();
+  for ((name, locale), old_metadata) in old {
+    let new_metadata = match new.remove(&(name.clone(), locale)) {
+      Some(metadata) => metadata,
+      None => {
+        diff.removed.push((name, locale));
+        continue;
+      }
+    };
+    let mut old_file = FileHandle::open_locale(a, &name, locale)?;
+    let mut new_file = FileHandle::open_locale(b, &name, locale)?;
+    let old_raw = read_stored(&mut old_file)?;
+    let new_raw = read_stored(&mut new_file)?;
+    let old_metadata = metadata(old_raw.as_ref(), old_metadata)?;
+    let new_metadata = metadata(new_raw.as_ref(), new_metadata)?;
+    let same_content = match (mode, old_raw, new_raw) {
+      (DiffMode::Content, _, _) => old_file.read_all()? == new_file.read_all()?,
+      (DiffMode::Raw, Some(old_raw), Some(new_raw)) => old_raw.data == new_raw.data,
+      (DiffMode::Raw, _, _) => return Err(StormError::NotSupported),
+    };
+    let change = FileChange {
+      name,
+      old: old_metadata,
+      new: new_metadata,
+    };
+    if !same_content {
+      diff.modified.push(change);
+    } else if old_metadata != new_metadata {
+      diff.metadata_changed.push(change);
+    }
+  }
+  diff.added = new.into_keys().collect();
+  Ok(diff)
+}
+
+/// Metadata of the files found by a search by name and locale, without the compression
+fn list<M: ArchiveMode>(archive: &Archive<M>) -> Result<BTreeMap<(MpqPathBuf, u32), FileMetadata>> {
+  let mut files = BTreeMap::new();
+  for data in archive.search(None)? {
+    let name = data.path();
+    // The internal files change whenever any other file does
+    if crate::name::is_internal(name) {
+      continue;
+    }
+    files.insert(
+      (name.to_mpq_path_buf(), data.lcLocale),
+      FileMetadata {
+        flags: data.dwFileFlags,
+        compression: None,
+        locale: data.lcLocale,
+        file_time: (data.dwFileTimeHi as u64) << 32 | data.dwFileTimeLo as u64,
+      },
+    );
+  }
+  Ok(files)
+}
+
+/// Stored data of a file, `None` if the archive is not a plain local file
+fn read_stored(file: &mut FileHandle) -> Result<Option<RawFile>> {
+  match file.read_raw() {
+    Ok(raw) => Ok(Some(raw)),
+    Err(StormError::NotSupported) => Ok(None),
+    Err(err) => Err(err),
+  }
+}
+
+fn metadata(raw: Option<&RawFile>, metadata: FileMetadata) -> Result<FileMetadata> {
+  Ok(FileMetadata {
+    compression: raw.map(RawFile::compression).transpose()?,
+    ..metadata
+  })
+}
+
+#[test]
+fn test_diff() {
+  use crate::{CompressionFlags, CreateArchiveFlags, CreateFileFlags, CreateFileOptions, MpqPath};
+
+  let paths = [
+    "../../samples/test_diff_a.mpq",
+    "../../samples/test_diff_b.mpq",
+  ];
+  let script = std::fs::read("../../samples/war3map.j").unwrap();
+  let zlib = CompressionFlags::MPQ_COMPRESSION_ZLIB;
+  let bzip2 = CompressionFlags::MPQ_COMPRESSION_BZIP2;
+  let old: [(&str, &[u8], CompressionFlags, u64); 5] = [
+    ("war3map.j", &script, zlib, 0),
+    ("removed.txt", b"removed", zlib, 0),
+    ("modified.txt", b"old", zlib, 0),
+    ("recompressed.j", &script, zlib, 0),
+    ("touched.txt", b"touched", zlib, 0),
+  ];
+  let new: [(&str, &[u8], CompressionFlags, u64); 5] = [
+    ("war3map.j", &script, zlib, 0),
+    ("added.txt", b"added", zlib, 0),
+    ("modified.txt", b"new", zlib, 0),
+    ("recompressed.j", &script, bzip2, 0),
+    ("touched.txt", b"touched", zlib, 0x01D0_0000_0000_0000),
+  ];
+
+  let result = std::panic::catch_unwind(|| {
+    let flags = CreateArchiveFlags::MPQ_CREATE_LISTFILE | CreateArchiveFlags::MPQ_CREATE_ATTRIBUTES;
+    let mut archives = vec![];
+    for (path, files) in paths.iter().zip([old, new]) {
+      let mut archive = Archive::create(path, flags, 16).unwrap();
+      for (name, data, compression, mtime) in files {
+        archive
+          .create_file(CreateFileOptions {
+            path: MpqPath::new(name),
+            data: &data.to_vec(),
+            flags: CreateFileFlags::MPQ_FILE_COMPRESS,
+            mtime,
+            compression,
+          })
+          .unwrap();
+      }
+      archive.flush().unwrap();
+      archives.push(archive);
+    }
+
+    let names = |changes: &[FileChange]| -> Vec<MpqPathBuf> {
+      changes.iter().map(|change| change.name.clone()).collect()
+    };
+    let content = diff(&archives[0], &archives[1]).unwrap();
+    assert_eq!(content.added, [(MpqPathBuf::from("added.txt"), 0)]);
+    assert_eq!(content.removed, [(MpqPathBuf::from("removed.txt"), 0)]);
+    assert_eq!(names(&content.modified), [MpqPathBuf::from("modified.txt")]);
+    assert_eq!(
+      names(&content.metadata_changed),
+      [
+        MpqPathBuf::from("recompressed.j"),
+        MpqPathBuf::from("touched.txt")
+      ]
+    );
+    let recompressed = &content.metadata_changed[0];
+    assert_eq!(
+      (recompressed.old.compression, recompressed.new.compression),
+      (Some(zlib.bits()), Some(bzip2.bits()))
+    );
+    assert_eq!(
+      content.metadata_changed[1].new.file_time,
+      0x01D0_0000_0000_0000
+    );
+
+    let raw = diff_with(&archives[0], &archives[1], DiffMode::Raw).unwrap();
+    assert_eq!(
+      names(&raw.modified),
+      [
+        MpqPathBuf::from("modified.txt"),
+        MpqPathBuf::from("recompressed.j")
+      ]
+    );
+    assert_eq!(
+      names(&raw.metadata_changed),
+      [MpqPathBuf::from("touched.txt")]
+    );
+
+    assert!(diff(&archives[0], &archives[0]).unwrap().is_empty());
+    for archive in archives {
+      archive.close().unwrap();
+    }
+  });
+
+  // Clean up
+  for path in paths {
+    let _ = std::fs::remove_file(path);
+  }
+
+  // Propagate any panic that occurred during the test
+  result.unwrap();
+}
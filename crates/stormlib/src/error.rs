@@ -44,6 +44,16 @@ pub enum StormError {
   NonUtf8,
   #[error("an interior nul byte was found")]
   InteriorNul,
+  #[error("the name can't be represented in the codepage")]
+  UnmappableName,
+  #[error("the name can't be stored in an archive")]
+  InvalidName,
+  #[error("invalid search pattern: {0}")]
+  InvalidPattern(String),
+  #[error("the content of `{0}` doesn't match the manifest")]
+  ManifestMismatch(String),
+  #[error("invalid SOURCE_DATE_EPOCH: {0:?}")]
+  InvalidSourceDateEpoch(String),
 }
 
 pub type Result<T, E = StormError> = std::result::Result<T, E>;
@@ -78,3 +88,13 @@ impl From<std::ffi::NulError> for StormError {
     StormError::InteriorNul
   }
 }
+
+/// Maps errors of local file operations to the closest StormLib error
+pub(crate) fn io_error(err: std::io::Error) -> StormError {
+  match err.kind() {
+    std::io::ErrorKind::NotFound => StormError::FileNotFound,
+    std::io::ErrorKind::PermissionDenied => StormError::AccessDenied,
+    std::io::ErrorKind::AlreadyExists => StormError::AlreadyExists,
+    _ => StormError::CanNotComplete,
+  }
+}
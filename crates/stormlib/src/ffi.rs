@@ -0,0 +1,924 @@
+//! StormLib backend
+
+use std::ffi::*;
+use std::marker::PhantomData;
+use std::path::Path;
+use std::ptr;
+use std::sync::Arc;
+use stormlib_sys::*;
+
+use crate::error::*;
+use crate::raw::RawFile;
+use crate::{util, ArchiveInfo, ArchiveMode, CreateFileOptions, MpqPath, ReadOnly, ReadWrite};
+use crate::{CompressionFlags, CreateArchiveFlags, CreateFileFlags, OpenArchiveFlags};
+use crate::{SignatureStatus, VerifyFileFlags, VerifyFileResult};
+
+/// MPQ archive
+///
+/// Archives are writable by default. Archives opened with [`Archive::open_read_only`] are
+/// `Archive<ReadOnly>` and only provide the read methods.
+///
+/// Methods that modify the archive take `&mut self`, so they can't be called while files or
+/// searches borrowed from the archive are alive.
+///
+/// StormLib writes the archive tables, `(listfile)` and `(attributes)` when the archive is
+/// closed. Use [`Archive::close`] to find out whether that succeeded, dropping the archive
+/// only logs the error.
+#[derive(Debug)]
+pub struct Archive<M: ArchiveMode = ReadWrite> {
+  pub(crate) handle: HANDLE,
+  panic_on_unflushed_drop: bool,
+  _mode: PhantomData<M>,
+}
+
+unsafe impl<M: ArchiveMode> Send for Archive<M> {}
+// Methods taking `&self` don't modify the archive and every StormLib call is serialized by
+// the global lock, so shared references can be used from multiple threads
+unsafe impl<M: ArchiveMode> Sync for Archive<M> {}
+
+impl Archive {
+  /// Creates new MPQ archive
+  pub fn create<P: AsRef<Path>>(
+    path: P,
+    flags: CreateArchiveFlags,
+    max_files_count: DWORD,
+  ) -> Result<Self> {
+    let cpath = util::to_tpath(path.as_ref())?;
+
+    let mut handle: HANDLE = ptr::null_mut();
+    unsafe_try_call!(SFileCreateArchive(
+      cpath.as_ptr(),
+      flags.bits(),
+      max_files_count,
+      &mut handle
+    ));
+
+    Ok(Archive::from_handle(handle))
+  }
+
+  /// Creates new MPQ archive with files split into sectors of `sector_size` bytes, a power of
+  /// two of at least 512
+  pub fn create_with_sector_size<P: AsRef<Path>>(
+    path: P,
+    flags: CreateArchiveFlags,
+    max_files_count: DWORD,
+    sector_size: u32,
+  ) -> Result<Self> {
+    if sector_size < 0x200 || !sector_size.is_power_of_two() {
+      return Err(StormError::InvalidParameter);
+    }
+    let cpath = util::to_tpath(path.as_ref())?;
+
+    // The settings `SFileCreateArchive` uses, except for the sector size
+    let bits = flags.bits();
+    let internal = |flag: u32| {
+      if bits & flag != 0 {
+        MPQ_FILE_DEFAULT_INTERNAL
+      } else {
+        0
+      }
+    };
+    let format_version = (bits & MPQ_CREATE_ARCHIVE_VMASK) >> FLAGS_TO_FORMAT_SHIFT;
+    let mut create_info = SFILE_CREATE_MPQ {
+      cbSize: std::mem::size_of::<SFILE_CREATE_MPQ>() as DWORD,
+      dwMpqVersion: format_version,
+      pvUserData: ptr::null_mut(),
+      cbUserData: 0,
+      dwStreamFlags: STREAM_PROVIDER_FLAT | BASE_PROVIDER_FILE | (bits & STREAM_OPTIONS_MASK),
+      dwFileFlags1: internal(MPQ_CREATE_LISTFILE),
+      dwFileFlags2: internal(MPQ_CREATE_ATTRIBUTES),
+      dwFileFlags3: internal(MPQ_CREATE_SIGNATURE),
+      dwAttrFlags: if bits & MPQ_CREATE_ATTRIBUTES != 0 {
+        MPQ_ATTRIBUTE_CRC32 | MPQ_ATTRIBUTE_FILETIME | MPQ_ATTRIBUTE_MD5
+      } else {
+        0
+      },
+      dwSectorSize: sector_size,
+      dwRawChunkSize: if format_version >= MPQ_FORMAT_VERSION_4 {
+        0x4000
+      } else {
+        0
+      },
+      dwMaxFileCount: max_files_count,
+    };
+
+    let mut handle: HANDLE = ptr::null_mut();
+    unsafe_try_call!(SFileCreateArchive2(
+      cpath.as_ptr(),
+      &mut create_info,
+      &mut handle
+    ));
+
+    Ok(Archive::from_handle(handle))
+  }
+
+  /// Opens a MPQ archive for reading and writing
+  ///
+  /// `STREAM_FLAG_READ_ONLY` is removed from `flags`, use [`Archive::open_read_only`] to open
+  /// an archive that can't be changed.
+  pub fn open<P: AsRef<Path>>(path: P, flags: OpenArchiveFlags) -> Result<Self> {
+    open_archive(
+      path.as_ref(),
+      flags - OpenArchiveFlags::STREAM_FLAG_READ_ONLY,
+    )
+    .map(Archive::from_handle)
+  }
+
+  /// Converts the archive to a read-only archive
+  pub fn into_read_only(self) -> Archive<ReadOnly> {
+    let archive = std::mem::ManuallyDrop::new(self);
+    Archive {
+      handle: archive.handle,
+      panic_on_unflushed_drop: archive.panic_on_unflushed_drop,
+      _mode: PhantomData,
+    }
+  }
+
+  /// Makes dropping the archive panic in debug builds if it has changes that were not
+  /// flushed, to catch code paths that forget to call [`Archive::close`] or [`Archive::flush`].
+  /// The archive is still closed before panicking.
+  pub fn set_panic_on_unflushed_drop(&mut self, enabled: bool) {
+    self.panic_on_unflushed_drop = enabled;
+  }
+
+  /// Flushes in-memory changes to the archive on disk. This function is not necessary to call, as the archive will be flushed automatically when closed
+  pub fn flush(&mut self) -> Result<()> {
+    unsafe_try_call!(SFileFlushArchive(self.handle));
+    Ok(())
+  }
+
+  /// Compacts the archive with an optional progress callback
+  pub fn compact(&mut self) -> Result<()> {
+    unsafe_try_call!(SFileCompactArchive(self.handle, ptr::null_mut(), false));
+    Ok(())
+  }
+
+  /// Changes max file count of the archive
+  pub fn set_max_file_count(&mut self, max_files_count: DWORD) -> Result<()> {
+    unsafe_try_call!(SFileSetMaxFileCount(
+      self.handle,
+      max_files_count.clamp(HASH_TABLE_SIZE_MIN, HASH_TABLE_SIZE_MAX)
+    ));
+    Ok(())
+  }
+
+  /// Creates a new file within the archive
+  pub fn create_file<P: AsRef<MpqPath> + ?Sized>(
+    &mut self,
+    opts: CreateFileOptions<P>,
+  ) -> Result<()> {
+    let cpath = opts.path.as_ref().to_cstring()?;
+
+    let mut file_handle: HANDLE = ptr::null_mut();
+    unsafe_try_call!(SFileCreateFile(
+      self.handle,
+      cpath.as_ptr(),
+      opts.mtime,
+      opts.data.len() as u32,
+      0,
+      opts.flags.bits(),
+      &mut file_handle,
+    ));
+
+    unsafe_try_call!(SFileWriteFile(
+      file_handle,
+      opts.data.as_ptr() as *const _,
+      opts.data.len() as u32,
+      opts.compression.bits(),
+    ));
+
+    unsafe_try_call!(SFileFinishFile(file_handle));
+
+    Ok(())
+  }
+
+  /// Adds a file from the local filesystem to the archive
+  pub fn add_file<P: AsRef<Path>, N: AsRef<MpqPath>>(
+    &mut self,
+    local_path: P,
+    archived_name: N,
+    flags: CreateFileFlags,
+    compression: CompressionFlags,
+  ) -> Result<()> {
+    let clocal_path = util::to_tpath(local_path.as_ref())?;
+    let carchived_name = archived_name.as_ref().to_cstring()?;
+
+    unsafe_try_call!(SFileAddFileEx(
+      self.handle,
+      clocal_path.as_ptr(),
+      carchived_name.as_ptr(),
+      flags.bits(),
+      compression.bits(),
+      MPQ_COMPRESSION_NEXT_SAME,
+    ));
+
+    Ok(())
+  }
+
+  pub fn remove_file<N: AsRef<MpqPath>>(&mut self, path: N) -> Result<bool> {
+    let cpath = path.as_ref().to_cstring()?;
+    let _guard = util::lock();
+    unsafe {
+      let r = SFileRemoveFile(self.handle, cpath.as_ptr(), 0);
+      if !r {
+        let err = SErrGetLastError();
+        if err != ERROR_FILE_NOT_FOUND {
+          return Err(From::from(ErrorCode(err)));
+        }
+      }
+      Ok(r)
+    }
+  }
+
+  /// Renames a file within the archive
+  pub fn rename_file<N: AsRef<MpqPath>, T: AsRef<MpqPath>>(
+    &mut self,
+    old_path: N,
+    new_path: T,
+  ) -> Result<()> {
+    let cold_path = old_path.as_ref().to_cstring()?;
+    let cnew_path = new_path.as_ref().to_cstring()?;
+    unsafe_try_call!(SFileRenameFile(
+      self.handle,
+      cold_path.as_ptr(),
+      cnew_path.as_ptr()
+    ));
+    Ok(())
+  }
+
+  /// Copies a file of `other` as it is stored. StormLib has no call to write stored data, so
+  /// the bytes are written as an uncompressed file and its entry is given the flags and sizes
+  /// of the stored file afterwards. The file is only decoded when the sector sizes differ.
+  pub(crate) fn copy_file<M: ArchiveMode>(
+    &mut self,
+    other: &Archive<M>,
+    name: &MpqPath,
+    new_name: &MpqPath,
+    replace: bool,
+  ) -> Result<()> {
+    let mut file = FileHandle::open(other, name)?;
+    let mut raw = file.read_raw()?;
+    let file_time = file.file_time()?;
+    let source: _TFileEntry = handle_info(file.file_handle, _SFileInfoClass_SFileInfoFileEntry)?;
+    let sector_size: DWORD = handle_info(self.handle, _SFileInfoClass_SFileMpqSectorSize)?;
+
+    let mut flags = CreateFileFlags::from_bits_truncate(raw.flags & !MPQ_FILE_EXISTS);
+    flags.set(CreateFileFlags::MPQ_FILE_REPLACEEXISTING, replace);
+    let sectored = raw.flags & MPQ_FILE_SINGLE_UNIT == 0 && raw.size != 0;
+    if sectored && raw.sector_size != sector_size {
+      let data = file.read_all()?;
+      return self.create_file(CreateFileOptions {
+        path: new_name,
+        data: &data,
+        flags,
+        mtime: file_time,
+        compression: CompressionFlags::from_bits_truncate(raw.compression()?),
+      });
+    }
+
+    let cpath = new_name.to_cstring()?;
+    let mut file_handle: HANDLE = ptr::null_mut();
+    unsafe_try_call!(SFileCreateFile(
+      self.handle,
+      cpath.as_ptr(),
+      file_time,
+      raw.data.len() as u32,
+      0,
+      (flags & CreateFileFlags::MPQ_FILE_REPLACEEXISTING).bits(),
+      &mut file_handle,
+    ));
+    let index: DWORD = handle_info(file_handle, _SFileInfoClass_SFileInfoFileIndex)?;
+    if raw.flags & MPQ_FILE_ENCRYPTED != 0 {
+      let offset: u64 = handle_info(file_handle, _SFileInfoClass_SFileInfoByteOffset)?;
+      let fix_key = raw.flags & MPQ_FILE_FIX_KEY != 0;
+      let key = crate::crypto::file_key(new_name, offset, raw.size as u32, fix_key);
+      if raw.key != Some(key) {
+        raw.rekey(key)?;
+      }
+    }
+    unsafe_try_call!(SFileWriteFile(
+      file_handle,
+      raw.data.as_ptr() as *const _,
+      raw.data.len() as u32,
+      0,
+    ));
+    unsafe_try_call!(SFileFinishFile(file_handle));
+
+    // StormLib took the bytes for plain data, so the entry gets the flags and sizes of the
+    // stored file and the checksums of its content. The checksums stay zero, which StormLib
+    // reads as missing, when `other` has none.
+    let _guard = util::lock();
+    unsafe {
+      let archive = self.handle as *mut _TMPQArchive;
+      if index >= (*archive).dwFileTableSize {
+        return Err(StormError::FileCorrupt);
+      }
+      let entry = &mut *(*archive).pFileTable.add(index as usize);
+      entry.dwFlags = raw.flags;
+      entry.dwFileSize = raw.size as DWORD;
+      entry.dwCmpSize = raw.data.len() as DWORD;
+      entry.dwCrc32 = source.dwCrc32;
+      entry.md5 = source.md5;
+    }
+    Ok(())
+  }
+}
+
+impl Archive<ReadOnly> {
+  /// Opens a MPQ archive for reading only. `STREAM_FLAG_READ_ONLY` is always added to `flags`.
+  pub fn open_read_only<P: AsRef<Path>>(path: P, flags: OpenArchiveFlags) -> Result<Self> {
+    open_archive(
+      path.as_ref(),
+      flags | OpenArchiveFlags::STREAM_FLAG_READ_ONLY,
+    )
+    .map(Archive::from_handle)
+  }
+}
+
+impl<M: ArchiveMode> Archive<M> {
+  fn from_handle(handle: HANDLE) -> Self {
+    Archive {
+      handle,
+      panic_on_unflushed_drop: false,
+      _mode: PhantomData,
+    }
+  }
+
+  /// Closes the archive, writing any pending changes to disk
+  pub fn close(self) -> Result<()> {
+    let mut archive = std::mem::ManuallyDrop::new(self);
+    archive.close_handle()
+  }
+
+  fn close_handle(&mut self) -> Result<()> {
+    unsafe_try_call!(SFileCloseArchive(self.handle));
+    Ok(())
+  }
+
+  /// Returns `true` if the archive has changes that will be written when it's flushed or closed
+  pub fn has_unflushed_changes(&self) -> bool {
+    let _guard = util::lock();
+    // The lock keeps other threads from changing the struct while it's read
+    unsafe { self.get_ref() }
+      .map(|archive| archive.dwFlags & MPQ_FLAG_CHANGED != 0)
+      .unwrap_or(false)
+  }
+
+  /// Quick check if the file exists within MPQ archive, without opening it
+  ///
+  /// Like all methods taking a file name, `path` can be a string, an [`MpqPath`], or the raw
+  /// bytes of a name stored in a legacy codepage (see [`Codepage`]).
+  pub fn has_file<N: AsRef<MpqPath>>(&self, path: N) -> Result<bool> {
+    let cpath = path.as_ref().to_cstring()?;
+    let _guard = util::lock();
+    unsafe {
+      let r = SFileHasFile(self.handle, cpath.as_ptr());
+      if !r {
+        let err = SErrGetLastError();
+        if err != ERROR_FILE_NOT_FOUND {
+          return Err(From::from(ErrorCode(err)));
+        }
+      }
+      Ok(r)
+    }
+  }
+
+  /// Returns StormLib's archive struct
+  ///
+  /// # Safety
+  ///
+  /// StormLib changes the struct in place. The caller must hold no reference across calls
+  /// that can modify the archive, including calls made by other threads through a shared
+  /// archive.
+  pub unsafe fn get_ref(&self) -> Option<&_TMPQArchive> {
+    // Cast the generic HANDLE to a specific pointer type
+    let archive_ptr = self.handle as *const _TMPQArchive;
+
+    // Check if the pointer is null before dereferencing
+    if archive_ptr.is_null() {
+      return None;
+    }
+
+    Some(&*archive_ptr)
+  }
+
+  /// Opens a file from MPQ archive
+  pub fn open_file<N: AsRef<MpqPath>>(&self, path: N) -> Result<File<'_>> {
+    Ok(File {
+      inner: FileHandle::open(self, path.as_ref())?,
+      _archive: PhantomData,
+    })
+  }
+
+  /// Opens a file from MPQ archive. The returned file holds a reference to the archive
+  /// instead of borrowing it, so it can be stored, returned or moved into another thread.
+  pub fn open_file_owned<N: AsRef<MpqPath>>(self: &Arc<Self>, path: N) -> Result<OwnedFile<M>> {
+    Ok(OwnedFile {
+      inner: FileHandle::open(self, path.as_ref())?,
+      archive: self.clone(),
+    })
+  }
+
+  /// Extracts a file from the archive to the local filesystem
+  pub fn extract_file<N: AsRef<MpqPath>, P: AsRef<Path>>(
+    &self,
+    archived_name: N,
+    local_path: P,
+  ) -> Result<()> {
+    let carchived_name = archived_name.as_ref().to_cstring()?;
+    let clocal_path = util::to_tpath(local_path.as_ref())?;
+
+    unsafe_try_call!(SFileExtractFile(
+      self.handle,
+      carchived_name.as_ptr(),
+      clocal_path.as_ptr(),
+      SFILE_OPEN_FROM_MPQ,
+    ));
+
+    Ok(())
+  }
+
+  /// Searches for files within the archive. If `filter` is `None`, all files will be returned
+  pub fn search(&self, filter: Option<&str>) -> Result<Search<'_>> {
+    self.search_with_listfile(filter.unwrap_or("*"), None)
+  }
+
+  /// Searches for files matching the StormLib `mask`, naming files with an additional
+  /// local listfile
+  pub(crate) fn search_with_listfile(
+    &self,
+    mask: &str,
+    listfile: Option<&Path>,
+  ) -> Result<Search<'_>> {
+    Ok(Search {
+      archive_handle: self.handle,
+      filter: CString::new(mask)?,
+      listfile: listfile.map(util::to_tpath).transpose()?,
+      find_handle: None,
+      _archive: PhantomData,
+    })
+  }
+}
+
+impl<M: ArchiveMode> Archive<M> {
+  /// Retrieves the archive header and table information
+  pub fn info(&self) -> Result<ArchiveInfo> {
+    let format_version = {
+      let _guard = util::lock();
+      unsafe { self.get_ref() }
+        .and_then(|archive| unsafe { archive.pHeader.as_ref() })
+        .map(|header| header.wFormatVersion)
+        .unwrap_or_default()
+    };
+
+    Ok(ArchiveInfo {
+      format_version,
+      header_offset: self.get_info(_SFileInfoClass_SFileMpqHeaderOffset)?,
+      archive_size: self.get_info(_SFileInfoClass_SFileMpqArchiveSize64)?,
+      sector_size: self.get_info(_SFileInfoClass_SFileMpqSectorSize)?,
+      file_count: self.get_info(_SFileInfoClass_SFileMpqNumberOfFiles)?,
+      max_file_count: self.get_info(_SFileInfoClass_SFileMpqMaxFileCount)?,
+      hash_table_size: self.get_info(_SFileInfoClass_SFileMpqHashTableSize)?,
+      block_table_size: self.get_info(_SFileInfoClass_SFileMpqBlockTableSize)?,
+      flags: self.get_info(_SFileInfoClass_SFileMpqFlags)?,
+    })
+  }
+
+  /// Reads up to `len` bytes before the MPQ header, such as the header of a Warcraft III map
+  pub(crate) fn read_prefix(&self, len: u64) -> Result<Vec<u8>> {
+    use std::io::Read;
+
+    let header_offset: u64 = self.get_info(_SFileInfoClass_SFileMpqHeaderOffset)?;
+    let mut data = vec![0; len.min(header_offset) as usize];
+    let mut file = std::fs::File::open(archive_path(self.handle)?).map_err(io_error)?;
+    file.read_exact(&mut data).map_err(io_error)?;
+    Ok(data)
+  }
+
+  fn get_info<T: Copy + Default>(&self, class: SFileInfoClass) -> Result<T> {
+    let mut value = T::default();
+    unsafe_try_call!(SFileGetFileInfo(
+      self.handle,
+      class,
+      &mut value as *mut T as *mut c_void,
+      std::mem::size_of::<T>() as DWORD,
+      ptr::null_mut(),
+    ));
+    Ok(value)
+  }
+
+  /// Verifies the checksums stored for a file. Problems are reported in the result flags,
+  /// the `Err` variant is only returned for invalid names.
+  pub fn verify_file<N: AsRef<MpqPath>>(
+    &self,
+    path: N,
+    flags: VerifyFileFlags,
+  ) -> Result<VerifyFileResult> {
+    let cpath = path.as_ref().to_cstring()?;
+    let _guard = util::lock();
+    let r = unsafe { SFileVerifyFile(self.handle, cpath.as_ptr(), flags.bits()) };
+    Ok(VerifyFileResult::from_bits_truncate(r))
+  }
+
+  /// Verifies the digital signature of the archive
+  pub fn verify_signature(&self) -> Result<SignatureStatus> {
+    let _guard = util::lock();
+    match unsafe { SFileVerifyArchive(self.handle) } {
+      ERROR_NO_SIGNATURE => Ok(SignatureStatus::None),
+      ERROR_WEAK_SIGNATURE_OK => Ok(SignatureStatus::WeakOk),
+      ERROR_WEAK_SIGNATURE_ERROR => Ok(SignatureStatus::WeakError),
+      ERROR_STRONG_SIGNATURE_OK => Ok(SignatureStatus::StrongOk),
+      ERROR_STRONG_SIGNATURE_ERROR => Ok(SignatureStatus::StrongError),
+      _ => Err(ErrorCode(unsafe { SErrGetLastError() }).into()),
+    }
+  }
+}
+
+impl<M: ArchiveMode> std::ops::Drop for Archive<M> {
+  fn drop(&mut self) {
+    let unflushed = cfg!(debug_assertions)
+      && self.panic_on_unflushed_drop
+      && !std::thread::panicking()
+      && self.has_unflushed_changes();
+
+    if let Err(err) = self.close_handle() {
+      log::error!("failed to close archive: {}", err);
+    }
+
+    if unflushed {
+      panic!("archive with unflushed changes was dropped, use `Archive::close` instead");
+    }
+  }
+}
+
+fn open_archive(path: &Path, flags: OpenArchiveFlags) -> Result<HANDLE> {
+  let cpath = util::to_tpath(path)?;
+
+  let mut handle: HANDLE = ptr::null_mut();
+  unsafe_try_call!(SFileOpenArchive(
+    cpath.as_ptr(),
+    0,
+    flags.bits(),
+    &mut handle
+  ));
+
+  Ok(handle)
+}
+
+/// Opened file
+#[derive(Debug)]
+pub struct File<'a> {
+  inner: FileHandle,
+  _archive: PhantomData<&'a ()>,
+}
+
+unsafe impl<'a> Send for File<'a> {}
+
+impl<'a> File<'a> {
+  /// Retrieves a size of the file within archive
+  pub fn get_size(&mut self) -> Result<u64> {
+    self.inner.get_size()
+  }
+
+  /// Reads all data from the file
+  pub fn read_all(&mut self) -> Result<Vec<u8>> {
+    self.inner.read_all()
+  }
+
+  /// Reads the data of the file as it is stored in the archive, without decrypting and
+  /// decompressing it. Fails with [`StormError::NotSupported`] unless the archive is a plain
+  /// local file.
+  pub fn read_raw(&mut self) -> Result<RawFile> {
+    self.inner.read_raw()
+  }
+
+  /// `FILETIME` of the file, `0` if it has none
+  pub(crate) fn file_time(&mut self) -> Result<u64> {
+    self.inner.file_time()
+  }
+
+  /// Closes the file
+  pub fn close(self) -> Result<()> {
+    self.inner.close()
+  }
+}
+
+/// Opened file that keeps its archive alive
+#[derive(Debug)]
+pub struct OwnedFile<M: ArchiveMode = ReadWrite> {
+  // Declared first so the file is closed before the last archive reference is dropped
+  inner: FileHandle,
+  archive: Arc<Archive<M>>,
+}
+
+// Every StormLib call made through the file is serialized by the global lock. The file hands
+// out its archive, so it's only shareable when the archive is.
+unsafe impl<M: ArchiveMode> Send for OwnedFile<M> where Archive<M>: Send + Sync {}
+unsafe impl<M: ArchiveMode> Sync for OwnedFile<M> where Archive<M>: Sync {}
+
+impl<M: ArchiveMode> OwnedFile<M> {
+  /// Returns the archive this file was opened from
+  pub fn archive(&self) -> &Arc<Archive<M>> {
+    &self.archive
+  }
+
+  /// Retrieves a size of the file within archive
+  pub fn get_size(&mut self) -> Result<u64> {
+    self.inner.get_size()
+  }
+
+  /// Reads all data from the file
+  pub fn read_all(&mut self) -> Result<Vec<u8>> {
+    self.inner.read_all()
+  }
+
+  /// Reads the data of the file as it is stored in the archive, without decrypting and
+  /// decompressing it. Fails with [`StormError::NotSupported`] unless the archive is a plain
+  /// local file.
+  pub fn read_raw(&mut self) -> Result<RawFile> {
+    self.inner.read_raw()
+  }
+
+  /// Closes the file
+  pub fn close(self) -> Result<()> {
+    self.inner.close()
+  }
+}
+
+/// StormLib file handle shared by the borrowed, owned and shared file types.
+/// The owner must make sure the archive outlives it.
+#[derive(Debug)]
+pub(crate) struct FileHandle {
+  archive_handle: HANDLE,
+  file_handle: HANDLE,
+  size: Option<u64>,
+  need_reset: bool,
+}
+
+impl FileHandle {
+  pub(crate) fn open<M: ArchiveMode>(archive: &Archive<M>, path: &MpqPath) -> Result<Self> {
+    let mut file_handle: HANDLE = ptr::null_mut();
+    let cpath = path.to_cstring()?;
+
+    unsafe_try_call!(SFileOpenFileEx(
+      archive.handle,
+      cpath.as_ptr(),
+      0,
+      &mut file_handle
+    ));
+
+    Ok(FileHandle {
+      archive_handle: archive.handle,
+      file_handle,
+      size: None,
+      need_reset: false,
+    })
+  }
+
+  /// Opens the file in `locale`. StormLib opens the file in the neutral locale if there is
+  /// none in `locale`.
+  pub(crate) fn open_locale<M: ArchiveMode>(
+    archive: &Archive<M>,
+    path: &MpqPath,
+    locale: LCID,
+  ) -> Result<Self> {
+    let mut file_handle: HANDLE = ptr::null_mut();
+    let cpath = path.to_cstring()?;
+
+    // The locale is a process-wide setting, it's restored before the lock is released
+    let _guard = util::lock();
+    unsafe {
+      let previous = SFileSetLocale(locale);
+      let opened = SFileOpenFileEx(archive.handle, cpath.as_ptr(), 0, &mut file_handle);
+      let err = SErrGetLastError();
+      SFileSetLocale(previous);
+      if !opened {
+        return Err(ErrorCode(err).into());
+      }
+    }
+
+    Ok(FileHandle {
+      archive_handle: archive.handle,
+      file_handle,
+      size: None,
+      need_reset: false,
+    })
+  }
+
+  pub(crate) fn close(self) -> Result<()> {
+    let mut file = std::mem::ManuallyDrop::new(self);
+    file.close_handle()
+  }
+
+  fn close_handle(&mut self) -> Result<()> {
+    unsafe_try_call!(SFileCloseFile(self.file_handle));
+    Ok(())
+  }
+
+  pub(crate) fn get_size(&mut self) -> Result<u64> {
+    if let Some(size) = self.size {
+      return Ok(size);
+    }
+
+    let mut high: DWORD = 0;
+    let low = {
+      let _guard = util::lock();
+      let low = unsafe { SFileGetFileSize(self.file_handle, &mut high as *mut DWORD) };
+      if low == SFILE_INVALID_SIZE {
+        return Err(From::from(ErrorCode(unsafe { SErrGetLastError() })));
+      }
+      low
+    };
+    let high = (high as u64) << 32;
+    let size = high | (low as u64);
+    self.size = Some(size);
+    Ok(size)
+  }
+
+  pub(crate) fn read_all(&mut self) -> Result<Vec<u8>> {
+    if self.need_reset {
+      let _guard = util::lock();
+      unsafe {
+        if SFileSetFilePointer(self.file_handle, 0, ptr::null_mut(), 0) == SFILE_INVALID_SIZE {
+          return Err(From::from(ErrorCode(SErrGetLastError())));
+        }
+      }
+    }
+
+    let size = self.get_size()?;
+    let mut buf = vec![0u8; size as usize];
+    let mut read: DWORD = 0;
+    self.need_reset = true;
+
+    unsafe_try_call!(SFileReadFile(
+      self.file_handle,
+      buf.as_mut_ptr() as *mut c_void,
+      size as u32,
+      &mut read as *mut DWORD,
+      ptr::null_mut(),
+    ));
+
+    if (read as u64) < size {
+      buf.truncate(read as usize);
+    }
+
+    Ok(buf)
+  }
+
+  pub(crate) fn file_time(&mut self) -> Result<u64> {
+    handle_info(self.file_handle, _SFileInfoClass_SFileInfoFileTime)
+  }
+
+  /// Reads the stored data of the file from the archive file, StormLib has no call for it
+  pub(crate) fn read_raw(&mut self) -> Result<RawFile> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let file = self.file_handle;
+    let flags: DWORD = handle_info(file, _SFileInfoClass_SFileInfoFlags)?;
+    let size: DWORD = handle_info(file, _SFileInfoClass_SFileInfoFileSize)?;
+    let stored_size: DWORD = if flags & MPQ_FILE_COMPRESS_MASK != 0 {
+      handle_info(file, _SFileInfoClass_SFileInfoCompressedSize)?
+    } else {
+      size
+    };
+    let byte_offset: u64 = handle_info(file, _SFileInfoClass_SFileInfoByteOffset)?;
+    let key = if flags & MPQ_FILE_ENCRYPTED != 0 {
+      Some(handle_info(file, _SFileInfoClass_SFileInfoEncryptionKey)?)
+    } else {
+      None
+    };
+    let header_offset: u64 =
+      handle_info(self.archive_handle, _SFileInfoClass_SFileMpqHeaderOffset)?;
+    let sector_size = handle_info(self.archive_handle, _SFileInfoClass_SFileMpqSectorSize)?;
+
+    let mut file = std::fs::File::open(archive_path(self.archive_handle)?).map_err(io_error)?;
+    let mut data = vec![0; stored_size as usize];
+    file
+      .seek(SeekFrom::Start(header_offset + byte_offset))
+      .and_then(|_| file.read_exact(&mut data))
+      .map_err(io_error)?;
+    Ok(RawFile {
+      data,
+      flags,
+      size: size as u64,
+      sector_size,
+      key,
+    })
+  }
+}
+
+/// Reads a value of a file or archive handle
+fn handle_info<T: Copy>(handle: HANDLE, class: SFileInfoClass) -> Result<T> {
+  let mut value = std::mem::MaybeUninit::<T>::zeroed();
+  unsafe_try_call!(SFileGetFileInfo(
+    handle,
+    class,
+    value.as_mut_ptr() as *mut c_void,
+    std::mem::size_of::<T>() as DWORD,
+    ptr::null_mut(),
+  ));
+  Ok(unsafe { value.assume_init() })
+}
+
+/// Path of the file an archive was opened from, to read its stored data directly
+///
+/// Only archives stored as a plain local file are read this way. Encrypted (MPQE), block4 and
+/// partial streams and archives opened over HTTP fail with [`StormError::NotSupported`].
+fn archive_path(handle: HANDLE) -> Result<std::path::PathBuf> {
+  let stream_flags: DWORD = handle_info(handle, _SFileInfoClass_SFileMpqStreamFlags)?;
+  if stream_flags & STREAM_PROVIDER_MASK != STREAM_PROVIDER_FLAT
+    || stream_flags & BASE_PROVIDER_MASK == BASE_PROVIDER_HTTP
+  {
+    return Err(StormError::NotSupported);
+  }
+
+  let mut needed: DWORD = 0;
+  let _guard = util::lock();
+  // The first call only calculates the needed length
+  unsafe {
+    SFileGetFileInfo(
+      handle,
+      _SFileInfoClass_SFileMpqFileName,
+      ptr::null_mut(),
+      0,
+      &mut needed,
+    )
+  };
+  let mut buf: Vec<TCHAR> = vec![0; needed as usize / std::mem::size_of::<TCHAR>() + 1];
+  let ok = unsafe {
+    SFileGetFileInfo(
+      handle,
+      _SFileInfoClass_SFileMpqFileName,
+      buf.as_mut_ptr() as *mut c_void,
+      (buf.len() * std::mem::size_of::<TCHAR>()) as DWORD,
+      ptr::null_mut(),
+    )
+  };
+  if !ok {
+    return Err(ErrorCode(unsafe { SErrGetLastError() }).into());
+  }
+  util::from_tchars(buf)
+}
+
+impl std::ops::Drop for FileHandle {
+  fn drop(&mut self) {
+    if let Err(err) = self.close_handle() {
+      log::error!("failed to close file: {}", err);
+    }
+  }
+}
+
+/// Search iterator
+#[derive(Debug)]
+pub struct Search<'a> {
+  archive_handle: HANDLE,
+  filter: CString,
+  listfile: Option<util::TPath>,
+  find_handle: Option<HANDLE>,
+  _archive: PhantomData<&'a ()>,
+}
+
+impl<'a> Iterator for Search<'a> {
+  type Item = SFILE_FIND_DATA;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    let mut file_data: SFILE_FIND_DATA = unsafe { std::mem::zeroed() };
+    let _guard = util::lock();
+
+    if let Some(handle) = self.find_handle {
+      let result = unsafe { SFileFindNextFile(handle, &mut file_data) };
+      if result {
+        return Some(file_data);
+      }
+    } else {
+      let handle = unsafe {
+        SFileFindFirstFile(
+          self.archive_handle,
+          self.filter.as_ptr(),
+          &mut file_data,
+          self
+            .listfile
+            .as_ref()
+            .map_or(ptr::null(), |listfile| listfile.as_ptr()),
+        )
+      };
+      if !handle.is_null() {
+        self.find_handle = Some(handle);
+        return Some(file_data);
+      }
+    }
+
+    None
+  }
+}
+
+impl<'a> Drop for Search<'a> {
+  fn drop(&mut self) {
+    if let Some(handle) = self.find_handle {
+      let _guard = util::lock();
+      unsafe {
+        SFileFindClose(handle);
+      }
+    }
+  }
+}
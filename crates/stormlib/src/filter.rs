@@ -0,0 +1,323 @@
+//! Search filters
+//!
+//! StormLib only matches `*` and `?` wildcards against the whole name. [`SearchFilter`]
+//! narrows the results further with glob patterns, regular expressions, extensions, sizes
+//! and file flags. All conditions of a filter must match.
+
+use std::collections::HashSet;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+
+use regex::bytes::{Regex, RegexBuilder};
+use stormlib_sys::SFILE_FIND_DATA;
+
+use crate::error::*;
+use crate::{Archive, ArchiveMode, CreateFileFlags, FindDataExt, MpqPath};
+
+/// Conditions for [`Archive::search_filtered`]
+///
+/// ```no_run
+/// # use stormlib::*;
+/// # fn main() -> stormlib::error::Result<()> {
+/// # let archive = Archive::open("map.w3x", OpenArchiveFlags::empty())?;
+/// // All .mdx under Units\ larger than 1MB
+/// let filter = SearchFilter::new().glob("Units/**/*.mdx")?.size(1 << 20..);
+/// for data in archive.search_filtered(&filter)? {
+///   println!("{}", data.name(Codepage::Utf8));
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct SearchFilter {
+  mask: Option<String>,
+  listfile: Option<PathBuf>,
+  patterns: Vec<Regex>,
+  extensions: Option<HashSet<Vec<u8>>>,
+  size: (Bound<u64>, Bound<u64>),
+  with_flags: u32,
+  without_flags: u32,
+}
+
+impl Default for SearchFilter {
+  fn default() -> Self {
+    SearchFilter {
+      mask: None,
+      listfile: None,
+      patterns: vec![],
+      extensions: None,
+      size: (Bound::Unbounded, Bound::Unbounded),
+      with_flags: 0,
+      without_flags: 0,
+    }
+  }
+}
+
+impl SearchFilter {
+  /// Creates a filter matching all files
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// StormLib wildcard mask passed to `SFileFindFirstFile`, `*` by default
+  pub fn mask(mut self, mask: &str) -> Self {
+    self.mask = Some(mask.to_string());
+    self
+  }
+
+  /// Additional listfile passed to `SFileFindFirstFile`, used to name files missing from the
+  /// archive's `(listfile)`
+  pub fn listfile<P: AsRef<Path>>(mut self, path: P) -> Self {
+    self.listfile = Some(path.as_ref().to_path_buf());
+    self
+  }
+
+  /// Adds a glob pattern matched case-insensitively against the whole name
+  ///
+  /// Both `/` and `\` separate directories. `*` and `?` don't match separators, `**`
+  /// matches any number of directories, `[abc]`, `[a-z]` and `[!abc]` match character
+  /// classes, and `{mdx,mdl}` matches any of the alternatives.
+  pub fn glob(mut self, pattern: &str) -> Result<Self> {
+    self
+      .patterns
+      .push(compile(&glob_to_regex(pattern)?, false)?);
+    Ok(self)
+  }
+
+  /// Adds a regular expression matched case-insensitively against the name. The pattern
+  /// is not anchored and directories are separated by `\`.
+  pub fn regex(mut self, pattern: &str) -> Result<Self> {
+    self.patterns.push(compile(pattern, true)?);
+    Ok(self)
+  }
+
+  /// Only matches names with one of the extensions, compared case-insensitively, with or
+  /// without the leading dot
+  pub fn extensions<I, S>(mut self, extensions: I) -> Self
+  where
+    I: IntoIterator<Item = S>,
+    S: AsRef<[u8]>,
+  {
+    let set = self.extensions.get_or_insert_with(HashSet::new);
+    for ext in extensions {
+      let ext = ext.as_ref();
+      let ext = ext.strip_prefix(b".").unwrap_or(ext);
+      set.insert(ext.to_ascii_uppercase());
+    }
+    self
+  }
+
+  /// Only matches files with an uncompressed size in `range`
+  pub fn size<R: RangeBounds<u64>>(mut self, range: R) -> Self {
+    self.size = (range.start_bound().cloned(), range.end_bound().cloned());
+    self
+  }
+
+  /// Only matches files with all of the flags set
+  pub fn with_flags(mut self, flags: CreateFileFlags) -> Self {
+    self.with_flags |= flags.bits();
+    self
+  }
+
+  /// Only matches files with none of the flags set
+  pub fn without_flags(mut self, flags: CreateFileFlags) -> Self {
+    self.without_flags |= flags.bits();
+    self
+  }
+
+  /// Checks a search result against the conditions, except for the mask
+  pub fn matches(&self, data: &SFILE_FIND_DATA) -> bool {
+    let name = data.name_bytes();
+    let flags = data.dwFileFlags;
+    let extension = match &self.extensions {
+      Some(set) => MpqPath::new(name)
+        .extension()
+        .is_some_and(|ext| set.contains(&ext.as_bytes().to_ascii_uppercase())),
+      None => true,
+    };
+
+    self.size.contains(&(data.dwFileSize as u64))
+      && flags & self.with_flags == self.with_flags
+      && flags & self.without_flags == 0
+      && extension
+      && self.patterns.iter().all(|re| re.is_match(name))
+  }
+}
+
+impl<M: ArchiveMode> Archive<M> {
+  /// Searches for files matching the filter
+  pub fn search_filtered<'a>(
+    &'a self,
+    filter: &'a SearchFilter,
+  ) -> Result<impl Iterator<Item = SFILE_FIND_DATA> + 'a> {
+    let search = self.search_with_listfile(
+      filter.mask.as_deref().unwrap_or("*"),
+      filter.listfile.as_deref(),
+    )?;
+    Ok(search.filter(move |data| filter.matches(data)))
+  }
+}
+
+fn compile(pattern: &str, unicode: bool) -> Result<Regex> {
+  RegexBuilder::new(pattern)
+    .case_insensitive(true)
+    .unicode(unicode)
+    .build()
+    .map_err(|err| StormError::InvalidPattern(err.to_string()))
+}
+
+/// Translates a glob pattern to an anchored regular expression
+fn glob_to_regex(glob: &str) -> Result<String> {
+  let invalid = || StormError::InvalidPattern(format!("invalid glob `{}`", glob));
+  let is_sep = |c: char| c == '/' || c == '\\';
+
+  let mut re = String::from("(?s)^");
+  let mut braces = 0;
+  let mut chars = glob.chars().peekable();
+  while let Some(c) = chars.next() {
+    match c {
+      '*' if chars.peek() == Some(&'*') => {
+        chars.next();
+        if chars.peek().is_some_and(|&c| is_sep(c)) {
+          chars.next();
+          re.push_str(r"(?:.*\\)?");
+        } else {
+          re.push_str(".*");
+        }
+      }
+      '*' => re.push_str(r"[^\\]*"),
+      '?' => re.push_str(r"[^\\]"),
+      '/' | '\\' => re.push_str(r"\\"),
+      '[' => {
+        re.push('[');
+        // Negated classes never match either separator
+        if chars.peek() == Some(&'!') {
+          chars.next();
+          re.push_str(r"^\\/");
+        }
+        let mut empty = true;
+        loop {
+          match chars.next().ok_or_else(invalid)? {
+            ']' if !empty => break,
+            '/' | '\\' => re.push_str(r"\\"),
+            c @ ('[' | '&' | '~' | '^') => {
+              re.push('\\');
+              re.push(c);
+            }
+            c => re.push(c),
+          }
+          empty = false;
+        }
+        re.push(']');
+      }
+      '{' => {
+        braces += 1;
+        re.push_str("(?:");
+      }
+      '}' if braces > 0 => {
+        braces -= 1;
+        re.push(')');
+      }
+      ',' if braces > 0 => re.push('|'),
+      c => re.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+    }
+  }
+  if braces > 0 {
+    return Err(invalid());
+  }
+  re.push('$');
+  Ok(re)
+}
+
+#[cfg(test)]
+fn find_data(name: &str, size: u32, flags: u32) -> SFILE_FIND_DATA {
+  let mut data: SFILE_FIND_DATA = unsafe { std::mem::zeroed() };
+  for (dst, &src) in data.cFileName.iter_mut().zip(name.as_bytes()) {
+    *dst = src as _;
+  }
+  data.dwFileSize = size;
+  data.dwFileFlags = flags;
+  data
+}
+
+#[test]
+fn test_glob() {
+  let filter = SearchFilter::new().glob("Units/**/*.{mdx,MDL}").unwrap();
+  assert!(filter.matches(&find_data("Units\\Human\\Footman.mdx", 0, 0)));
+  assert!(filter.matches(&find_data("units\\a\\b\\c.mdl", 0, 0)));
+  assert!(filter.matches(&find_data("Units\\c.MDX", 0, 0)));
+  assert!(!filter.matches(&find_data("Units\\c.blp", 0, 0)));
+  assert!(!filter.matches(&find_data("Doodads\\Units\\c.mdx", 0, 0)));
+
+  let filter = SearchFilter::new().glob("war3map.[!j]*").unwrap();
+  assert!(filter.matches(&find_data("war3map.w3i", 0, 0)));
+  assert!(!filter.matches(&find_data("war3map.j", 0, 0)));
+
+  let filter = SearchFilter::new().glob("a[!b]c").unwrap();
+  assert!(filter.matches(&find_data("axc", 0, 0)));
+  assert!(!filter.matches(&find_data("a\\c", 0, 0)));
+  assert!(!filter.matches(&find_data("a/c", 0, 0)));
+
+  let filter = SearchFilter::new().glob("a[/_]c").unwrap();
+  assert!(filter.matches(&find_data("a\\c", 0, 0)));
+  assert!(filter.matches(&find_data("a_c", 0, 0)));
+
+  let filter = SearchFilter::new().glob("*.?").unwrap();
+  assert!(filter.matches(&find_data("war3map.j", 0, 0)));
+  assert!(!filter.matches(&find_data("Scripts\\war3map.j", 0, 0)));
+
+  let filter = SearchFilter::new().glob("中文*").unwrap();
+  assert!(filter.matches(&find_data("中文.txt", 0, 0)));
+
+  assert!(SearchFilter::new().glob("{a,b").is_err());
+  assert!(SearchFilter::new().glob("[ab").is_err());
+}
+
+#[test]
+fn test_filter_conditions() {
+  let filter = SearchFilter::new()
+    .regex(r"^units\\")
+    .unwrap()
+    .extensions(["mdx", ".BLP"])
+    .size(1 << 20..)
+    .without_flags(CreateFileFlags::MPQ_FILE_ENCRYPTED);
+  let compressed = CreateFileFlags::MPQ_FILE_COMPRESS.bits();
+  let encrypted = CreateFileFlags::MPQ_FILE_ENCRYPTED.bits();
+
+  assert!(filter.matches(&find_data("Units\\Footman.mdx", 2 << 20, compressed)));
+  assert!(filter.matches(&find_data("Units\\Footman.blp", 1 << 20, 0)));
+  assert!(!filter.matches(&find_data("Units\\Footman.mdx", 1000, 0)));
+  assert!(!filter.matches(&find_data("Units\\Footman.mdl", 2 << 20, 0)));
+  assert!(!filter.matches(&find_data("Units\\Footman.mdx", 2 << 20, encrypted)));
+  assert!(!filter.matches(&find_data("Doodads\\Tree.mdx", 2 << 20, 0)));
+
+  let filter = SearchFilter::new()
+    .size(..=100)
+    .with_flags(CreateFileFlags::MPQ_FILE_COMPRESS);
+  assert!(filter.matches(&find_data("a", 100, compressed)));
+  assert!(!filter.matches(&find_data("a", 100, 0)));
+  assert!(!filter.matches(&find_data("a", 101, compressed)));
+
+  assert!(matches!(
+    SearchFilter::new().regex("("),
+    Err(StormError::InvalidPattern(_))
+  ));
+}
+
+#[test]
+fn test_search_filtered() {
+  use crate::{MpqPathBuf, OpenArchiveFlags};
+
+  let archive =
+    Archive::open_read_only("../../samples/test_tft.w3x", OpenArchiveFlags::empty()).unwrap();
+  let filter = SearchFilter::new()
+    .mask("war3map.*")
+    .extensions(["j", "w3i"])
+    .size(1000..);
+  let names: Vec<_> = archive
+    .search_filtered(&filter)
+    .unwrap()
+    .map(|data| data.path().to_mpq_path_buf())
+    .collect();
+  assert_eq!(names, [MpqPathBuf::from("war3map.j")]);
+}
@@ -0,0 +1,79 @@
+/// Information about an opened archive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchiveInfo {
+  /// MPQ format version, `0` to `3` for format 1 to 4
+  pub format_version: u16,
+  /// Offset of the MPQ header from the start of the file
+  pub header_offset: u64,
+  /// Size of the archive, counted from the MPQ header
+  pub archive_size: u64,
+  /// Size of a file sector in bytes
+  pub sector_size: u32,
+  /// Number of files in the archive, including internal files
+  pub file_count: u32,
+  /// Maximum number of files the archive can hold
+  pub max_file_count: u32,
+  /// Number of entries in the hash table
+  pub hash_table_size: u32,
+  /// Number of entries in the block table
+  pub block_table_size: u32,
+  /// `MPQ_FLAG_*` flags of the opened archive
+  pub flags: u32,
+}
+
+/// Result of verifying the digital signature of an archive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureStatus {
+  /// The archive is not signed
+  None,
+  /// The archive has a weak signature that matches its content
+  WeakOk,
+  /// The archive has a weak signature that doesn't match its content
+  WeakError,
+  /// The archive has a strong signature that matches its content
+  StrongOk,
+  /// The archive has a strong signature that doesn't match its content
+  StrongError,
+}
+
+impl SignatureStatus {
+  /// Returns `true` if the archive has a signature that doesn't match its content
+  pub fn is_error(self) -> bool {
+    matches!(
+      self,
+      SignatureStatus::WeakError | SignatureStatus::StrongError
+    )
+  }
+}
+
+#[test]
+fn test_info() {
+  use crate::{Archive, OpenArchiveFlags};
+
+  let archive = Archive::open_read_only(
+    "../../samples/test_tft.w3x",
+    OpenArchiveFlags::MPQ_OPEN_NO_LISTFILE | OpenArchiveFlags::MPQ_OPEN_NO_ATTRIBUTES,
+  )
+  .unwrap();
+  let info = archive.info().unwrap();
+  assert_eq!(info.format_version, 0);
+  assert_eq!(info.header_offset, 0x200);
+  assert_eq!(info.archive_size, 0x392c);
+  assert_eq!(info.sector_size, 0x1000);
+  assert_eq!(info.hash_table_size, 0x40);
+  assert_eq!(info.block_table_size, 0x11);
+}
+
+#[test]
+fn test_verify() {
+  use crate::{Archive, OpenArchiveFlags, VerifyFileFlags, VerifyFileResult};
+
+  let archive =
+    Archive::open_read_only("../../samples/test_tft.w3x", OpenArchiveFlags::empty()).unwrap();
+  let result = archive
+    .verify_file("war3map.j", VerifyFileFlags::SFILE_VERIFY_ALL)
+    .unwrap();
+  assert!(!result.intersects(VerifyFileResult::VERIFY_FILE_ERROR_MASK));
+  assert!(result.contains(VerifyFileResult::VERIFY_FILE_HAS_CHECKSUM));
+  assert_eq!(archive.verify_signature().unwrap(), SignatureStatus::None);
+}
@@ -1,351 +1,147 @@
-use std::ffi::*;
-use std::path::Path;
-use std::ptr;
-use stormlib_sys::*;
+// Tests compare with literal bools
+#![cfg_attr(test, allow(clippy::bool_assert_comparison))]
 
+#[cfg(not(feature = "pure-rust"))]
 #[macro_use]
 mod util;
 
 mod constants;
 pub use constants::*;
 
-pub mod error;
-use error::*;
-
-pub const STORMLIB_VERSION: u32 = stormlib_sys::STORMLIB_VERSION;
-
-pub struct CreateFileOptions<'a> {
-  pub path: &'a str,
-  pub data: &'a Vec<u8>,
-  pub flags: CreateFileFlags,
-  pub mtime: u64,
-  pub compression: CompressionFlags,
-}
+#[cfg(feature = "tokio")]
+mod async_archive;
+#[cfg(feature = "tokio")]
+pub use async_archive::{AsyncArchive, AsyncFile};
 
-/// MPQ archive
-#[derive(Debug)]
-pub struct Archive {
-  handle: HANDLE,
-}
-
-unsafe impl Send for Archive {}
-
-impl Archive {
-  /// Creates new MPQ archive
-  pub fn create<P: AsRef<Path>>(
-    path: P,
-    flags: CreateArchiveFlags,
-    max_files_count: DWORD,
-  ) -> Result<Self> {
-    #[cfg(not(target_os = "windows"))]
-    let cpath = {
-      let pathstr = path.as_ref().to_str().ok_or_else(|| StormError::NonUtf8)?;
-      CString::new(pathstr)?
-    };
-    #[cfg(target_os = "windows")]
-    let cpath = {
-      use widestring::U16CString;
-      U16CString::from_os_str(path.as_ref())
-        .map_err(|_| StormError::InteriorNul)?
-        .into_vec()
-    };
-
-    let mut handle: HANDLE = ptr::null_mut();
-    unsafe_try_call!(SFileCreateArchive(
-      cpath.as_ptr(),
-      flags.bits(),
-      max_files_count,
-      &mut handle
-    ));
-
-    Ok(Archive { handle })
-  }
+pub mod crypto;
 
-  /// Opens a MPQ archive
-  pub fn open<P: AsRef<Path>>(path: P, flags: OpenArchiveFlags) -> Result<Self> {
-    #[cfg(not(target_os = "windows"))]
-    let cpath = {
-      let pathstr = path.as_ref().to_str().ok_or_else(|| StormError::NonUtf8)?;
-      CString::new(pathstr)?
-    };
-    #[cfg(target_os = "windows")]
-    let cpath = {
-      use widestring::U16CString;
-      U16CString::from_os_str(path.as_ref())
-        .map_err(|_| StormError::InteriorNul)?
-        .into_vec()
-    };
-
-    let mut handle: HANDLE = ptr::null_mut();
-    unsafe_try_call!(SFileOpenArchive(
-      cpath.as_ptr(),
-      0,
-      flags.bits(),
-      &mut handle
-    ));
+mod copy;
 
-    Ok(Archive { handle })
-  }
+mod diff;
+pub use diff::{diff, diff_with, ArchiveDiff, DiffMode, FileChange, FileMetadata};
 
-  /// Flushes in-memory changes to the archive on disk. This function is not necessary to call, as the archive will be flushed automatically when closed
-  pub fn flush(&self) -> Result<()> {
-    unsafe_try_call!(SFileFlushArchive(self.handle));
-    Ok(())
-  }
-
-  /// Compacts the archive with an optional progress callback
-  pub fn compact(&self) -> Result<()> {
-    unsafe_try_call!(SFileCompactArchive(self.handle, ptr::null_mut(), false));
-    Ok(())
-  }
-
-  /// Quick check if the file exists within MPQ archive, without opening it
-  pub fn has_file(&self, path: &str) -> Result<bool> {
-    let cpath = CString::new(path)?;
-    unsafe {
-      let r = SFileHasFile(self.handle, cpath.as_ptr());
-      if !r {
-        let err = SErrGetLastError();
-        if err != ERROR_FILE_NOT_FOUND {
-          return Err(From::from(ErrorCode(err)));
-        }
-      }
-      Ok(r)
-    }
-  }
-
-  pub fn get_ref(&self) -> Option<&'static _TMPQArchive> {
-    // Cast the generic HANDLE to a specific pointer type
-    let archive_ptr = self.handle as *const _TMPQArchive;
-
-    // Check if the pointer is null before dereferencing
-    if archive_ptr.is_null() {
-      return None;
-    }
-
-    unsafe { Some(&*archive_ptr) }
-  }
-
-  /// Changes max file count of the archive
-  pub fn set_max_file_count(&self, max_files_count: DWORD) -> Result<()> {
-    unsafe_try_call!(SFileSetMaxFileCount(
-      self.handle,
-      max_files_count
-        .min(HASH_TABLE_SIZE_MAX)
-        .max(HASH_TABLE_SIZE_MIN)
-    ));
-    Ok(())
-  }
-
-  /// Creates a new file within the archive
-  pub fn create_file<'a>(&'a self, opts: CreateFileOptions) -> Result<()> {
-    let cpath = CString::new(opts.path)?;
-
-    let mut file_handle: HANDLE = ptr::null_mut();
-    unsafe_try_call!(SFileCreateFile(
-      self.handle,
-      cpath.as_ptr(),
-      opts.mtime,
-      opts.data.len() as u32,
-      0,
-      opts.flags.bits(),
-      &mut file_handle,
-    ));
-
-    unsafe_try_call!(SFileWriteFile(
-      file_handle,
-      opts.data.as_ptr() as *const _,
-      opts.data.len() as u32,
-      opts.compression.bits(),
-    ));
+pub mod error;
+#[cfg(test)]
+use error::*;
 
-    unsafe_try_call!(SFileFinishFile(file_handle));
+#[cfg(not(feature = "pure-rust"))]
+mod ffi;
+#[cfg(not(feature = "pure-rust"))]
+use ffi::FileHandle;
+#[cfg(not(feature = "pure-rust"))]
+pub use ffi::{Archive, File, OwnedFile, Search};
 
-    Ok(())
-  }
+#[cfg(feature = "pure-rust")]
+mod native;
+#[cfg(feature = "pure-rust")]
+use native::FileHandle;
+#[cfg(feature = "pure-rust")]
+pub use native::{Archive, File, OwnedFile, Search};
 
-  /// Opens a file from MPQ archive
-  pub fn open_file<'a>(&'a self, path: &str) -> Result<File<'a>> {
-    let mut file_handle: HANDLE = ptr::null_mut();
-    let cpath = CString::new(path)?;
+#[cfg(feature = "serde")]
+pub mod manifest;
+#[cfg(feature = "serde")]
+pub use manifest::{Manifest, ManifestEntry};
 
-    unsafe_try_call!(SFileOpenFileEx(
-      self.handle,
-      cpath.as_ptr(),
-      0,
-      &mut file_handle
-    ));
+pub mod filter;
+pub use filter::SearchFilter;
 
-    Ok(File {
-      _archive: self,
-      file_handle,
-      size: None,
-      need_reset: false,
-    })
-  }
+mod mode;
+pub use mode::{ArchiveMode, ReadOnly, ReadWrite};
 
-  pub fn remove_file(&self, path: &str) -> Result<bool> {
-    let cpath = CString::new(path)?;
-    unsafe {
-      let r = SFileRemoveFile(self.handle, cpath.as_ptr(), 0);
-      if !r {
-        let err = SErrGetLastError();
-        if err != ERROR_FILE_NOT_FOUND {
-          return Err(From::from(ErrorCode(err)));
-        }
-      }
-      Ok(r)
-    }
-  }
+mod info;
+pub use info::{ArchiveInfo, SignatureStatus};
 
-  /// Searches for files within the archive. If `search_phrase` is `None`, all files will be returned
-  pub fn search<'a>(&'a self, filter: Option<&str>) -> Result<Search<'a>> {
-    let cfilter = CString::new(filter.unwrap_or("*"))?;
-    Ok(Search {
-      archive: self,
-      filter: cfilter,
-      find_handle: None,
-    })
-  }
-}
+pub mod name;
+pub use name::{Codepage, FindDataExt};
 
-impl std::ops::Drop for Archive {
-  fn drop(&mut self) {
-    unsafe {
-      SFileCloseArchive(self.handle);
-    }
-  }
-}
+pub mod path;
+pub use path::{MpqPath, MpqPathBuf};
 
-/// Opened file
-#[derive(Debug)]
-pub struct File<'a> {
-  _archive: &'a Archive,
-  file_handle: HANDLE,
-  size: Option<u64>,
-  need_reset: bool,
-}
+pub mod reproducible;
+pub use reproducible::ReproducibleBuilder;
 
-unsafe impl<'a> Send for File<'a> {}
+pub mod raw;
+pub use raw::{RawFile, RawSector, RawSectors};
 
-impl<'a> File<'a> {
-  /// Retrieves a size of the file within archive
-  pub fn get_size(&mut self) -> Result<u64> {
-    if let Some(size) = self.size.clone() {
-      return Ok(size);
-    }
+mod shared;
+pub use shared::{SharedArchive, SharedFile};
 
-    let mut high: DWORD = 0;
-    let low = unsafe { SFileGetFileSize(self.file_handle, &mut high as *mut DWORD) };
-    if low == SFILE_INVALID_SIZE {
-      return Err(From::from(ErrorCode(unsafe { SErrGetLastError() })));
-    }
-    let high = (high as u64) << 32;
-    let size = high | (low as u64);
-    self.size = Some(size);
-    return Ok(size);
-  }
+mod stack;
+pub use stack::{ArchiveStack, StackEntry};
 
-  /// Reads all data from the file
-  pub fn read_all(&mut self) -> Result<Vec<u8>> {
-    if self.need_reset {
-      unsafe {
-        if SFileSetFilePointer(self.file_handle, 0, ptr::null_mut(), 0) == SFILE_INVALID_SIZE {
-          return Err(From::from(ErrorCode(SErrGetLastError())));
-        }
-      }
-    }
+pub mod tree;
+pub use tree::{ArchiveTree, TreeNode};
 
-    let size = self.get_size()?;
-    let mut buf = Vec::<u8>::with_capacity(size as usize);
-    buf.resize(buf.capacity(), 0);
-    let mut read: DWORD = 0;
-    self.need_reset = true;
-
-    unsafe_try_call!(SFileReadFile(
-      self.file_handle,
-      std::mem::transmute(buf.as_mut_ptr()),
-      size as u32,
-      &mut read as *mut DWORD,
-      ptr::null_mut(),
-    ));
+pub mod w3x;
+pub use w3x::{MapHeader, MapInfo, TriggerStrings};
 
-    if (read as u64) < size {
-      buf.truncate(read as usize);
-    }
+pub const STORMLIB_VERSION: u32 = stormlib_sys::STORMLIB_VERSION;
 
-    Ok(buf)
-  }
+/// Options of [`Archive::create_file`]. The path can be a `&str` or any other name type.
+pub struct CreateFileOptions<'a, P: AsRef<MpqPath> + ?Sized = MpqPath> {
+  pub path: &'a P,
+  pub data: &'a Vec<u8>,
+  pub flags: CreateFileFlags,
+  pub mtime: u64,
+  pub compression: CompressionFlags,
 }
 
-impl<'a> std::ops::Drop for File<'a> {
-  fn drop(&mut self) {
-    unsafe {
-      SFileCloseFile(self.file_handle);
-    }
-  }
-}
+#[test]
+fn test_read() {
+  let archive = Archive::open(
+    "../../samples/test_tft.w3x",
+    OpenArchiveFlags::MPQ_OPEN_NO_LISTFILE | OpenArchiveFlags::MPQ_OPEN_NO_ATTRIBUTES,
+  )
+  .unwrap();
 
-/// Search iterator
-#[derive(Debug)]
-pub struct Search<'a> {
-  archive: &'a Archive,
-  filter: CString,
-  find_handle: Option<HANDLE>,
+  assert_eq!(archive.has_file("invalid").unwrap(), false);
+  assert_eq!(archive.has_file("war3map.j").unwrap(), true);
+  let mut f = archive.open_file("war3map.j").unwrap();
+  assert_eq!(f.get_size().unwrap(), 14115);
+  assert_eq!(
+    f.read_all().unwrap(),
+    std::fs::read("../../samples/war3map.j").unwrap()
+  );
 }
 
-impl<'a> Iterator for Search<'a> {
-  type Item = SFILE_FIND_DATA;
-
-  fn next(&mut self) -> Option<Self::Item> {
-    let mut file_data: SFILE_FIND_DATA = unsafe { std::mem::zeroed() };
-
-    if let Some(handle) = self.find_handle {
-      let result = unsafe { SFileFindNextFile(handle, &mut file_data) };
-      if result {
-        return Some(file_data);
-      }
-    } else {
-      let handle = unsafe {
-        SFileFindFirstFile(
-          self.archive.handle,
-          self.filter.as_ptr(),
-          &mut file_data,
-          ptr::null_mut(),
-        )
-      };
-      if !handle.is_null() {
-        self.find_handle = Some(handle);
-        return Some(file_data);
-      }
-    }
-
-    None
+#[test]
+fn test_read_owned() {
+  use std::sync::Arc;
+
+  fn open_war3map_j() -> Result<OwnedFile> {
+    let archive = Arc::new(Archive::open(
+      "../../samples/test_tft.w3x",
+      OpenArchiveFlags::MPQ_OPEN_NO_LISTFILE | OpenArchiveFlags::MPQ_OPEN_NO_ATTRIBUTES,
+    )?);
+    archive.open_file_owned("war3map.j")
   }
-}
 
-impl<'a> Drop for Search<'a> {
-  fn drop(&mut self) {
-    if let Some(handle) = self.find_handle {
-      unsafe {
-        SFileFindClose(handle);
-      }
-    }
-  }
+  let mut f = open_war3map_j().unwrap();
+  let data = std::thread::spawn(move || f.read_all().unwrap())
+    .join()
+    .unwrap();
+  assert_eq!(data, std::fs::read("../../samples/war3map.j").unwrap());
 }
 
 #[test]
-fn test_read() {
+fn test_read_only() {
+  let flags = OpenArchiveFlags::MPQ_OPEN_NO_LISTFILE | OpenArchiveFlags::MPQ_OPEN_NO_ATTRIBUTES;
+
+  // `open` always opens a writable archive
   let archive = Archive::open(
     "../../samples/test_tft.w3x",
-    OpenArchiveFlags::MPQ_OPEN_NO_LISTFILE | OpenArchiveFlags::MPQ_OPEN_NO_ATTRIBUTES,
+    flags | OpenArchiveFlags::MPQ_OPEN_READ_ONLY,
   )
   .unwrap();
+  assert_eq!(
+    archive.info().unwrap().flags & stormlib_sys::MPQ_FLAG_READ_ONLY,
+    0
+  );
+  archive.close().unwrap();
 
-  assert_eq!(archive.has_file("invalid").unwrap(), false);
-  assert_eq!(archive.has_file("war3map.j").unwrap(), true);
+  let archive = Archive::open_read_only("../../samples/test_tft.w3x", flags).unwrap();
   let mut f = archive.open_file("war3map.j").unwrap();
-  assert_eq!(f.get_size().unwrap(), 14115);
   assert_eq!(
     f.read_all().unwrap(),
     std::fs::read("../../samples/war3map.j").unwrap()
@@ -362,7 +158,7 @@ fn test_create_archive() {
   let result = std::panic::catch_unwind(|| {
     {
       // Create a new archive
-      let archive =
+      let mut archive =
         Archive::create(archive_path, CreateArchiveFlags::MPQ_CREATE_LISTFILE, 1000).unwrap();
 
       // Create a new file within the archive
@@ -382,7 +178,8 @@ fn test_create_archive() {
 
     {
       // Reopen the archive
-      let archive = Archive::open(archive_path, OpenArchiveFlags::STREAM_PROVIDER_FLAT).unwrap();
+      let mut archive =
+        Archive::open(archive_path, OpenArchiveFlags::STREAM_PROVIDER_FLAT).unwrap();
 
       // Ensure the file exists within the archive
       assert_eq!(archive.has_file(file_path).unwrap(), true);
@@ -421,9 +218,188 @@ fn test_create_archive() {
   result.unwrap();
 }
 
+#[test]
+fn test_close() {
+  let archive_path = "../../samples/test_close.mpq";
+
+  let result = std::panic::catch_unwind(|| {
+    let mut archive =
+      Archive::create(archive_path, CreateArchiveFlags::MPQ_CREATE_LISTFILE, 16).unwrap();
+    archive
+      .create_file(CreateFileOptions {
+        path: "test.txt",
+        data: &b"Hello, MPQ!".to_vec(),
+        flags: CreateFileFlags::MPQ_FILE_COMPRESS,
+        mtime: 0,
+        compression: CompressionFlags::MPQ_COMPRESSION_ZLIB,
+      })
+      .unwrap();
+    assert!(archive.has_unflushed_changes());
+    archive.close().unwrap();
+
+    let archive = Archive::open_read_only(archive_path, OpenArchiveFlags::empty()).unwrap();
+    assert!(!archive.has_unflushed_changes());
+    let mut file = archive.open_file("test.txt").unwrap();
+    assert_eq!(file.read_all().unwrap(), b"Hello, MPQ!");
+    file.close().unwrap();
+    archive.close().unwrap();
+  });
+
+  // Clean up
+  std::fs::remove_file(archive_path).unwrap();
+
+  // Propagate any panic that occurred during the test
+  result.unwrap();
+}
+
+#[test]
+fn test_rename_file() {
+  let archive_path = "../../samples/test_rename_file.mpq";
+
+  let result = std::panic::catch_unwind(|| {
+    let mut archive =
+      Archive::create(archive_path, CreateArchiveFlags::MPQ_CREATE_LISTFILE, 16).unwrap();
+    archive
+      .create_file(CreateFileOptions {
+        path: "test.txt",
+        data: &b"Hello, MPQ!".to_vec(),
+        flags: CreateFileFlags::MPQ_FILE_COMPRESS | CreateFileFlags::MPQ_FILE_ENCRYPTED,
+        mtime: 0,
+        compression: CompressionFlags::MPQ_COMPRESSION_ZLIB,
+      })
+      .unwrap();
+    archive.rename_file("test.txt", "dir\\renamed.txt").unwrap();
+    assert!(!archive.has_file("test.txt").unwrap());
+    assert!(archive.has_file("dir\\renamed.txt").unwrap());
+    assert!(matches!(
+      archive.rename_file("missing.txt", "other.txt"),
+      Err(StormError::FileNotFound)
+    ));
+    archive.close().unwrap();
+
+    let archive = Archive::open_read_only(archive_path, OpenArchiveFlags::empty()).unwrap();
+    let mut file = archive.open_file("dir\\renamed.txt").unwrap();
+    assert_eq!(file.read_all().unwrap(), b"Hello, MPQ!");
+  });
+
+  // Clean up
+  std::fs::remove_file(archive_path).unwrap();
+
+  // Propagate any panic that occurred during the test
+  result.unwrap();
+}
+
+#[cfg(debug_assertions)]
+#[test]
+fn test_panic_on_unflushed_drop() {
+  let archive_path = "../../samples/test_panic_on_unflushed_drop.mpq";
+
+  let result = std::panic::catch_unwind(|| {
+    let mut archive =
+      Archive::create(archive_path, CreateArchiveFlags::MPQ_CREATE_LISTFILE, 16).unwrap();
+    archive.set_panic_on_unflushed_drop(true);
+    archive
+      .create_file(CreateFileOptions {
+        path: MpqPath::new("test.txt"),
+        data: &b"Hello, MPQ!".to_vec(),
+        flags: CreateFileFlags::MPQ_FILE_COMPRESS,
+        mtime: 0,
+        compression: CompressionFlags::MPQ_COMPRESSION_ZLIB,
+      })
+      .unwrap();
+  });
+  assert!(result.is_err());
+
+  // The archive was still written before panicking
+  let archive = Archive::open_read_only(archive_path, OpenArchiveFlags::empty()).unwrap();
+  assert!(archive.has_file("test.txt").unwrap());
+  archive.close().unwrap();
+
+  std::fs::remove_file(archive_path).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_non_utf8_paths() {
+  use std::ffi::OsStr;
+  use std::os::unix::ffi::OsStrExt;
+  use std::path::Path;
+
+  let archive_path = Path::new(OsStr::from_bytes(b"../../samples/test_non_utf8_\xff.mpq"));
+  let extracted_path = Path::new(OsStr::from_bytes(b"../../samples/war3map_\xfe.j"));
+
+  let result = std::panic::catch_unwind(|| {
+    std::fs::copy("../../samples/war3map.j", extracted_path).unwrap();
+
+    let mut archive =
+      Archive::create(archive_path, CreateArchiveFlags::MPQ_CREATE_LISTFILE, 16).unwrap();
+    archive
+      .add_file(
+        extracted_path,
+        "war3map.j",
+        CreateFileFlags::MPQ_FILE_COMPRESS,
+        CompressionFlags::MPQ_COMPRESSION_ZLIB,
+      )
+      .unwrap();
+    archive.close().unwrap();
+    std::fs::remove_file(extracted_path).unwrap();
+
+    let archive = Archive::open_read_only(archive_path, OpenArchiveFlags::empty()).unwrap();
+    archive.extract_file("war3map.j", extracted_path).unwrap();
+    assert_eq!(
+      std::fs::read(extracted_path).unwrap(),
+      std::fs::read("../../samples/war3map.j").unwrap()
+    );
+  });
+
+  std::fs::remove_file(archive_path).ok();
+  std::fs::remove_file(extracted_path).ok();
+
+  result.unwrap();
+}
+
+#[test]
+fn test_legacy_names() {
+  let archive_path = "../../samples/test_legacy_names.mpq";
+  let name = Codepage::Gbk.encode("单位\\中文.j").unwrap().into_owned();
+
+  let result = std::panic::catch_unwind(|| {
+    let mut archive =
+      Archive::create(archive_path, CreateArchiveFlags::MPQ_CREATE_LISTFILE, 16).unwrap();
+    archive
+      .add_file(
+        "../../samples/war3map.j",
+        &name,
+        CreateFileFlags::MPQ_FILE_COMPRESS,
+        CompressionFlags::MPQ_COMPRESSION_ZLIB,
+      )
+      .unwrap();
+    archive.close().unwrap();
+
+    let archive = Archive::open_read_only(archive_path, OpenArchiveFlags::empty()).unwrap();
+    assert!(archive.has_file(&name).unwrap());
+    assert_eq!(
+      archive.open_file(&name).unwrap().read_all().unwrap(),
+      std::fs::read("../../samples/war3map.j").unwrap()
+    );
+
+    let found = archive
+      .search(None)
+      .unwrap()
+      .find(|data| data.name_bytes() == &name[..])
+      .unwrap();
+    assert_eq!(found.name(Codepage::Gbk), "单位\\中文.j");
+  });
+
+  std::fs::remove_file(archive_path).unwrap();
+
+  result.unwrap();
+}
+
 #[cfg(target_os = "windows")]
 #[test]
 fn test_read_unicode() {
+  use std::ffi::OsString;
   use std::os::windows::ffi::OsStringExt;
   use widestring::U16CString;
   let archive = Archive::open(
@@ -0,0 +1,297 @@
+//! Reviewable listing of the files of an archive
+//!
+//! A [`Manifest`] lists every file with the settings it is stored with and checksums of its
+//! content. Serialized as JSON or TOML it can be reviewed like source code, and
+//! [`Archive::build_from_manifest`] builds the archive again from the manifest and a directory
+//! with the extracted files. Archives built from the same manifest and content by the same
+//! backend are identical.
+
+use std::path::Path;
+
+use md5::{Digest, Md5};
+use serde::{Deserialize, Serialize};
+use stormlib_sys::*;
+
+use crate::error::*;
+use crate::{Archive, ArchiveMode, CompressionFlags, CreateArchiveFlags, CreateFileFlags};
+use crate::{CreateFileOptions, FileHandle, FindDataExt, MpqPath, MpqPathBuf};
+
+/// Settings of an archive and the files in it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+  /// MPQ format version, `0` to `3` for format 1 to 4
+  pub format_version: u16,
+  /// Size of a file sector in bytes
+  pub sector_size: u32,
+  /// Number of entries in the hash table
+  pub hash_table_size: u32,
+  /// Whether the archive has a `(listfile)`
+  pub listfile: bool,
+  /// Whether the archive has an `(attributes)`
+  pub attributes: bool,
+  /// Files in the order they are stored in, without the internal files
+  pub files: Vec<ManifestEntry>,
+}
+
+/// File of a [`Manifest`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+  pub name: MpqPathBuf,
+  /// `MPQ_FILE_*` flags
+  pub flags: u32,
+  /// `MPQ_COMPRESSION_*` mask of the compressed sectors, `0` if no sector is compressed
+  pub compression: u32,
+  pub locale: u32,
+  /// Windows `FILETIME` stored in the `(attributes)`, `0` if there is none
+  pub file_time: u64,
+  pub size: u64,
+  pub compressed_size: u64,
+  /// CRC32 of the content
+  pub crc32: u32,
+  /// MD5 of the content as a lowercase hex string
+  pub md5: String,
+}
+
+impl ManifestEntry {
+  /// Checks that `data` is the content this entry was made from
+  fn check(&self, data: &[u8]) -> Result<()> {
+    let (crc32, md5) = checksums(data);
+    if data.len() as u64 != self.size || crc32 != self.crc32 || md5 != self.md5 {
+      return Err(StormError::ManifestMismatch(
+        self.name.to_string_lossy().into_owned(),
+      ));
+    }
+    Ok(())
+  }
+}
+
+impl<M: ArchiveMode> Archive<M> {
+  /// Lists the files of the archive with their settings and checksums
+  ///
+  /// Names come from the search, so files not named in the `(listfile)` are listed with the
+  /// `FileXXXXXXXX.xxx` names StormLib generates for them. The `(signature)` is not listed.
+  pub fn manifest(&self) -> Result<Manifest> {
+    let info = self.info()?;
+    let mut found: Vec<_> = self.search(None)?.collect();
+    found.sort_by_key(|data| data.dwBlockIndex);
+
+    let mut manifest = Manifest {
+      format_version: info.format_version,
+      sector_size: info.sector_size,
+      hash_table_size: info.hash_table_size,
+      listfile: false,
+      attributes: false,
+      files: Vec::with_capacity(found.len()),
+    };
+    for data in found {
+      let name = data.path();
+      if name == MpqPath::new("(listfile)") {
+        manifest.listfile = true;
+        continue;
+      }
+      if name == MpqPath::new("(attributes)") {
+        manifest.attributes = true;
+        continue;
+      }
+      if name == MpqPath::new("(signature)") {
+        continue;
+      }
+
+      let mut file = FileHandle::open(self, name)?;
+      let content = file.read_all()?;
+      let compression = file.read_raw()?.compression()?;
+      let (crc32, md5) = checksums(&content);
+      manifest.files.push(ManifestEntry {
+        name: name.to_mpq_path_buf(),
+        flags: data.dwFileFlags,
+        compression,
+        locale: data.lcLocale,
+        file_time: (data.dwFileTimeHi as u64) << 32 | data.dwFileTimeLo as u64,
+        size: data.dwFileSize as u64,
+        compressed_size: data.dwCompSize as u64,
+        crc32,
+        md5,
+      });
+    }
+    Ok(manifest)
+  }
+}
+
+impl Archive {
+  /// Creates an archive at `path` with the files of `manifest`. The content of the files is
+  /// read from `content_dir`, where archive directories are local directories.
+  ///
+  /// Fails with [`StormError::ManifestMismatch`] if the content doesn't match the checksums,
+  /// and with [`StormError::NotSupported`] for files with a locale.
+  pub fn build_from_manifest<P: AsRef<Path>, D: AsRef<Path>>(
+    path: P,
+    manifest: &Manifest,
+    content_dir: D,
+  ) -> Result<Archive> {
+    let mut flags = match manifest.format_version {
+      0 => CreateArchiveFlags::MPQ_CREATE_ARCHIVE_V1,
+      1 => CreateArchiveFlags::MPQ_CREATE_ARCHIVE_V2,
+      2 => CreateArchiveFlags::MPQ_CREATE_ARCHIVE_V3,
+      3 => CreateArchiveFlags::MPQ_CREATE_ARCHIVE_V4,
+      _ => return Err(StormError::BadFormat),
+    };
+    flags.set(CreateArchiveFlags::MPQ_CREATE_LISTFILE, manifest.listfile);
+    flags.set(
+      CreateArchiveFlags::MPQ_CREATE_ATTRIBUTES,
+      manifest.attributes,
+    );
+    if manifest.files.iter().any(|entry| entry.locale != 0) {
+      return Err(StormError::NotSupported);
+    }
+
+    // All content is checked before the archive is created
+    let mut contents = Vec::with_capacity(manifest.files.len());
+    for entry in &manifest.files {
+      let local_path = crate::name::local_path(content_dir.as_ref(), &entry.name)?;
+      let data = std::fs::read(local_path).map_err(io_error)?;
+      entry.check(&data)?;
+      contents.push(data);
+    }
+
+    // The internal files are added to the file count when the hash table size is chosen
+    let internal_files = manifest.listfile as u32 + manifest.attributes as u32;
+    let max_files_count = manifest.hash_table_size.saturating_sub(internal_files);
+    let mut archive =
+      Archive::create_with_sector_size(path, flags, max_files_count, manifest.sector_size)?;
+    for (entry, data) in manifest.files.iter().zip(&contents) {
+      archive.create_file(CreateFileOptions {
+        path: &entry.name,
+        data,
+        flags: CreateFileFlags::from_bits_truncate(entry.flags & !MPQ_FILE_EXISTS),
+        mtime: entry.file_time,
+        compression: CompressionFlags::from_bits_truncate(entry.compression),
+      })?;
+    }
+    archive.flush()?;
+    Ok(archive)
+  }
+}
+
+/// CRC32 and hex MD5 of file content
+fn checksums(data: &[u8]) -> (u32, String) {
+  let md5 = Md5::digest(data)
+    .iter()
+    .map(|byte| format!("{:02x}", byte))
+    .collect();
+  (crc32fast::hash(data), md5)
+}
+
+#[test]
+fn test_manifest_round_trip() {
+  let archive_path = "../../samples/test_manifest.mpq";
+  let rebuilt_path = "../../samples/test_manifest_rebuilt.mpq";
+  let content_dir = "../../samples/test_manifest";
+  let script = std::fs::read("../../samples/war3map.j").unwrap();
+  let files: [(&str, &[u8], CreateFileFlags, CompressionFlags); 4] = [
+    (
+      "war3map.j",
+      &script,
+      CreateFileFlags::MPQ_FILE_COMPRESS,
+      CompressionFlags::MPQ_COMPRESSION_ZLIB,
+    ),
+    (
+      "scripts\\bzip2.j",
+      &script,
+      CreateFileFlags::MPQ_FILE_COMPRESS | CreateFileFlags::MPQ_FILE_SECTOR_CRC,
+      CompressionFlags::MPQ_COMPRESSION_BZIP2,
+    ),
+    (
+      "scripts\\encrypted.j",
+      &script,
+      CreateFileFlags::MPQ_FILE_COMPRESS
+        | CreateFileFlags::MPQ_FILE_ENCRYPTED
+        | CreateFileFlags::MPQ_FILE_FIX_KEY
+        | CreateFileFlags::MPQ_FILE_SINGLE_UNIT,
+      CompressionFlags::MPQ_COMPRESSION_ZLIB,
+    ),
+    (
+      "stored.txt",
+      b"Hello, MPQ!",
+      CreateFileFlags::empty(),
+      CompressionFlags::empty(),
+    ),
+  ];
+
+  let result = std::panic::catch_unwind(|| {
+    let flags = CreateArchiveFlags::MPQ_CREATE_LISTFILE | CreateArchiveFlags::MPQ_CREATE_ATTRIBUTES;
+    let mut archive = Archive::create(archive_path, flags, 16).unwrap();
+    for (i, (name, data, flags, compression)) in files.iter().enumerate() {
+      archive
+        .create_file(CreateFileOptions {
+          path: MpqPath::new(name),
+          data: &data.to_vec(),
+          flags: *flags,
+          mtime: 0x01D0_0000_0000_0000 + i as u64,
+          compression: *compression,
+        })
+        .unwrap();
+      let path = crate::name::local_path(Path::new(content_dir), MpqPath::new(name)).unwrap();
+      std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+      std::fs::write(path, data).unwrap();
+    }
+    archive.flush().unwrap();
+
+    let manifest = archive.manifest().unwrap();
+    archive.close().unwrap();
+    assert!(manifest.listfile && manifest.attributes);
+    let names: Vec<_> = manifest.files.iter().map(|e| e.name.clone()).collect();
+    let expected: Vec<MpqPathBuf> = files.iter().map(|f| f.0.into()).collect();
+    assert_eq!(names, expected);
+    let compressions: Vec<_> = manifest.files.iter().map(|e| e.compression).collect();
+    assert_eq!(
+      compressions,
+      [
+        MPQ_COMPRESSION_ZLIB,
+        MPQ_COMPRESSION_BZIP2,
+        MPQ_COMPRESSION_ZLIB,
+        0
+      ]
+    );
+    assert_eq!(manifest.files[1].file_time, 0x01D0_0000_0000_0001);
+    assert_eq!(manifest.files[3].md5, "2ab161f6accfedb8f4b94fc0efb1163e");
+
+    let json = serde_json::to_string_pretty(&manifest).unwrap();
+    let manifest: Manifest = serde_json::from_str(&json).unwrap();
+    Archive::build_from_manifest(rebuilt_path, &manifest, content_dir)
+      .unwrap()
+      .close()
+      .unwrap();
+    assert_eq!(
+      std::fs::read(rebuilt_path).unwrap(),
+      std::fs::read(archive_path).unwrap()
+    );
+
+    let changed_path = "../../samples/test_manifest_changed.mpq";
+    let mut changed = manifest.clone();
+    changed.files[3].crc32 ^= 1;
+    assert!(matches!(
+      Archive::build_from_manifest(changed_path, &changed, content_dir),
+      Err(StormError::ManifestMismatch(name)) if name == "stored.txt"
+    ));
+    assert!(!Path::new(changed_path).exists());
+
+    let mut changed = manifest.clone();
+    changed.sector_size = 0x200;
+    let archive = Archive::build_from_manifest(changed_path, &changed, content_dir).unwrap();
+    let rebuilt = archive.manifest().unwrap();
+    archive.close().unwrap();
+    assert_eq!(rebuilt.sector_size, 0x200);
+    // Smaller sectors compress worse
+    assert!(rebuilt.files[0].compressed_size > manifest.files[0].compressed_size);
+    assert_eq!(rebuilt.files[0].md5, manifest.files[0].md5);
+  });
+
+  // Clean up
+  let _ = std::fs::remove_file(archive_path);
+  let _ = std::fs::remove_file(rebuilt_path);
+  let _ = std::fs::remove_file("../../samples/test_manifest_changed.mpq");
+  let _ = std::fs::remove_dir_all(content_dir);
+
+  // Propagate any panic that occurred during the test
+  result.unwrap();
+}
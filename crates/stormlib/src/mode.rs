@@ -0,0 +1,23 @@
+/// Access mode of an [`Archive`](crate::Archive)
+///
+/// Write methods are only available on `Archive<ReadWrite>`, so the compiler rejects
+/// modifications of archives opened read-only.
+pub trait ArchiveMode: sealed::Sealed {}
+
+/// Archive opened for reading and writing. This is the default mode.
+#[derive(Debug)]
+pub enum ReadWrite {}
+
+/// Archive opened with `STREAM_FLAG_READ_ONLY`, write methods are not available
+#[derive(Debug)]
+pub enum ReadOnly {}
+
+impl ArchiveMode for ReadWrite {}
+impl ArchiveMode for ReadOnly {}
+
+mod sealed {
+  pub trait Sealed {}
+
+  impl Sealed for super::ReadWrite {}
+  impl Sealed for super::ReadOnly {}
+}
@@ -0,0 +1,308 @@
+//! Names of files inside archives
+//!
+//! MPQ stores file names as raw bytes without any encoding information. Archives made by
+//! localized tools often contain names in a legacy codepage such as GBK, Big5 or CP949, so
+//! all archive methods accept names as bytes through [`MpqPath`](crate::MpqPath), and
+//! [`Codepage`] converts them for display.
+
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+
+use encoding_rs::Encoding;
+use stormlib_sys::*;
+
+use crate::error::*;
+#[cfg(not(feature = "pure-rust"))]
+use crate::util;
+use crate::MpqPath;
+
+/// Codepage used to interpret file names stored in an archive
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Codepage {
+  /// UTF-8, used by Warcraft III since patch 1.20 and by most modern tools
+  #[default]
+  Utf8,
+  /// Windows-1250, Central European
+  Windows1250,
+  /// Windows-1251, Cyrillic
+  Windows1251,
+  /// Windows-1252, Western European
+  Windows1252,
+  /// Shift_JIS (CP932), Japanese
+  ShiftJis,
+  /// GBK (CP936), Simplified Chinese
+  Gbk,
+  /// CP949 (EUC-KR), Korean
+  Cp949,
+  /// Big5 (CP950), Traditional Chinese
+  Big5,
+}
+
+impl Codepage {
+  /// Returns the codepage for a Windows codepage identifier
+  pub fn from_windows_codepage(codepage: u16) -> Option<Self> {
+    Some(match codepage {
+      65001 => Codepage::Utf8,
+      1250 => Codepage::Windows1250,
+      1251 => Codepage::Windows1251,
+      1252 => Codepage::Windows1252,
+      932 => Codepage::ShiftJis,
+      936 => Codepage::Gbk,
+      949 => Codepage::Cp949,
+      950 => Codepage::Big5,
+      _ => return None,
+    })
+  }
+
+  fn encoding(self) -> &'static Encoding {
+    match self {
+      Codepage::Utf8 => encoding_rs::UTF_8,
+      Codepage::Windows1250 => encoding_rs::WINDOWS_1250,
+      Codepage::Windows1251 => encoding_rs::WINDOWS_1251,
+      Codepage::Windows1252 => encoding_rs::WINDOWS_1252,
+      Codepage::ShiftJis => encoding_rs::SHIFT_JIS,
+      Codepage::Gbk => encoding_rs::GBK,
+      Codepage::Cp949 => encoding_rs::EUC_KR,
+      Codepage::Big5 => encoding_rs::BIG5,
+    }
+  }
+
+  /// Decodes a raw archive name for display. Invalid sequences are replaced with U+FFFD.
+  pub fn decode(self, name: &[u8]) -> Cow<'_, str> {
+    self.encoding().decode_without_bom_handling(name).0
+  }
+
+  /// Encodes a name to the raw bytes stored in the archive
+  pub fn encode(self, name: &str) -> Result<Cow<'_, [u8]>> {
+    let (bytes, _, unmappable) = self.encoding().encode(name);
+    if unmappable {
+      return Err(StormError::UnmappableName);
+    }
+    Ok(bytes)
+  }
+}
+
+/// Accessors for the file name of a search result
+pub trait FindDataExt {
+  /// Name of the file as stored in the archive
+  fn name_bytes(&self) -> &[u8];
+
+  /// Name of the file decoded with `codepage`
+  fn name(&self, codepage: Codepage) -> Cow<'_, str> {
+    codepage.decode(self.name_bytes())
+  }
+
+  /// Name of the file as a path
+  fn path(&self) -> &MpqPath {
+    MpqPath::new(self.name_bytes())
+  }
+}
+
+impl FindDataExt for SFILE_FIND_DATA {
+  fn name_bytes(&self) -> &[u8] {
+    let name = unsafe { &*(&self.cFileName[..] as *const [_] as *const [u8]) };
+    let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+    &name[..len]
+  }
+}
+
+/// Converts an archive name to a name usable on the local filesystem
+///
+/// Byte sequences that are not valid UTF-8 are escaped by StormLib, so names in legacy
+/// codepages map to distinct local files and convert back with [`file_name_to_name`].
+#[cfg(not(feature = "pure-rust"))]
+pub fn name_to_file_name(name: &[u8]) -> Result<PathBuf> {
+  let range = name.as_ptr_range();
+  let begin = range.start as *const _;
+  let end = range.end as *const _;
+
+  // The first call only calculates the needed length
+  let mut len: usize = 0;
+  unsafe { SMemUTF8ToFileName(std::ptr::null_mut(), 0, begin, end, 0, &mut len) };
+
+  let mut buf: Vec<TCHAR> = vec![0; len + 1];
+  check_code(unsafe { SMemUTF8ToFileName(buf.as_mut_ptr(), buf.len(), begin, end, 0, &mut len) })?;
+  util::from_tchars(buf)
+}
+
+/// Converts a local file name produced by [`name_to_file_name`] back to the archive name
+#[cfg(not(feature = "pure-rust"))]
+pub fn file_name_to_name<P: AsRef<Path>>(path: P) -> Result<Vec<u8>> {
+  let tpath = util::to_tpath(path.as_ref())?;
+  let begin = tpath.as_ptr();
+
+  // The first call only calculates the needed length
+  let mut len: usize = 0;
+  unsafe {
+    SMemFileNameToUTF8(
+      std::ptr::null_mut(),
+      0,
+      begin,
+      std::ptr::null(),
+      0,
+      &mut len,
+    )
+  };
+
+  let mut buf: Vec<u8> = vec![0; len + 1];
+  check_code(unsafe {
+    SMemFileNameToUTF8(
+      buf.as_mut_ptr() as *mut _,
+      buf.len(),
+      begin,
+      std::ptr::null(),
+      0,
+      &mut len,
+    )
+  })?;
+  if let Some(nul) = buf.iter().position(|&c| c == 0) {
+    buf.truncate(nul);
+  }
+  Ok(buf)
+}
+
+/// Converts an archive name to a name usable on the local filesystem
+///
+/// Bytes that are not valid UTF-8 and characters not allowed in file names are escaped as
+/// `%xx`, so names in legacy codepages map to distinct local files and convert back with
+/// [`file_name_to_name`].
+#[cfg(feature = "pure-rust")]
+pub fn name_to_file_name(name: &[u8]) -> Result<PathBuf> {
+  let mut file_name = String::with_capacity(name.len());
+  let mut rest = name;
+  while !rest.is_empty() {
+    let (valid, invalid) = match std::str::from_utf8(rest) {
+      Ok(valid) => (valid, &[][..]),
+      Err(err) => {
+        let (valid, invalid) = rest.split_at(err.valid_up_to());
+        let len = err.error_len().unwrap_or(invalid.len());
+        (std::str::from_utf8(valid).unwrap(), &invalid[..len])
+      }
+    };
+    for (i, c) in valid.char_indices() {
+      let escaped = match c {
+        '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*' => true,
+        // Only escape `%` where it would be read back as an escape
+        '%' => parse_escape(&valid.as_bytes()[i..]).is_some(),
+        c => c.is_control(),
+      };
+      if escaped {
+        file_name.push_str(&format!("%{:02x}", c as u32));
+      } else {
+        file_name.push(c);
+      }
+    }
+    for byte in invalid {
+      file_name.push_str(&format!("%{:02x}", byte));
+    }
+    rest = &rest[valid.len() + invalid.len()..];
+  }
+  Ok(PathBuf::from(file_name))
+}
+
+/// Converts a local file name produced by [`name_to_file_name`] back to the archive name
+#[cfg(feature = "pure-rust")]
+pub fn file_name_to_name<P: AsRef<Path>>(path: P) -> Result<Vec<u8>> {
+  let file_name = path.as_ref().to_str().ok_or(StormError::InvalidName)?;
+  let bytes = file_name.as_bytes();
+  let mut name = Vec::with_capacity(bytes.len());
+  let mut i = 0;
+  while i < bytes.len() {
+    match parse_escape(&bytes[i..]) {
+      Some(byte) => {
+        name.push(byte);
+        i += 3;
+      }
+      None => {
+        name.push(bytes[i]);
+        i += 1;
+      }
+    }
+  }
+  Ok(name)
+}
+
+/// Parses a `%xx` escape at the start of `s`
+#[cfg(feature = "pure-rust")]
+fn parse_escape(s: &[u8]) -> Option<u8> {
+  match s {
+    [b'%', hi, lo, ..] if hi.is_ascii_hexdigit() && lo.is_ascii_hexdigit() => {
+      u8::from_str_radix(std::str::from_utf8(&s[1..3]).ok()?, 16).ok()
+    }
+    _ => None,
+  }
+}
+
+#[cfg(not(feature = "pure-rust"))]
+fn check_code(code: DWORD) -> Result<()> {
+  match code {
+    ERROR_SUCCESS => Ok(()),
+    code => Err(ErrorCode(code).into()),
+  }
+}
+
+/// Local path a file is extracted to, archive directories become local directories. Names
+/// that would end up outside of `dir` are rejected.
+#[cfg(any(feature = "serde", feature = "tokio"))]
+pub(crate) fn local_path(dir: &Path, name: &MpqPath) -> Result<PathBuf> {
+  use std::path::Component;
+
+  let mut path = dir.to_path_buf();
+  for component in name.components() {
+    let file_name = name_to_file_name(component.as_bytes())?;
+    match Path::new(&file_name).components().collect::<Vec<_>>()[..] {
+      [Component::Normal(_)] => path.push(file_name),
+      _ => return Err(StormError::InvalidName),
+    }
+  }
+  Ok(path)
+}
+
+/// Whether the file is one of the internal files StormLib maintains
+pub(crate) fn is_internal(name: &MpqPath) -> bool {
+  ["(listfile)", "(attributes)", "(signature)"]
+    .iter()
+    .any(|internal| name == MpqPath::new(internal))
+}
+
+#[test]
+fn test_codepage() {
+  let gbk = b"\xd6\xd0\xce\xc4.txt";
+  assert_eq!(Codepage::Gbk.decode(gbk), "中文.txt");
+  assert_eq!(Codepage::Gbk.encode("中文.txt").unwrap(), &gbk[..]);
+  assert_eq!(
+    Codepage::Utf8.decode(gbk),
+    "\u{fffd}\u{fffd}\u{fffd}\u{fffd}.txt"
+  );
+  assert_eq!(Codepage::from_windows_codepage(936), Some(Codepage::Gbk));
+  assert!(matches!(
+    Codepage::Windows1252.encode("中文"),
+    Err(StormError::UnmappableName)
+  ));
+}
+
+#[test]
+fn test_file_name_round_trip() {
+  for name in [
+    &b"war3map.j"[..],
+    b"\xd6\xd0\xce\xc4.txt",
+    "中文.txt".as_bytes(),
+  ] {
+    let file_name = name_to_file_name(name).unwrap();
+    assert_eq!(file_name_to_name(&file_name).unwrap(), name);
+  }
+}
+
+#[cfg(feature = "pure-rust")]
+#[test]
+fn test_file_name_escapes() {
+  for (name, file_name) in [
+    (&b"\xd6\xd0.txt"[..], "%d6%d0.txt"),
+    (b"a?.txt", "a%3f.txt"),
+    (b"100%.txt", "100%.txt"),
+    (b"%41.txt", "%2541.txt"),
+  ] {
+    assert_eq!(name_to_file_name(name).unwrap(), Path::new(file_name));
+    assert_eq!(file_name_to_name(file_name).unwrap(), name);
+  }
+}
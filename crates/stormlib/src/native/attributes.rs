@@ -0,0 +1,130 @@
+//! The `(attributes)` file
+//!
+//! Holds a CRC32, `FILETIME` and MD5 for every entry of the block table, in separate arrays
+//! selected by the flags.
+
+use md5::{Digest, Md5};
+use stormlib_sys::*;
+
+use super::tables::u32_at;
+
+/// Attributes of a single block
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FileAttributes {
+  pub crc32: u32,
+  pub file_time: u64,
+  pub md5: [u8; 16],
+}
+
+impl FileAttributes {
+  /// Attributes of file data written at `file_time`
+  pub fn new(data: &[u8], file_time: u64) -> Self {
+    FileAttributes {
+      crc32: crc32fast::hash(data),
+      file_time,
+      md5: Md5::digest(data).into(),
+    }
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct Attributes {
+  /// `MPQ_ATTRIBUTE_*` flags of the arrays that are present
+  pub flags: u32,
+  /// Attributes by block index
+  pub entries: Vec<FileAttributes>,
+}
+
+impl Attributes {
+  pub fn new(flags: u32) -> Self {
+    Attributes {
+      flags: flags & (MPQ_ATTRIBUTE_CRC32 | MPQ_ATTRIBUTE_FILETIME | MPQ_ATTRIBUTE_MD5),
+      entries: vec![],
+    }
+  }
+
+  /// Parses the arrays of `block_count` entries. Arrays cut at the end of the file are
+  /// read as far as they go.
+  pub fn parse(data: &[u8], block_count: usize) -> Option<Self> {
+    if data.len() < 8 || u32_at(data, 0) != MPQ_ATTRIBUTES_V1 {
+      return None;
+    }
+    let mut attributes = Attributes::new(u32_at(data, 4));
+    attributes.entries = vec![FileAttributes::default(); block_count];
+    let mut rest = &data[8..];
+    let flags = attributes.flags;
+    let mut array =
+      |flag: u32, item_size: usize| take(&mut rest, flags & flag, block_count * item_size);
+    let crc32 = array(MPQ_ATTRIBUTE_CRC32, 4);
+    let file_time = array(MPQ_ATTRIBUTE_FILETIME, 8);
+    let md5 = array(MPQ_ATTRIBUTE_MD5, 16);
+    for (entry, crc32) in attributes.entries.iter_mut().zip(crc32.chunks_exact(4)) {
+      entry.crc32 = u32_at(crc32, 0);
+    }
+    for (entry, time) in attributes.entries.iter_mut().zip(file_time.chunks_exact(8)) {
+      entry.file_time = u32_at(time, 0) as u64 | (u32_at(time, 4) as u64) << 32;
+    }
+    for (entry, md5) in attributes.entries.iter_mut().zip(md5.chunks_exact(16)) {
+      entry.md5.copy_from_slice(md5);
+    }
+    Some(attributes)
+  }
+
+  pub fn get(&self, block_index: u32) -> FileAttributes {
+    self
+      .entries
+      .get(block_index as usize)
+      .copied()
+      .unwrap_or_default()
+  }
+
+  pub fn set(&mut self, block_index: u32, attributes: FileAttributes) {
+    let index = block_index as usize;
+    if self.entries.len() <= index {
+      self.entries.resize(index + 1, FileAttributes::default());
+    }
+    self.entries[index] = attributes;
+  }
+
+  /// Serializes the arrays for `block_count` entries
+  pub fn to_bytes(&self, block_count: usize) -> Vec<u8> {
+    let entries = (0..block_count as u32).map(|i| self.get(i));
+    let mut buf = vec![];
+    buf.extend_from_slice(&MPQ_ATTRIBUTES_V1.to_le_bytes());
+    buf.extend_from_slice(&self.flags.to_le_bytes());
+    if self.flags & MPQ_ATTRIBUTE_CRC32 != 0 {
+      buf.extend(entries.clone().flat_map(|e| e.crc32.to_le_bytes()));
+    }
+    if self.flags & MPQ_ATTRIBUTE_FILETIME != 0 {
+      buf.extend(entries.clone().flat_map(|e| e.file_time.to_le_bytes()));
+    }
+    if self.flags & MPQ_ATTRIBUTE_MD5 != 0 {
+      buf.extend(entries.flat_map(|e| e.md5));
+    }
+    buf
+  }
+}
+
+/// Splits off an array if `present` is non-zero
+fn take<'a>(rest: &mut &'a [u8], present: u32, len: usize) -> &'a [u8] {
+  if present == 0 {
+    return &[];
+  }
+  let (array, tail) = rest.split_at(rest.len().min(len));
+  *rest = tail;
+  array
+}
+
+#[test]
+fn test_attributes_round_trip() {
+  let mut attributes = Attributes::new(MPQ_ATTRIBUTE_CRC32 | MPQ_ATTRIBUTE_MD5);
+  attributes.set(1, FileAttributes::new(b"Hello, MPQ!", 0));
+  let data = attributes.to_bytes(3);
+  assert_eq!(data.len(), 8 + 3 * 4 + 3 * 16);
+
+  let parsed = Attributes::parse(&data, 3).unwrap();
+  assert_eq!(parsed.get(0), FileAttributes::default());
+  assert_eq!(parsed.get(1).crc32, crc32fast::hash(b"Hello, MPQ!"));
+  assert_eq!(parsed.get(1).md5, attributes.get(1).md5);
+  assert_eq!(parsed.get(1).file_time, 0);
+}
@@ -0,0 +1,387 @@
+//! Sector decompression
+
+use std::io::{Read, Write};
+
+use crate::error::*;
+
+const COMPRESSION_HUFFMANN: u8 = 0x01;
+const COMPRESSION_ZLIB: u8 = 0x02;
+const COMPRESSION_PKWARE: u8 = 0x08;
+const COMPRESSION_BZIP2: u8 = 0x10;
+const COMPRESSION_SPARSE: u8 = 0x20;
+const COMPRESSION_ADPCM_MONO: u8 = 0x40;
+const COMPRESSION_ADPCM_STEREO: u8 = 0x80;
+const COMPRESSION_LZMA: u8 = 0x12;
+
+type Decompressor = fn(&[u8], usize) -> Result<Vec<u8>>;
+
+/// Decompresses a sector compressed with `MPQ_FILE_COMPRESS`. The first byte is the mask
+/// of the compressions that were applied.
+pub fn decompress(data: &[u8], size: usize) -> Result<Vec<u8>> {
+  let (&mask, data) = data.split_first().ok_or(StormError::FileCorrupt)?;
+
+  // LZMA can't be combined with anything else and its mask overlaps with zlib and bzip2
+  if mask == COMPRESSION_LZMA {
+    return check_size(lzma(data, size)?, size);
+  }
+
+  if mask & COMPRESSION_HUFFMANN != 0 {
+    return Err(StormError::NotSupported);
+  }
+
+  // Applied in the reverse order of the compression
+  let mut buf = data.to_vec();
+  let steps: [(u8, Decompressor); 6] = [
+    (COMPRESSION_BZIP2, bzip2),
+    (COMPRESSION_PKWARE, explode),
+    (COMPRESSION_ZLIB, zlib),
+    (COMPRESSION_SPARSE, sparse),
+    (COMPRESSION_ADPCM_STEREO, |data, size| adpcm(data, size, 2)),
+    (COMPRESSION_ADPCM_MONO, |data, size| adpcm(data, size, 1)),
+  ];
+  for (flag, step) in steps.iter() {
+    if mask & flag != 0 {
+      buf = step(&buf, size)?;
+    }
+  }
+  check_size(buf, size)
+}
+
+/// Compresses a sector with zlib or bzip2, prefixed with the compression mask. Returns
+/// `None` when that doesn't make the data smaller, the sector is then stored as is.
+pub fn compress(data: &[u8], mask: u32) -> Result<Option<Vec<u8>>> {
+  if mask > 0xFF {
+    return Err(StormError::NotSupported);
+  }
+  let mut buf = vec![mask as u8];
+  match mask as u8 {
+    COMPRESSION_ZLIB => {
+      let mut encoder = flate2::write::ZlibEncoder::new(buf, flate2::Compression::default());
+      encoder
+        .write_all(data)
+        .map_err(|_| StormError::CanNotComplete)?;
+      buf = encoder.finish().map_err(|_| StormError::CanNotComplete)?;
+    }
+    COMPRESSION_BZIP2 => {
+      let mut encoder = bzip2::write::BzEncoder::new(buf, bzip2::Compression::best());
+      encoder
+        .write_all(data)
+        .map_err(|_| StormError::CanNotComplete)?;
+      buf = encoder.finish().map_err(|_| StormError::CanNotComplete)?;
+    }
+    _ if mask == 0 => return Ok(None),
+    _ => return Err(StormError::NotSupported),
+  }
+  Ok(Some(buf).filter(|buf| buf.len() < data.len()))
+}
+
+fn check_size(mut buf: Vec<u8>, size: usize) -> Result<Vec<u8>> {
+  if buf.len() < size {
+    return Err(StormError::FileCorrupt);
+  }
+  buf.truncate(size);
+  Ok(buf)
+}
+
+fn zlib(data: &[u8], size: usize) -> Result<Vec<u8>> {
+  let mut buf = Vec::with_capacity(size);
+  flate2::read::ZlibDecoder::new(data)
+    .read_to_end(&mut buf)
+    .map_err(|_| StormError::FileCorrupt)?;
+  Ok(buf)
+}
+
+fn bzip2(data: &[u8], size: usize) -> Result<Vec<u8>> {
+  let mut buf = Vec::with_capacity(size);
+  bzip2::read::BzDecoder::new(data)
+    .read_to_end(&mut buf)
+    .map_err(|_| StormError::FileCorrupt)?;
+  Ok(buf)
+}
+
+/// LZMA data starts with a filter byte, which must be zero, and the 5 property bytes
+fn lzma(data: &[u8], size: usize) -> Result<Vec<u8>> {
+  match data.split_first() {
+    Some((0, stream)) => {
+      let mut buf = Vec::with_capacity(size);
+      let options = lzma_rs::decompress::Options {
+        unpacked_size: lzma_rs::decompress::UnpackedSize::UseProvided(Some(size as u64)),
+        ..Default::default()
+      };
+      lzma_rs::lzma_decompress_with_options(&mut &stream[..], &mut buf, &options)
+        .map_err(|_| StormError::FileCorrupt)?;
+      Ok(buf)
+    }
+    _ => Err(StormError::FileCorrupt),
+  }
+}
+
+/// Run length encoding of zero bytes, prefixed with the big endian size
+fn sparse(data: &[u8], size: usize) -> Result<Vec<u8>> {
+  if data.len() < 4 {
+    return Err(StormError::FileCorrupt);
+  }
+  let total = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+  let total = total.min(size);
+  let mut buf = Vec::with_capacity(total);
+  let mut input = data[4..].iter();
+  while buf.len() < total {
+    let &op = match input.next() {
+      Some(op) => op,
+      None => break,
+    };
+    let remaining = total - buf.len();
+    if op & 0x80 != 0 {
+      let len = ((op & 0x7F) as usize + 1).min(remaining);
+      let bytes = input.as_slice();
+      if bytes.len() < len {
+        return Err(StormError::FileCorrupt);
+      }
+      buf.extend_from_slice(&bytes[..len]);
+      input = bytes[len..].iter();
+    } else {
+      let len = ((op & 0x7F) as usize + 3).min(remaining);
+      buf.resize(buf.len() + len, 0);
+    }
+  }
+  Ok(buf)
+}
+
+/// Bit reader for the PKWARE Data Compression Library format, least significant bit first
+struct Bits<'a> {
+  data: &'a [u8],
+  pos: usize,
+  buf: u32,
+  count: u32,
+}
+
+impl<'a> Bits<'a> {
+  fn new(data: &'a [u8]) -> Self {
+    Bits {
+      data,
+      pos: 0,
+      buf: 0,
+      count: 0,
+    }
+  }
+
+  fn bits(&mut self, n: u32) -> Result<u32> {
+    while self.count < n {
+      let byte = *self.data.get(self.pos).ok_or(StormError::FileCorrupt)?;
+      self.pos += 1;
+      self.buf |= (byte as u32) << self.count;
+      self.count += 8;
+    }
+    let value = self.buf & ((1u32 << n) - 1);
+    self.buf >>= n;
+    self.count -= n;
+    Ok(value)
+  }
+}
+
+/// Canonical Huffman code of the PKWARE format, the codes are stored bit-inverted
+struct Huffman {
+  count: [u16; 14],
+  symbol: Vec<u16>,
+}
+
+impl Huffman {
+  /// Builds the code from run length encoded code lengths, each byte holds the repeat count
+  /// minus one in the high and the length in the low nibble
+  fn new(rep: &[u8]) -> Self {
+    let lengths: Vec<u8> = rep
+      .iter()
+      .flat_map(|&b| std::iter::repeat_n(b & 15, (b >> 4) as usize + 1))
+      .collect();
+    let mut count = [0u16; 14];
+    for &len in &lengths {
+      count[len as usize] += 1;
+    }
+    let mut offs = [0u16; 14];
+    for len in 1..13 {
+      offs[len + 1] = offs[len] + count[len];
+    }
+    let mut symbol = vec![0u16; lengths.len()];
+    for (sym, &len) in lengths.iter().enumerate() {
+      if len != 0 {
+        symbol[offs[len as usize] as usize] = sym as u16;
+        offs[len as usize] += 1;
+      }
+    }
+    Huffman { count, symbol }
+  }
+
+  fn decode(&self, bits: &mut Bits) -> Result<usize> {
+    let mut code = 0i32;
+    let mut first = 0i32;
+    let mut index = 0i32;
+    for len in 1..14 {
+      code |= (bits.bits(1)? ^ 1) as i32;
+      let count = self.count[len] as i32;
+      if code - first < count {
+        return Ok(self.symbol[(index + code - first) as usize] as usize);
+      }
+      index += count;
+      first = (first + count) << 1;
+      code <<= 1;
+    }
+    Err(StormError::FileCorrupt)
+  }
+}
+
+const LITERAL_LENGTHS: [u8; 98] = [
+  11, 124, 8, 7, 28, 7, 188, 13, 76, 4, 10, 8, 12, 10, 12, 10, 8, 23, 8, 9, 7, 6, 7, 8, 7, 6, 55,
+  8, 23, 24, 12, 11, 7, 9, 11, 12, 6, 7, 22, 5, 7, 24, 6, 11, 9, 6, 7, 22, 7, 11, 38, 7, 9, 8, 25,
+  11, 8, 11, 9, 12, 8, 12, 5, 38, 5, 38, 5, 11, 7, 5, 6, 21, 6, 10, 53, 8, 7, 24, 10, 27, 44, 253,
+  253, 253, 252, 252, 252, 13, 12, 45, 12, 45, 12, 61, 12, 45, 44, 173,
+];
+const LENGTH_LENGTHS: [u8; 6] = [2, 35, 36, 53, 38, 23];
+const DISTANCE_LENGTHS: [u8; 7] = [2, 20, 53, 230, 247, 151, 248];
+const LENGTH_BASE: [u16; 16] = [3, 2, 4, 5, 6, 7, 8, 9, 10, 12, 16, 24, 40, 72, 136, 264];
+const LENGTH_EXTRA: [u8; 16] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8];
+
+/// Decompresses data imploded with the PKWARE Data Compression Library
+pub fn explode(data: &[u8], size: usize) -> Result<Vec<u8>> {
+  let literals = Huffman::new(&LITERAL_LENGTHS);
+  let lengths = Huffman::new(&LENGTH_LENGTHS);
+  let distances = Huffman::new(&DISTANCE_LENGTHS);
+
+  let mut bits = Bits::new(data);
+  let coded_literals = match bits.bits(8)? {
+    0 => false,
+    1 => true,
+    _ => return Err(StormError::FileCorrupt),
+  };
+  let dict_bits = bits.bits(8)?;
+  if !(4..=6).contains(&dict_bits) {
+    return Err(StormError::FileCorrupt);
+  }
+
+  let mut buf = Vec::with_capacity(size);
+  while buf.len() < size {
+    if bits.bits(1)? == 1 {
+      let symbol = lengths.decode(&mut bits)?;
+      let len = LENGTH_BASE[symbol] as usize + bits.bits(LENGTH_EXTRA[symbol] as u32)? as usize;
+      if len == 519 {
+        break;
+      }
+      let shift = if len == 2 { 2 } else { dict_bits };
+      let dist = (distances.decode(&mut bits)? << shift) + bits.bits(shift)? as usize + 1;
+      if dist > buf.len() {
+        return Err(StormError::FileCorrupt);
+      }
+      let start = buf.len() - dist;
+      for i in 0..len {
+        let byte = buf[start + i];
+        buf.push(byte);
+      }
+    } else {
+      let literal = if coded_literals {
+        literals.decode(&mut bits)? as u8
+      } else {
+        bits.bits(8)? as u8
+      };
+      buf.push(literal);
+    }
+  }
+  Ok(buf)
+}
+
+const ADPCM_STEP_SIZES: [i32; 89] = [
+  7, 8, 9, 10, 11, 12, 13, 14, 16, 17, 19, 21, 23, 25, 28, 31, 34, 37, 41, 45, 50, 55, 60, 66, 73,
+  80, 88, 97, 107, 118, 130, 143, 157, 173, 190, 209, 230, 253, 279, 307, 337, 371, 408, 449, 494,
+  544, 598, 658, 724, 796, 876, 963, 1060, 1166, 1282, 1411, 1552, 1707, 1878, 2066, 2272, 2499,
+  2749, 3024, 3327, 3660, 4026, 4428, 4871, 5358, 5894, 6484, 7132, 7845, 8630, 9493, 10442, 11487,
+  12635, 13899, 15289, 16818, 18500, 20350, 22385, 24623, 27086, 29794, 32767,
+];
+const ADPCM_NEXT_STEP: [i32; 32] = [
+  -1, 0, -1, 4, -1, 2, -1, 6, -1, 1, -1, 5, -1, 3, -1, 7, -1, 1, -1, 5, -1, 3, -1, 7, -1, 2, -1, 4,
+  -1, 6, -1, 8,
+];
+const ADPCM_INITIAL_STEP: i32 = 0x2C;
+const ADPCM_MAX_STEP: i32 = 0x58;
+
+/// Decompresses 16-bit PCM samples compressed with Blizzard's ADPCM variant
+fn adpcm(data: &[u8], size: usize, channels: usize) -> Result<Vec<u8>> {
+  if data.len() < 2 + 2 * channels {
+    return Err(StormError::FileCorrupt);
+  }
+  let shift = data[1] as u32;
+  let mut buf = Vec::with_capacity(size);
+  let mut predicted = [0i32; 2];
+  let mut step = [ADPCM_INITIAL_STEP; 2];
+  for (ch, sample) in data[2..2 + 2 * channels].chunks_exact(2).enumerate() {
+    predicted[ch] = i16::from_le_bytes([sample[0], sample[1]]) as i32;
+    buf.extend_from_slice(&sample[..2]);
+  }
+
+  let mut ch = channels - 1;
+  for &encoded in &data[2 + 2 * channels..] {
+    if buf.len() + 2 > size {
+      break;
+    }
+    ch = (ch + 1) % channels;
+    if encoded & 0x80 != 0 {
+      match encoded & 0x7F {
+        0 => {
+          step[ch] = (step[ch] - 1).max(0);
+          buf.extend_from_slice(&(predicted[ch] as i16).to_le_bytes());
+        }
+        1 => {
+          step[ch] = (step[ch] + 8).min(ADPCM_MAX_STEP);
+          ch = (ch + 1) % channels;
+        }
+        2 => ch = (ch + 1) % channels,
+        _ => {
+          step[ch] = (step[ch] - 8).max(0);
+          ch = (ch + 1) % channels;
+        }
+      }
+    } else {
+      let step_size = ADPCM_STEP_SIZES[step[ch] as usize];
+      let mut difference = step_size >> shift;
+      for bit in 0..6 {
+        if encoded & (1 << bit) != 0 {
+          difference += step_size >> bit;
+        }
+      }
+      predicted[ch] = if encoded & 0x40 != 0 {
+        (predicted[ch] - difference).max(i16::MIN as i32)
+      } else {
+        (predicted[ch] + difference).min(i16::MAX as i32)
+      };
+      buf.extend_from_slice(&(predicted[ch] as i16).to_le_bytes());
+      step[ch] = (step[ch] + ADPCM_NEXT_STEP[(encoded & 0x1F) as usize]).clamp(0, ADPCM_MAX_STEP);
+    }
+  }
+  Ok(buf)
+}
+
+#[test]
+fn test_explode() {
+  // "AIAIAIAIAIAIA" imploded in binary mode with a 1024 byte dictionary, from the
+  // PKWARE DCL format description
+  let data = [0x00, 0x04, 0x82, 0x24, 0x25, 0x8f, 0x80, 0x7f];
+  assert_eq!(explode(&data, 13).unwrap(), b"AIAIAIAIAIAIA");
+}
+
+#[test]
+fn test_sparse() {
+  let data = [0, 0, 0, 8, 0x81, 1, 2, 0x00, 0x80, 3, 0x7f];
+  assert_eq!(sparse(&data, 8).unwrap(), [1, 2, 0, 0, 0, 3, 0, 0]);
+}
+
+#[test]
+fn test_compress() {
+  let data = b"Hello, MPQ! ".repeat(64);
+  for mask in [COMPRESSION_ZLIB, COMPRESSION_BZIP2] {
+    let compressed = compress(&data, mask as u32).unwrap().unwrap();
+    assert_eq!(compressed[0], mask);
+    assert_eq!(decompress(&compressed, data.len()).unwrap(), data);
+  }
+  assert!(compress(b"abc", COMPRESSION_ZLIB as u32).unwrap().is_none());
+  assert!(matches!(
+    compress(&data, COMPRESSION_PKWARE as u32),
+    Err(StormError::NotSupported)
+  ));
+}
@@ -0,0 +1,1135 @@
+//! Native backend
+//!
+//! Reads MPQ archives of format 1 to 4 without StormLib, selected with the `pure-rust`
+//! feature. The hash, block, hi-block, HET and BET tables, encrypted files, sector CRCs
+//! and all compressions except Huffman are supported. Huffman compressed sectors, which
+//! are only used together with ADPCM for WAVE files, fail with
+//! [`StormError::NotSupported`], as do patch files.
+//!
+//! Archives of format 1 and 2 can also be created and modified. Files are written with
+//! zlib or bzip2 compression, and the `(listfile)` and `(attributes)` are kept up to date
+//! the way StormLib does.
+
+mod attributes;
+mod compression;
+mod sectors;
+mod tables;
+mod writer;
+
+use std::collections::HashMap;
+use std::fs;
+use std::marker::PhantomData;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use stormlib_sys::*;
+
+use self::attributes::{Attributes, FileAttributes};
+use self::tables::{BlockEntry, HashEntry, Header, Tables};
+use crate::error::*;
+use crate::raw::RawFile;
+use crate::{ArchiveInfo, ArchiveMode, MpqPath, OpenArchiveFlags, ReadOnly, ReadWrite};
+use crate::{CompressionFlags, CreateArchiveFlags, CreateFileFlags, CreateFileOptions};
+use crate::{SignatureStatus, VerifyFileFlags, VerifyFileResult};
+
+const LISTFILE_NAME: &[u8] = b"(listfile)";
+const ATTRIBUTES_NAME: &[u8] = b"(attributes)";
+const INTERNAL_NAMES: [&[u8]; 3] = [LISTFILE_NAME, ATTRIBUTES_NAME, b"(signature)"];
+
+/// Sector size of new archives, the same StormLib uses
+const DEFAULT_SECTOR_SIZE: u32 = 0x1000;
+
+/// MPQ archive
+///
+/// Archives are writable by default. Archives opened with [`Archive::open_read_only`] are
+/// `Archive<ReadOnly>` and only provide the read methods.
+///
+/// Changes are written to the file right away, the tables, `(listfile)` and `(attributes)`
+/// when the archive is flushed or closed. Use [`Archive::close`] to find out whether that
+/// succeeded, dropping the archive only logs the error.
+#[derive(Debug)]
+pub struct Archive<M: ArchiveMode = ReadWrite> {
+  inner: Arc<Inner>,
+  _mode: PhantomData<M>,
+}
+
+#[derive(Debug)]
+pub(crate) struct Inner {
+  state: Mutex<State>,
+  flags: OpenArchiveFlags,
+}
+
+#[derive(Debug)]
+struct State {
+  file: fs::File,
+  tables: Tables,
+  /// Names found in listfiles, by hash index
+  names: HashMap<u32, Vec<u8>>,
+  /// Contents of the `(attributes)`, written again on flush
+  attributes: Option<Attributes>,
+  /// Whether the `(listfile)` is written again on flush
+  listfile: bool,
+  /// Opened with `STREAM_FLAG_READ_ONLY`
+  read_only: bool,
+  changed: bool,
+  panic_on_unflushed_drop: bool,
+}
+
+impl Archive {
+  /// Creates new MPQ archive. Only format 1 and 2 archives can be created.
+  pub fn create<P: AsRef<Path>>(
+    path: P,
+    flags: CreateArchiveFlags,
+    max_files_count: DWORD,
+  ) -> Result<Self> {
+    Self::create_with_sector_size(path, flags, max_files_count, DEFAULT_SECTOR_SIZE)
+  }
+
+  /// Creates new MPQ archive with files split into sectors of `sector_size` bytes, a power of
+  /// two of at least 512
+  pub fn create_with_sector_size<P: AsRef<Path>>(
+    path: P,
+    flags: CreateArchiveFlags,
+    max_files_count: DWORD,
+    sector_size: u32,
+  ) -> Result<Self> {
+    if sector_size < 0x200 || !sector_size.is_power_of_two() {
+      return Err(StormError::InvalidParameter);
+    }
+    let format_version = match flags.bits() & MPQ_CREATE_ARCHIVE_VMASK {
+      MPQ_CREATE_ARCHIVE_V1 => 0,
+      MPQ_CREATE_ARCHIVE_V2 => 1,
+      _ => return Err(StormError::NotSupported),
+    };
+    let listfile = flags.contains(CreateArchiveFlags::MPQ_CREATE_LISTFILE);
+    let attributes = flags
+      .contains(CreateArchiveFlags::MPQ_CREATE_ATTRIBUTES)
+      .then(|| Attributes::new(MPQ_ATTRIBUTE_CRC32 | MPQ_ATTRIBUTE_FILETIME | MPQ_ATTRIBUTE_MD5));
+    // Like StormLib, the internal files don't count towards the file limit
+    let hash_table_size = max_files_count
+      .saturating_add(listfile as u32 + attributes.is_some() as u32)
+      .clamp(HASH_TABLE_SIZE_MIN, HASH_TABLE_SIZE_MAX)
+      .next_power_of_two();
+
+    let file = fs::OpenOptions::new()
+      .read(true)
+      .write(true)
+      .create_new(true)
+      .open(path)
+      .map_err(io_error)?;
+    let header = Header {
+      format_version,
+      sector_size,
+      ..Header::default()
+    };
+    Ok(Archive::from_state(
+      State {
+        file,
+        tables: Tables::create(header, hash_table_size),
+        names: HashMap::new(),
+        attributes,
+        listfile,
+        read_only: false,
+        changed: true,
+        panic_on_unflushed_drop: false,
+      },
+      OpenArchiveFlags::empty(),
+    ))
+  }
+
+  /// Opens a MPQ archive
+  ///
+  /// `STREAM_FLAG_READ_ONLY` is removed from `flags`, use [`Archive::open_read_only`] to open
+  /// an archive that can't be changed.
+  pub fn open<P: AsRef<Path>>(path: P, flags: OpenArchiveFlags) -> Result<Self> {
+    Archive::open_with_flags(
+      path.as_ref(),
+      flags - OpenArchiveFlags::STREAM_FLAG_READ_ONLY,
+    )
+  }
+
+  /// Converts the archive to a read-only archive
+  pub fn into_read_only(self) -> Archive<ReadOnly> {
+    Archive {
+      inner: self.inner,
+      _mode: PhantomData,
+    }
+  }
+
+  /// Makes dropping the archive panic in debug builds if it has changes that were not
+  /// flushed, to catch code paths that forget to call [`Archive::close`] or [`Archive::flush`].
+  /// The archive is still flushed before panicking.
+  pub fn set_panic_on_unflushed_drop(&mut self, enabled: bool) {
+    self.inner.lock().panic_on_unflushed_drop = enabled;
+  }
+
+  /// Flushes in-memory changes to the archive on disk. This function is not necessary to call, as the archive will be flushed automatically when closed
+  pub fn flush(&mut self) -> Result<()> {
+    self.inner.lock().flush()
+  }
+
+  /// Compacts the archive, moving all files to the start and dropping the space of removed
+  /// and replaced files
+  pub fn compact(&mut self) -> Result<()> {
+    self.inner.lock().compact()
+  }
+
+  /// Changes max file count of the archive. Rebuilding the hash table needs the names of all
+  /// files, archives with unnamed files fail with [`StormError::CanNotComplete`].
+  pub fn set_max_file_count(&mut self, max_files_count: DWORD) -> Result<()> {
+    self.inner.lock().set_max_file_count(max_files_count)
+  }
+
+  /// Creates a new file within the archive
+  pub fn create_file<P: AsRef<MpqPath> + ?Sized>(
+    &mut self,
+    opts: CreateFileOptions<P>,
+  ) -> Result<()> {
+    let name = opts.path.as_ref().to_cstring()?.into_bytes();
+    self.inner.lock().write_file(
+      &name,
+      opts.data,
+      opts.flags.bits(),
+      opts.compression.bits(),
+      opts.mtime,
+    )
+  }
+
+  /// Adds a file from the local filesystem to the archive
+  pub fn add_file<P: AsRef<Path>, N: AsRef<MpqPath>>(
+    &mut self,
+    local_path: P,
+    archived_name: N,
+    flags: CreateFileFlags,
+    compression: CompressionFlags,
+  ) -> Result<()> {
+    let name = archived_name.as_ref().to_cstring()?.into_bytes();
+    let data = fs::read(local_path.as_ref()).map_err(io_error)?;
+    let file_time = fs::metadata(local_path.as_ref())
+      .and_then(|metadata| metadata.modified())
+      .map(file_time)
+      .unwrap_or(0);
+    self
+      .inner
+      .lock()
+      .write_file(&name, &data, flags.bits(), compression.bits(), file_time)
+  }
+
+  pub fn remove_file<N: AsRef<MpqPath>>(&mut self, path: N) -> Result<bool> {
+    let name = path.as_ref().to_cstring()?.into_bytes();
+    self.inner.lock().remove_file(&name)
+  }
+
+  /// Renames a file within the archive
+  pub fn rename_file<N: AsRef<MpqPath>, T: AsRef<MpqPath>>(
+    &mut self,
+    old_path: N,
+    new_path: T,
+  ) -> Result<()> {
+    let old_name = old_path.as_ref().to_cstring()?.into_bytes();
+    let new_name = new_path.as_ref().to_cstring()?.into_bytes();
+    self.inner.lock().rename_file(&old_name, &new_name)
+  }
+
+  /// Copies a file of `other` as it is stored. The file is only decoded when the sector sizes
+  /// differ, or for its `(attributes)` entry when `other` has no checksums for it.
+  pub(crate) fn copy_file<M: ArchiveMode>(
+    &mut self,
+    other: &Archive<M>,
+    name: &MpqPath,
+    new_name: &MpqPath,
+    replace: bool,
+  ) -> Result<()> {
+    let new_name = new_name.to_cstring()?.into_bytes();
+    let mut file = FileHandle::open(other, name)?;
+    let raw = file.read_raw()?;
+    let source = other
+      .inner
+      .lock()
+      .attributes
+      .as_ref()
+      .map(|attributes| (attributes.flags, attributes.get(file.block_index)));
+    let (sector_size, has_attributes) = {
+      let state = self.inner.lock();
+      (state.tables.header.sector_size, state.attributes.is_some())
+    };
+    let file_time = source.map_or(0, |(_, attributes)| attributes.file_time);
+
+    let sectored = raw.flags & MPQ_FILE_SINGLE_UNIT == 0 && raw.size != 0;
+    if sectored && raw.sector_size != sector_size {
+      let data = file.read_all()?;
+      let mut flags = raw.flags & !MPQ_FILE_EXISTS;
+      if replace {
+        flags |= MPQ_FILE_REPLACEEXISTING;
+      }
+      let compression = raw.compression()?;
+      return self
+        .inner
+        .lock()
+        .write_file(&new_name, &data, flags, compression, file_time);
+    }
+
+    let checksums = MPQ_ATTRIBUTE_CRC32 | MPQ_ATTRIBUTE_MD5;
+    let attributes = match source {
+      Some((flags, attributes)) if flags & checksums == checksums => attributes,
+      _ if has_attributes => FileAttributes::new(&file.read_all()?, file_time),
+      _ => FileAttributes::default(),
+    };
+    self
+      .inner
+      .lock()
+      .write_raw_file(&new_name, raw, attributes, replace)
+  }
+}
+
+impl Archive<ReadOnly> {
+  /// Opens a MPQ archive for reading only. `STREAM_FLAG_READ_ONLY` is always added to `flags`.
+  pub fn open_read_only<P: AsRef<Path>>(path: P, flags: OpenArchiveFlags) -> Result<Self> {
+    Archive::open_with_flags(
+      path.as_ref(),
+      flags | OpenArchiveFlags::STREAM_FLAG_READ_ONLY,
+    )
+  }
+}
+
+impl<M: ArchiveMode> Archive<M> {
+  fn open_with_flags(path: &Path, flags: OpenArchiveFlags) -> Result<Self> {
+    let mut file = fs::OpenOptions::new()
+      .read(true)
+      .write(!flags.contains(OpenArchiveFlags::STREAM_FLAG_READ_ONLY))
+      .open(path)
+      .map_err(io_error)?;
+    let tables = Tables::load(&mut file, flags)?;
+    let archive = Archive::from_state(
+      State {
+        file,
+        tables,
+        names: HashMap::new(),
+        attributes: None,
+        listfile: false,
+        read_only: flags.contains(OpenArchiveFlags::STREAM_FLAG_READ_ONLY),
+        changed: false,
+        panic_on_unflushed_drop: false,
+      },
+      flags,
+    );
+    let inner = &archive.inner;
+
+    let mut names: Vec<Vec<u8>> = INTERNAL_NAMES.iter().map(|name| name.to_vec()).collect();
+    let listfile = if flags.contains(OpenArchiveFlags::MPQ_OPEN_NO_LISTFILE) {
+      None
+    } else {
+      inner.read_named(LISTFILE_NAME).ok()
+    };
+    if let Some(listfile) = &listfile {
+      names.extend(parse_listfile(listfile));
+    }
+    let attributes = if flags.contains(OpenArchiveFlags::MPQ_OPEN_NO_ATTRIBUTES) {
+      None
+    } else {
+      inner
+        .read_named(ATTRIBUTES_NAME)
+        .ok()
+        .and_then(|data| Attributes::parse(&data, inner.lock().tables.blocks.len()))
+    };
+
+    {
+      let mut state = inner.lock();
+      state.names = state.resolve_names(names);
+      state.listfile = listfile.is_some();
+      state.attributes = attributes;
+    }
+    Ok(archive)
+  }
+
+  fn from_state(state: State, flags: OpenArchiveFlags) -> Self {
+    Archive {
+      inner: Arc::new(Inner {
+        state: Mutex::new(state),
+        flags,
+      }),
+      _mode: PhantomData,
+    }
+  }
+
+  /// Closes the archive, writing any pending changes to disk
+  pub fn close(self) -> Result<()> {
+    let mut state = self.inner.lock();
+    let result = state.flush();
+    // Like StormLib, changes that could not be written are dropped with the archive
+    state.changed = false;
+    result
+  }
+
+  /// Returns `true` if the archive has changes that will be written when it's flushed or closed
+  pub fn has_unflushed_changes(&self) -> bool {
+    self.inner.lock().changed
+  }
+
+  /// Quick check if the file exists within MPQ archive, without opening it
+  ///
+  /// Like all methods taking a file name, `path` can be a string, an [`MpqPath`], or the raw
+  /// bytes of a name stored in a legacy codepage (see [`Codepage`](crate::Codepage)).
+  pub fn has_file<N: AsRef<MpqPath>>(&self, path: N) -> Result<bool> {
+    let name = path.as_ref().to_cstring()?.into_bytes();
+    Ok(self.inner.lock().find(&name).is_some())
+  }
+
+  /// Opens a file from MPQ archive
+  pub fn open_file<N: AsRef<MpqPath>>(&self, path: N) -> Result<File<'_>> {
+    Ok(File {
+      inner: FileHandle::open(self, path.as_ref())?,
+      _archive: PhantomData,
+    })
+  }
+
+  /// Opens a file from MPQ archive. The returned file holds a reference to the archive
+  /// instead of borrowing it, so it can be stored, returned or moved into another thread.
+  pub fn open_file_owned<N: AsRef<MpqPath>>(self: &Arc<Self>, path: N) -> Result<OwnedFile<M>> {
+    Ok(OwnedFile {
+      inner: FileHandle::open(self, path.as_ref())?,
+      archive: self.clone(),
+    })
+  }
+
+  /// Extracts a file from the archive to the local filesystem
+  pub fn extract_file<N: AsRef<MpqPath>, P: AsRef<Path>>(
+    &self,
+    archived_name: N,
+    local_path: P,
+  ) -> Result<()> {
+    let data = self.open_file(archived_name)?.read_all()?;
+    fs::write(local_path, data).map_err(io_error)
+  }
+
+  /// Searches for files within the archive. If `filter` is `None`, all files will be returned
+  pub fn search(&self, filter: Option<&str>) -> Result<Search<'_>> {
+    self.search_with_listfile(filter.unwrap_or("*"), None)
+  }
+
+  /// Searches for files matching the StormLib `mask`, naming files with an additional
+  /// local listfile
+  pub(crate) fn search_with_listfile(
+    &self,
+    mask: &str,
+    listfile: Option<&Path>,
+  ) -> Result<Search<'_>> {
+    let state = self.inner.lock();
+    let extra_names = match listfile {
+      Some(path) => state.resolve_names(parse_listfile(&fs::read(path).map_err(io_error)?)),
+      None => HashMap::new(),
+    };
+
+    let results: Vec<_> = state
+      .tables
+      .entries()
+      .into_iter()
+      .filter_map(|entry| {
+        let name = match state
+          .names
+          .get(&entry.hash_index)
+          .or_else(|| extra_names.get(&entry.hash_index))
+        {
+          Some(name) => name.clone(),
+          None => pseudo_name(entry.block_index),
+        };
+        if !matches_mask(mask.as_bytes(), &name) {
+          return None;
+        }
+        Some(state.find_data(&entry, &name))
+      })
+      .collect();
+
+    Ok(Search {
+      results: results.into_iter(),
+      _archive: PhantomData,
+    })
+  }
+
+  /// Retrieves the archive header and table information
+  pub fn info(&self) -> Result<ArchiveInfo> {
+    let state = self.inner.lock();
+    let header = &state.tables.header;
+    let mut flags = 0;
+    if self
+      .inner
+      .flags
+      .contains(OpenArchiveFlags::STREAM_FLAG_READ_ONLY)
+    {
+      flags |= MPQ_FLAG_READ_ONLY;
+    }
+    if header.war3_map {
+      flags |= MPQ_FLAG_WAR3_MAP;
+    }
+    Ok(ArchiveInfo {
+      format_version: header.format_version,
+      header_offset: header.offset,
+      archive_size: header.archive_size,
+      sector_size: header.sector_size,
+      file_count: state
+        .tables
+        .blocks
+        .iter()
+        .filter(|block| block.flags & MPQ_FILE_EXISTS != 0)
+        .count() as u32,
+      max_file_count: state.tables.hash_table_size(),
+      hash_table_size: state.tables.hash_table_size(),
+      block_table_size: state.tables.blocks.len() as u32,
+      flags,
+    })
+  }
+
+  /// Returns StormLib's archive struct
+  ///
+  /// The native backend has no such struct and always returns `None`.
+  ///
+  /// # Safety
+  ///
+  /// Always safe to call, it is only `unsafe` to match the StormLib backend.
+  pub unsafe fn get_ref(&self) -> Option<&_TMPQArchive> {
+    None
+  }
+
+  /// Verifies the checksums stored for a file. Problems are reported in the result flags,
+  /// the `Err` variant is only returned for invalid names. The CRC32 and MD5 come from the
+  /// `(attributes)`, raw MD5s of format 4 archives are not checked.
+  pub fn verify_file<N: AsRef<MpqPath>>(
+    &self,
+    path: N,
+    flags: VerifyFileFlags,
+  ) -> Result<VerifyFileResult> {
+    let path = path.as_ref();
+    path.to_cstring()?;
+    let file = match FileHandle::open(self, path) {
+      Ok(file) => file,
+      Err(_) => return Ok(VerifyFileResult::VERIFY_OPEN_ERROR),
+    };
+    let (block, attributes) = {
+      let state = self.inner.lock();
+      let block = state.tables.blocks[file.block_index as usize];
+      let attributes = state
+        .attributes
+        .as_ref()
+        .map(|attributes| (attributes.flags, attributes.get(file.block_index)));
+      (block, attributes)
+    };
+
+    let mut result = VerifyFileResult::empty();
+    let check_sectors = flags.contains(VerifyFileFlags::SFILE_VERIFY_SECTOR_CRC)
+      && block.flags & MPQ_FILE_SECTOR_CRC != 0;
+    if check_sectors {
+      result |= VerifyFileResult::VERIFY_FILE_HAS_SECTOR_CRC;
+    }
+    let name = file.name.as_deref();
+    let data = match self
+      .inner
+      .decode_file(file.block_index, name, check_sectors)
+    {
+      Ok(data) => data,
+      // Tell a sector CRC mismatch from data that can't be read at all
+      Err(_) if check_sectors => match self.inner.decode_file(file.block_index, name, false) {
+        Ok(_) => return Ok(result | VerifyFileResult::VERIFY_FILE_SECTOR_CRC_ERROR),
+        Err(_) => return Ok(result | VerifyFileResult::VERIFY_READ_ERROR),
+      },
+      Err(_) => return Ok(result | VerifyFileResult::VERIFY_READ_ERROR),
+    };
+
+    if let Some((attribute_flags, expected)) = attributes {
+      let actual = FileAttributes::new(&data, 0);
+      if flags.contains(VerifyFileFlags::SFILE_VERIFY_FILE_CRC)
+        && attribute_flags & MPQ_ATTRIBUTE_CRC32 != 0
+        && expected.crc32 != 0
+      {
+        result |= VerifyFileResult::VERIFY_FILE_HAS_CHECKSUM;
+        if actual.crc32 != expected.crc32 {
+          result |= VerifyFileResult::VERIFY_FILE_CHECKSUM_ERROR;
+        }
+      }
+      if flags.contains(VerifyFileFlags::SFILE_VERIFY_FILE_MD5)
+        && attribute_flags & MPQ_ATTRIBUTE_MD5 != 0
+        && expected.md5 != [0; 16]
+      {
+        result |= VerifyFileResult::VERIFY_FILE_HAS_MD5;
+        if actual.md5 != expected.md5 {
+          result |= VerifyFileResult::VERIFY_FILE_MD5_ERROR;
+        }
+      }
+    }
+    Ok(result)
+  }
+
+  /// Verifies the digital signature of the archive. Signatures can't be checked by the
+  /// native backend, signed archives fail with [`StormError::NotSupported`].
+  pub fn verify_signature(&self) -> Result<SignatureStatus> {
+    let mut state = self.inner.lock();
+    // A weak signature is stored in `(signature)`, a strong one follows the archive
+    let weak = state.tables.find(b"(signature)").is_some();
+    let header = &state.tables.header;
+    let end = header.offset + header.archive_size;
+    let strong = tables::read_at(&mut state.file, end, 4)? == b"NGIS";
+    if weak || strong {
+      return Err(StormError::NotSupported);
+    }
+    Ok(SignatureStatus::None)
+  }
+
+  /// Reads up to `len` bytes before the MPQ header, such as the header of a Warcraft III map
+  pub(crate) fn read_prefix(&self, len: u64) -> Result<Vec<u8>> {
+    let mut state = self.inner.lock();
+    let len = len.min(state.tables.header.offset);
+    tables::read_at(&mut state.file, 0, len as usize)
+  }
+}
+
+impl Inner {
+  fn lock(&self) -> MutexGuard<'_, State> {
+    self.state.lock().unwrap_or_else(|e| e.into_inner())
+  }
+
+  fn read_named(&self, name: &[u8]) -> Result<Vec<u8>> {
+    let entry = self.lock().find(name).ok_or(StormError::FileNotFound)?;
+    self.read_file(entry.block_index, Some(name))
+  }
+
+  /// Reads and decodes a file. Without a name the key of encrypted files is recovered from
+  /// the sector offset table, which only works for compressed files.
+  fn read_file(&self, block_index: u32, name: Option<&[u8]>) -> Result<Vec<u8>> {
+    let check_crc = self
+      .flags
+      .contains(OpenArchiveFlags::MPQ_OPEN_CHECK_SECTOR_CRC);
+    self.decode_file(block_index, name, check_crc)
+  }
+
+  fn decode_file(&self, block_index: u32, name: Option<&[u8]>, check_crc: bool) -> Result<Vec<u8>> {
+    let (block, sector_size, raw) = {
+      let mut state = self.lock();
+      let block = state.tables.blocks[block_index as usize];
+      if block.flags & MPQ_FILE_PATCH_FILE != 0 {
+        return Err(StormError::NotSupported);
+      }
+      let raw = state.read_raw(&block)?;
+      (block, state.tables.header.sector_size, raw)
+    };
+    let key = sectors::file_key(&block, name, &raw, sector_size);
+    sectors::decode(&raw, &block, sector_size, key, check_crc)
+  }
+
+  fn read_raw_file(&self, block_index: u32, name: Option<&[u8]>) -> Result<RawFile> {
+    let mut state = self.lock();
+    let block = state.tables.blocks[block_index as usize];
+    let data = state.read_raw(&block)?;
+    let sector_size = state.tables.header.sector_size;
+    let key = sectors::file_key(&block, name, &data, sector_size);
+    Ok(RawFile {
+      data,
+      flags: block.flags,
+      size: block.size,
+      sector_size,
+      key,
+    })
+  }
+}
+
+impl Drop for Inner {
+  fn drop(&mut self) {
+    let state = self.state.get_mut().unwrap_or_else(|e| e.into_inner());
+    let unflushed = cfg!(debug_assertions)
+      && state.panic_on_unflushed_drop
+      && !std::thread::panicking()
+      && state.changed;
+
+    if let Err(err) = state.flush() {
+      log::error!("failed to close archive: {}", err);
+    }
+
+    if unflushed {
+      panic!("archive with unflushed changes was dropped, use `Archive::close` instead");
+    }
+  }
+}
+
+impl State {
+  /// Reads the stored data of a file
+  fn read_raw(&mut self, block: &BlockEntry) -> Result<Vec<u8>> {
+    let pos = self.tables.header.offset + block.offset;
+    tables::read_at(&mut self.file, pos, sectors::stored_size(block) as usize)
+  }
+
+  /// Finds a file by name, or by the `FileXXXXXXXX.xxx` name of an unnamed file
+  fn find(&self, name: &[u8]) -> Option<HashEntry> {
+    self.tables.find(name).or_else(|| {
+      let block_index = parse_pseudo_name(name)?;
+      self
+        .tables
+        .entries()
+        .into_iter()
+        .find(|entry| entry.block_index == block_index)
+    })
+  }
+
+  fn resolve_names(&self, names: Vec<Vec<u8>>) -> HashMap<u32, Vec<u8>> {
+    let mut resolved = self.names.clone();
+    for name in names {
+      if let Some(entry) = self.tables.find(&name) {
+        resolved.entry(entry.hash_index).or_insert(name);
+      }
+    }
+    resolved
+  }
+
+  fn find_data(&self, entry: &HashEntry, name: &[u8]) -> SFILE_FIND_DATA {
+    let block: BlockEntry = self.tables.blocks[entry.block_index as usize];
+    let file_time = self
+      .attributes
+      .as_ref()
+      .map_or(0, |attributes| attributes.get(entry.block_index).file_time);
+
+    let mut data: SFILE_FIND_DATA = unsafe { std::mem::zeroed() };
+    let len = name.len().min(data.cFileName.len() - 1);
+    for (dst, &src) in data.cFileName.iter_mut().zip(&name[..len]) {
+      *dst = src as _;
+    }
+    data.dwHashIndex = entry.hash_index;
+    data.dwBlockIndex = entry.block_index;
+    data.dwFileSize = block.size as u32;
+    data.dwFileFlags = block.flags;
+    data.dwCompSize = block.compressed_size as u32;
+    data.dwFileTimeLo = file_time as u32;
+    data.dwFileTimeHi = (file_time >> 32) as u32;
+    data.lcLocale = entry.locale as _;
+    data
+  }
+}
+
+/// Splits a listfile into names. Names are separated by line breaks or `;`.
+fn parse_listfile(data: &[u8]) -> Vec<Vec<u8>> {
+  data
+    .split(|&c| c == b'\r' || c == b'\n' || c == b';')
+    .map(|name| name.trim_ascii())
+    .filter(|name| !name.is_empty())
+    .map(|name| name.to_vec())
+    .collect()
+}
+
+/// Converts a time to a `FILETIME`, in 100ns intervals since 1601
+fn file_time(time: SystemTime) -> u64 {
+  let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+  (since_epoch.as_secs() + 11_644_473_600) * 10_000_000 + since_epoch.subsec_nanos() as u64 / 100
+}
+
+/// Name StormLib gives to files missing from the listfile
+fn pseudo_name(block_index: u32) -> Vec<u8> {
+  format!("File{:08}.xxx", block_index).into_bytes()
+}
+
+fn parse_pseudo_name(name: &[u8]) -> Option<u32> {
+  if name.len() < 12 || !name[..4].eq_ignore_ascii_case(b"File") {
+    return None;
+  }
+  let digits = &name[4..12];
+  if !digits.iter().all(u8::is_ascii_digit) || name.get(12).is_some_and(|&c| c != b'.') {
+    return None;
+  }
+  std::str::from_utf8(digits).ok()?.parse().ok()
+}
+
+/// Matches a name against a StormLib wildcard mask, where `*` matches any sequence and `?`
+/// any single character, ignoring ASCII case
+fn matches_mask(mask: &[u8], name: &[u8]) -> bool {
+  let (mut m, mut n) = (0, 0);
+  let mut star = None;
+  while n < name.len() {
+    match mask.get(m) {
+      Some(b'*') => {
+        star = Some((m, n));
+        m += 1;
+      }
+      Some(&c) if c == b'?' || c.eq_ignore_ascii_case(&name[n]) => {
+        m += 1;
+        n += 1;
+      }
+      _ => match star {
+        Some((star_m, star_n)) => {
+          m = star_m + 1;
+          n = star_n + 1;
+          star = Some((star_m, star_n + 1));
+        }
+        None => return false,
+      },
+    }
+  }
+  mask[m..].iter().all(|&c| c == b'*')
+}
+
+/// Opened file
+#[derive(Debug)]
+pub struct File<'a> {
+  inner: FileHandle,
+  _archive: PhantomData<&'a ()>,
+}
+
+impl<'a> File<'a> {
+  /// Retrieves a size of the file within archive
+  pub fn get_size(&mut self) -> Result<u64> {
+    self.inner.get_size()
+  }
+
+  /// Reads all data from the file
+  pub fn read_all(&mut self) -> Result<Vec<u8>> {
+    self.inner.read_all()
+  }
+
+  /// Reads the data of the file as it is stored in the archive, without decrypting and
+  /// decompressing it
+  pub fn read_raw(&mut self) -> Result<RawFile> {
+    self.inner.read_raw()
+  }
+
+  /// `FILETIME` of the file, `0` if it has none
+  pub(crate) fn file_time(&mut self) -> Result<u64> {
+    self.inner.file_time()
+  }
+
+  /// Closes the file
+  pub fn close(self) -> Result<()> {
+    self.inner.close()
+  }
+}
+
+/// Opened file that keeps its archive alive
+#[derive(Debug)]
+pub struct OwnedFile<M: ArchiveMode = ReadWrite> {
+  inner: FileHandle,
+  archive: Arc<Archive<M>>,
+}
+
+impl<M: ArchiveMode> OwnedFile<M> {
+  /// Returns the archive this file was opened from
+  pub fn archive(&self) -> &Arc<Archive<M>> {
+    &self.archive
+  }
+
+  /// Retrieves a size of the file within archive
+  pub fn get_size(&mut self) -> Result<u64> {
+    self.inner.get_size()
+  }
+
+  /// Reads all data from the file
+  pub fn read_all(&mut self) -> Result<Vec<u8>> {
+    self.inner.read_all()
+  }
+
+  /// Reads the data of the file as it is stored in the archive, without decrypting and
+  /// decompressing it
+  pub fn read_raw(&mut self) -> Result<RawFile> {
+    self.inner.read_raw()
+  }
+
+  /// Closes the file
+  pub fn close(self) -> Result<()> {
+    self.inner.close()
+  }
+}
+
+/// File entry shared by the borrowed, owned and shared file types
+#[derive(Debug)]
+pub(crate) struct FileHandle {
+  archive: Arc<Inner>,
+  block_index: u32,
+  size: u64,
+  name: Option<Vec<u8>>,
+}
+
+impl FileHandle {
+  pub(crate) fn open<M: ArchiveMode>(archive: &Archive<M>, path: &MpqPath) -> Result<Self> {
+    Self::open_with(archive, path, |state, name| state.find(name))
+  }
+
+  /// Opens the file in exactly `locale`, unnamed files by their pseudo name
+  pub(crate) fn open_locale<M: ArchiveMode>(
+    archive: &Archive<M>,
+    path: &MpqPath,
+    locale: LCID,
+  ) -> Result<Self> {
+    Self::open_with(archive, path, |state, name| {
+      state
+        .tables
+        .find_locale(name, locale as u16)
+        .or_else(|| parse_pseudo_name(name).and_then(|_| state.find(name)))
+    })
+  }
+
+  fn open_with<M: ArchiveMode>(
+    archive: &Archive<M>,
+    path: &MpqPath,
+    find: impl FnOnce(&State, &[u8]) -> Option<HashEntry>,
+  ) -> Result<Self> {
+    let name = path.to_cstring()?.into_bytes();
+    let state = archive.inner.lock();
+    let entry = find(&state, &name).ok_or(StormError::FileNotFound)?;
+    let size = state.tables.blocks[entry.block_index as usize].size;
+    // Files opened by their pseudo name can still be decrypted if the listfile named them
+    let name = if state.tables.find(&name).is_some() {
+      Some(name)
+    } else {
+      state.names.get(&entry.hash_index).cloned()
+    };
+    Ok(FileHandle {
+      archive: archive.inner.clone(),
+      block_index: entry.block_index,
+      size,
+      name,
+    })
+  }
+
+  pub(crate) fn close(self) -> Result<()> {
+    Ok(())
+  }
+
+  pub(crate) fn get_size(&mut self) -> Result<u64> {
+    Ok(self.size)
+  }
+
+  pub(crate) fn read_all(&mut self) -> Result<Vec<u8>> {
+    self
+      .archive
+      .read_file(self.block_index, self.name.as_deref())
+  }
+
+  pub(crate) fn read_raw(&mut self) -> Result<RawFile> {
+    self
+      .archive
+      .read_raw_file(self.block_index, self.name.as_deref())
+  }
+
+  pub(crate) fn file_time(&mut self) -> Result<u64> {
+    let state = self.archive.lock();
+    let attributes = state.attributes.as_ref();
+    Ok(attributes.map_or(0, |attributes| attributes.get(self.block_index).file_time))
+  }
+}
+
+/// Search iterator
+#[derive(Debug)]
+pub struct Search<'a> {
+  results: std::vec::IntoIter<SFILE_FIND_DATA>,
+  _archive: PhantomData<&'a ()>,
+}
+
+impl<'a> Iterator for Search<'a> {
+  type Item = SFILE_FIND_DATA;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    self.results.next()
+  }
+}
+
+#[test]
+fn test_matches_mask() {
+  assert!(matches_mask(b"*", b"war3map.j"));
+  assert!(matches_mask(b"WAR3MAP.*", b"war3map.j"));
+  assert!(matches_mask(b"*.w3?", b"war3map.w3e"));
+  assert!(matches_mask(b"units\\*.mdx", b"Units\\Human\\Footman.mdx"));
+  assert!(!matches_mask(b"*.j", b"war3map.w3e"));
+  assert!(!matches_mask(b"war3map.?", b"war3map.wts"));
+}
+
+#[test]
+fn test_pseudo_name() {
+  assert_eq!(parse_pseudo_name(&pseudo_name(16)), Some(16));
+  assert_eq!(parse_pseudo_name(b"file00000003"), Some(3));
+  assert_eq!(parse_pseudo_name(b"File0000003.xxx"), None);
+  assert_eq!(parse_pseudo_name(b"war3map.j"), None);
+}
+
+#[test]
+fn test_read_without_names() {
+  let archive = Archive::open_read_only(
+    "../../samples/test_tft.w3x",
+    OpenArchiveFlags::MPQ_OPEN_NO_LISTFILE | OpenArchiveFlags::MPQ_OPEN_CHECK_SECTOR_CRC,
+  )
+  .unwrap();
+  let names: Vec<_> = archive
+    .search(None)
+    .unwrap()
+    .map(|data| crate::FindDataExt::name_bytes(&data).to_vec())
+    .collect();
+  assert!(names.contains(&b"(listfile)".to_vec()));
+  assert!(names.contains(&b"File00000005.xxx".to_vec()));
+
+  // The key of the encrypted listfile is recovered from its sector offset table
+  assert_eq!(
+    archive.inner.read_file(15, None).unwrap(),
+    archive.open_file("(listfile)").unwrap().read_all().unwrap()
+  );
+  assert_eq!(
+    archive
+      .open_file("File00000005.xxx")
+      .unwrap()
+      .read_all()
+      .unwrap(),
+    std::fs::read("../../samples/war3map.j").unwrap()
+  );
+}
+
+#[test]
+fn test_write_archive() {
+  let war3map_j = std::fs::read("../../samples/war3map.j").unwrap();
+  let compress = CreateFileFlags::MPQ_FILE_COMPRESS;
+  let encrypted = CreateFileFlags::MPQ_FILE_ENCRYPTED | CreateFileFlags::MPQ_FILE_FIX_KEY;
+  let zlib = CompressionFlags::MPQ_COMPRESSION_ZLIB;
+  let bzip2 = CompressionFlags::MPQ_COMPRESSION_BZIP2;
+  let files = [
+    ("stored.j", CreateFileFlags::empty(), zlib),
+    ("zlib.j", compress, zlib),
+    (
+      "bzip2.j",
+      compress | CreateFileFlags::MPQ_FILE_SECTOR_CRC,
+      bzip2,
+    ),
+    (
+      "single.j",
+      compress | CreateFileFlags::MPQ_FILE_SINGLE_UNIT,
+      zlib,
+    ),
+    ("scripts\\encrypted.j", compress | encrypted, zlib),
+    ("encrypted_stored.j", encrypted, zlib),
+    ("empty.txt", compress | encrypted, zlib),
+  ];
+
+  for (version, archive_path) in [
+    (
+      CreateArchiveFlags::MPQ_CREATE_ARCHIVE_V1,
+      "../../samples/test_write_archive_v1.mpq",
+    ),
+    (
+      CreateArchiveFlags::MPQ_CREATE_ARCHIVE_V2,
+      "../../samples/test_write_archive_v2.mpq",
+    ),
+  ] {
+    let result = std::panic::catch_unwind(|| {
+      let flags = version
+        | CreateArchiveFlags::MPQ_CREATE_LISTFILE
+        | CreateArchiveFlags::MPQ_CREATE_ATTRIBUTES;
+      let mut archive = Archive::create(archive_path, flags, 16).unwrap();
+      for (name, flags, compression) in files {
+        let data = if name == "empty.txt" {
+          vec![]
+        } else {
+          war3map_j.clone()
+        };
+        archive
+          .create_file(CreateFileOptions {
+            path: MpqPath::new(name),
+            data: &data,
+            flags,
+            mtime: 0x01D0_0000_0000_0000,
+            compression,
+          })
+          .unwrap();
+      }
+      assert!(matches!(
+        archive.create_file(CreateFileOptions {
+          path: MpqPath::new("zlib.j"),
+          data: &vec![],
+          flags: compress,
+          mtime: 0,
+          compression: zlib,
+        }),
+        Err(StormError::AlreadyExists)
+      ));
+      archive.close().unwrap();
+
+      let archive =
+        Archive::open_read_only(archive_path, OpenArchiveFlags::MPQ_OPEN_CHECK_SECTOR_CRC).unwrap();
+      let info = archive.info().unwrap();
+      assert_eq!(info.format_version, (version.bits() >> 24) as u16);
+      assert_eq!(info.file_count, files.len() as u32 + 2);
+      for (name, _, _) in files {
+        let expected = if name == "empty.txt" {
+          vec![]
+        } else {
+          war3map_j.clone()
+        };
+        assert_eq!(
+          archive.open_file(name).unwrap().read_all().unwrap(),
+          expected
+        );
+      }
+      let listfile = archive.open_file("(listfile)").unwrap().read_all().unwrap();
+      assert_eq!(
+        listfile,
+        b"bzip2.j\r\nempty.txt\r\nencrypted_stored.j\r\nscripts\\encrypted.j\r\nsingle.j\r\nstored.j\r\nzlib.j\r\n"
+      );
+      let found = archive.search(Some("zlib.j")).unwrap().next().unwrap();
+      assert_eq!(found.dwFileTimeHi, 0x01D0_0000);
+      {
+        let state = archive.inner.lock();
+        let attributes = state.attributes.as_ref().unwrap();
+        let entry = state.tables.find(b"zlib.j").unwrap();
+        assert_eq!(
+          attributes.get(entry.block_index),
+          attributes::FileAttributes::new(&war3map_j, 0x01D0_0000_0000_0000)
+        );
+      }
+      archive.close().unwrap();
+
+      let mut archive = Archive::open(archive_path, OpenArchiveFlags::empty()).unwrap();
+      assert!(archive.remove_file("stored.j").unwrap());
+      assert!(!archive.remove_file("stored.j").unwrap());
+      archive
+        .rename_file("scripts\\encrypted.j", "renamed.j")
+        .unwrap();
+      archive.compact().unwrap();
+      archive.set_max_file_count(64).unwrap();
+      archive.close().unwrap();
+
+      let archive =
+        Archive::open_read_only(archive_path, OpenArchiveFlags::MPQ_OPEN_CHECK_SECTOR_CRC).unwrap();
+      assert_eq!(archive.info().unwrap().hash_table_size, 64);
+      assert!(!archive.has_file("stored.j").unwrap());
+      assert!(!archive.has_file("scripts\\encrypted.j").unwrap());
+      for name in ["renamed.j", "encrypted_stored.j", "bzip2.j"] {
+        assert_eq!(
+          archive.open_file(name).unwrap().read_all().unwrap(),
+          war3map_j
+        );
+      }
+      assert_eq!(
+        std::fs::metadata(archive_path).unwrap().len(),
+        archive.info().unwrap().archive_size
+      );
+      archive.close().unwrap();
+
+      #[cfg(all(unix, any(feature = "bundled", feature = "system")))]
+      verify_with_stormlib(archive_path);
+    });
+
+    std::fs::remove_file(archive_path).unwrap();
+    result.unwrap();
+  }
+}
+
+/// Opens an archive written by the native backend with StormLib and verifies every file
+#[cfg(all(test, unix, any(feature = "bundled", feature = "system")))]
+fn verify_with_stormlib(archive_path: &str) {
+  use std::ffi::CString;
+
+  let path = CString::new(archive_path).unwrap();
+  let mut handle: HANDLE = std::ptr::null_mut();
+  unsafe {
+    assert!(SFileOpenArchive(
+      path.as_ptr(),
+      0,
+      MPQ_OPEN_CHECK_SECTOR_CRC,
+      &mut handle
+    ));
+    for name in ["renamed.j", "encrypted_stored.j", "bzip2.j", "(listfile)"] {
+      let name = CString::new(name).unwrap();
+      let result = SFileVerifyFile(handle, name.as_ptr(), SFILE_VERIFY_ALL);
+      assert_eq!(result & VERIFY_FILE_ERROR_MASK, 0, "{:?}", name);
+    }
+    assert!(SFileCloseArchive(handle));
+  }
+}
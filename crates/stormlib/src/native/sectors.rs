@@ -0,0 +1,342 @@
+//! Encoding of file data
+//!
+//! Files are stored as a single unit or split into sectors. Compressed files start with a
+//! table of sector offsets, followed by the sectors and the optional sector checksums.
+
+use stormlib_sys::*;
+
+use super::compression;
+use super::tables::{u32_at, BlockEntry};
+use crate::crypto;
+use crate::error::*;
+
+/// Stored layout of a file
+struct Layout {
+  single_unit: bool,
+  compressed: bool,
+  sector_count: usize,
+  sector_size: usize,
+  /// Number of entries in the sector offset table
+  table_len: usize,
+}
+
+impl Layout {
+  fn new(block: &BlockEntry, sector_size: u32) -> Self {
+    let sector_size = sector_size as usize;
+    let sector_count = (block.size as usize).div_ceil(sector_size);
+    let single_unit = block.flags & MPQ_FILE_SINGLE_UNIT != 0 || block.size == 0;
+    let compressed = block.flags & MPQ_FILE_COMPRESS_MASK != 0;
+    Layout {
+      single_unit,
+      compressed,
+      sector_count,
+      sector_size,
+      table_len: sector_count + 1 + (block.flags & MPQ_FILE_SECTOR_CRC != 0) as usize,
+    }
+  }
+
+  fn has_offset_table(&self) -> bool {
+    !self.single_unit && self.compressed
+  }
+
+  /// Offsets of the sectors in the stored data, with the end of the checksums last
+  fn offsets(&self, raw: &[u8], key: Option<u32>) -> Result<Vec<usize>> {
+    if !self.has_offset_table() {
+      return Ok(
+        (0..=self.sector_count)
+          .map(|i| (i * self.sector_size).min(raw.len()))
+          .collect(),
+      );
+    }
+    let mut table = raw
+      .get(..self.table_len * 4)
+      .ok_or(StormError::FileCorrupt)?
+      .to_vec();
+    if let Some(key) = key {
+      crypto::decrypt_block(&mut table, key.wrapping_sub(1));
+    }
+    let offsets: Vec<usize> = table
+      .chunks_exact(4)
+      .map(|entry| u32_at(entry, 0) as usize)
+      .collect();
+    if offsets.windows(2).any(|w| w[0] > w[1]) || offsets.last().is_some_and(|&end| end > raw.len())
+    {
+      return Err(StormError::FileCorrupt);
+    }
+    Ok(offsets)
+  }
+}
+
+/// Size of the stored data of a file
+pub fn stored_size(block: &BlockEntry) -> u64 {
+  if block.flags & MPQ_FILE_COMPRESS_MASK != 0 {
+    block.compressed_size
+  } else {
+    block.size
+  }
+}
+
+/// Returns the key of an encrypted file. Without a name the key is recovered from the sector
+/// offset table, which only works for compressed files.
+pub fn file_key(
+  block: &BlockEntry,
+  name: Option<&[u8]>,
+  raw: &[u8],
+  sector_size: u32,
+) -> Option<u32> {
+  if block.flags & MPQ_FILE_ENCRYPTED == 0 {
+    return None;
+  }
+  if let Some(name) = name {
+    return Some(crypto::file_key(
+      name,
+      block.offset,
+      block.size as u32,
+      block.flags & MPQ_FILE_FIX_KEY != 0,
+    ));
+  }
+  let layout = Layout::new(block, sector_size);
+  if !layout.has_offset_table() || raw.len() < layout.table_len * 4 {
+    return None;
+  }
+  crypto::detect_file_key(
+    &raw[..layout.table_len * 4],
+    (layout.table_len * 4) as u32,
+    sector_size,
+  )
+}
+
+/// Adjusts the key of a `MPQ_FILE_FIX_KEY` file to a new position
+pub fn move_key(key: u32, block: &BlockEntry, offset: u64) -> u32 {
+  let base = (key ^ block.size as u32).wrapping_sub(block.offset as u32);
+  base.wrapping_add(offset as u32) ^ block.size as u32
+}
+
+pub fn unknown_key() -> StormError {
+  ErrorCode(ERROR_UNKNOWN_FILE_KEY).into()
+}
+
+/// Decrypts and decompresses the stored data of a file
+pub fn decode(
+  raw: &[u8],
+  block: &BlockEntry,
+  sector_size: u32,
+  key: Option<u32>,
+  check_crc: bool,
+) -> Result<Vec<u8>> {
+  let encrypted = block.flags & MPQ_FILE_ENCRYPTED != 0;
+  if encrypted && key.is_none() {
+    return Err(unknown_key());
+  }
+  let layout = Layout::new(block, sector_size);
+  let size = block.size as usize;
+
+  if layout.single_unit {
+    let mut data = raw.to_vec();
+    if let Some(key) = key {
+      crypto::decrypt_block(&mut data, key);
+    }
+    if layout.compressed && data.len() < size {
+      data = decompress_sector(&data, size, block.flags)?;
+    }
+    if data.len() < size {
+      return Err(StormError::FileCorrupt);
+    }
+    data.truncate(size);
+    return Ok(data);
+  }
+
+  let offsets = layout.offsets(raw, key)?;
+  let checksums = if check_crc && offsets.len() > layout.sector_count + 1 {
+    let data = &raw[offsets[layout.sector_count]..offsets[layout.sector_count + 1]];
+    if data.len() < layout.sector_count * 4 {
+      Some(compression::decompress(data, layout.sector_count * 4)?)
+    } else {
+      Some(data.to_vec())
+    }
+  } else {
+    None
+  };
+
+  let mut buf = Vec::with_capacity(size);
+  for i in 0..layout.sector_count {
+    let expected = (size - i * layout.sector_size).min(layout.sector_size);
+    let mut sector = raw[offsets[i]..offsets[i + 1]].to_vec();
+    if let Some(key) = key {
+      crypto::decrypt_block(&mut sector, key.wrapping_add(i as u32));
+    }
+    if let Some(checksums) = &checksums {
+      let checksum = u32_at(checksums, i * 4);
+      if checksum != 0 && checksum != u32::MAX && adler32(&sector) != checksum {
+        return Err(StormError::FileCorrupt);
+      }
+    }
+    if layout.compressed && sector.len() < expected {
+      sector = decompress_sector(&sector, expected, block.flags)?;
+    }
+    if sector.len() < expected {
+      return Err(StormError::FileCorrupt);
+    }
+    buf.extend_from_slice(&sector[..expected]);
+  }
+  Ok(buf)
+}
+
+fn decompress_sector(data: &[u8], size: usize, flags: u32) -> Result<Vec<u8>> {
+  if flags & MPQ_FILE_IMPLODE != 0 {
+    compression::explode(data, size)
+  } else {
+    compression::decompress(data, size)
+  }
+}
+
+/// Compresses and encrypts file data. `flags` must not contain `MPQ_FILE_IMPLODE`, and
+/// `MPQ_FILE_SECTOR_CRC` only for compressed files split into sectors.
+pub fn encode(
+  data: &[u8],
+  flags: u32,
+  compression: u32,
+  key: Option<u32>,
+  sector_size: u32,
+) -> Result<Vec<u8>> {
+  let block = BlockEntry {
+    size: data.len() as u64,
+    flags,
+    ..BlockEntry::default()
+  };
+  let layout = Layout::new(&block, sector_size);
+  let compress = |chunk: &[u8]| -> Result<Vec<u8>> {
+    if layout.compressed {
+      if let Some(compressed) = compression::compress(chunk, compression)? {
+        return Ok(compressed);
+      }
+    }
+    Ok(chunk.to_vec())
+  };
+
+  if layout.single_unit {
+    let mut buf = compress(data)?;
+    if let Some(key) = key {
+      crypto::encrypt_block(&mut buf, key);
+    }
+    return Ok(buf);
+  }
+
+  if !layout.compressed {
+    let mut buf = data.to_vec();
+    if let Some(key) = key {
+      for (i, sector) in buf.chunks_mut(layout.sector_size).enumerate() {
+        crypto::encrypt_block(sector, key.wrapping_add(i as u32));
+      }
+    }
+    return Ok(buf);
+  }
+
+  let mut buf = vec![0u8; layout.table_len * 4];
+  let mut offsets = vec![buf.len() as u32];
+  let mut checksums = vec![];
+  for (i, sector) in data.chunks(layout.sector_size).enumerate() {
+    let mut sector = compress(sector)?;
+    checksums.extend_from_slice(&adler32(&sector).to_le_bytes());
+    if let Some(key) = key {
+      crypto::encrypt_block(&mut sector, key.wrapping_add(i as u32));
+    }
+    buf.extend_from_slice(&sector);
+    offsets.push(buf.len() as u32);
+  }
+  if offsets.len() < layout.table_len {
+    // The checksums are compressed with zlib and never encrypted
+    let compressed = compression::compress(&checksums, MPQ_COMPRESSION_ZLIB)?;
+    buf.extend_from_slice(compressed.as_deref().unwrap_or(&checksums));
+    offsets.push(buf.len() as u32);
+  }
+
+  let mut table: Vec<u8> = offsets.iter().flat_map(|o| o.to_le_bytes()).collect();
+  if let Some(key) = key {
+    crypto::encrypt_block(&mut table, key.wrapping_sub(1));
+  }
+  buf[..table.len()].copy_from_slice(&table);
+  Ok(buf)
+}
+
+/// Re-encrypts the stored data of a file with a new key, without decompressing it
+pub fn reencrypt(
+  raw: &mut [u8],
+  block: &BlockEntry,
+  sector_size: u32,
+  old_key: u32,
+  new_key: u32,
+) -> Result<()> {
+  let layout = Layout::new(block, sector_size);
+  if layout.single_unit {
+    crypto::decrypt_block(raw, old_key);
+    crypto::encrypt_block(raw, new_key);
+    return Ok(());
+  }
+
+  let offsets = layout.offsets(raw, Some(old_key))?;
+  for i in 0..layout.sector_count {
+    let sector = &mut raw[offsets[i]..offsets[i + 1]];
+    crypto::decrypt_block(sector, old_key.wrapping_add(i as u32));
+    crypto::encrypt_block(sector, new_key.wrapping_add(i as u32));
+  }
+  if layout.has_offset_table() {
+    let table = &mut raw[..layout.table_len * 4];
+    crypto::decrypt_block(table, old_key.wrapping_sub(1));
+    crypto::encrypt_block(table, new_key.wrapping_sub(1));
+  }
+  Ok(())
+}
+
+/// Adler-32 with a zero seed, which is what StormLib stores as sector checksums
+fn adler32(data: &[u8]) -> u32 {
+  let (mut a, mut b) = (0u32, 0u32);
+  for chunk in data.chunks(5552) {
+    for &byte in chunk {
+      a += byte as u32;
+      b += a;
+    }
+    a %= 65521;
+    b %= 65521;
+  }
+  b << 16 | a
+}
+
+#[test]
+fn test_encode_round_trip() {
+  let data = std::fs::read("../../samples/war3map.j").unwrap();
+  let zlib = MPQ_COMPRESSION_ZLIB;
+  let bzip2 = MPQ_COMPRESSION_BZIP2;
+  let compress = MPQ_FILE_COMPRESS;
+  let encrypted = MPQ_FILE_ENCRYPTED | MPQ_FILE_FIX_KEY;
+  for (flags, compression) in [
+    (0, 0),
+    (compress, zlib),
+    (compress | MPQ_FILE_SECTOR_CRC, bzip2),
+    (compress | MPQ_FILE_SINGLE_UNIT, zlib),
+    (encrypted, 0),
+    (compress | encrypted | MPQ_FILE_SECTOR_CRC, zlib),
+    (compress | encrypted | MPQ_FILE_SINGLE_UNIT, bzip2),
+  ] {
+    let key = (flags & MPQ_FILE_ENCRYPTED != 0).then_some(0x1234_5678);
+    let raw = encode(&data, flags, compression, key, 0x1000).unwrap();
+    let mut block = BlockEntry {
+      offset: 0x20,
+      compressed_size: raw.len() as u64,
+      size: data.len() as u64,
+      flags,
+    };
+    assert_eq!(decode(&raw, &block, 0x1000, key, true).unwrap(), data);
+
+    if let Some(key) = key {
+      let mut moved = raw.clone();
+      let new_key = move_key(key, &block, 0x1000);
+      reencrypt(&mut moved, &block, 0x1000, key, new_key).unwrap();
+      block.offset = 0x1000;
+      assert_eq!(
+        decode(&moved, &block, 0x1000, Some(new_key), true).unwrap(),
+        data
+      );
+    }
+  }
+}
@@ -0,0 +1,952 @@
+//! MPQ header and file tables
+
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use stormlib_sys::{MPQ_KEY_BLOCK_TABLE, MPQ_KEY_HASH_TABLE};
+
+use super::compression;
+use crate::crypto;
+use crate::error::*;
+use crate::OpenArchiveFlags;
+
+const ID_MPQ: u32 = 0x1A51_504D;
+const ID_MPQ_USERDATA: u32 = 0x1B51_504D;
+const ID_HET: u32 = 0x1A54_4548;
+const ID_BET: u32 = 0x1A54_4542;
+const ID_W3X: u32 = 0x5733_4D48;
+
+const HEADER_SIZE_V1: usize = 0x20;
+const HEADER_SIZE_V2: usize = 0x2C;
+const HEADER_SIZE_V4: usize = 0xD0;
+const HASH_ENTRY_SIZE: usize = 16;
+const BLOCK_ENTRY_SIZE: usize = 16;
+
+const HASH_ENTRY_FREE: u32 = 0xFFFF_FFFF;
+const HASH_ENTRY_DELETED: u32 = 0xFFFF_FFFE;
+const HET_ENTRY_FREE: u8 = 0x00;
+const HET_ENTRY_DELETED: u8 = 0x80;
+
+/// Fields of the MPQ header, with positions made absolute where they are split
+#[derive(Debug, Clone, Default)]
+pub struct Header {
+  /// Offset of the header from the start of the file, all positions are relative to it
+  pub offset: u64,
+  pub format_version: u16,
+  pub archive_size: u64,
+  pub sector_size: u32,
+  pub hash_table_pos: u64,
+  pub hash_table_size: u32,
+  pub block_table_pos: u64,
+  pub block_table_size: u32,
+  pub hi_block_table_pos: u64,
+  pub het_table_pos: u64,
+  pub bet_table_pos: u64,
+  /// Stored sizes of the tables, only known since format 4
+  pub hash_table_stored_size: Option<u64>,
+  pub block_table_stored_size: Option<u64>,
+  pub het_table_stored_size: Option<u64>,
+  pub bet_table_stored_size: Option<u64>,
+  /// Warcraft III map, which always uses format 1 and tolerates malformed headers
+  pub war3_map: bool,
+}
+
+/// Entry of the block table, or of the BET table
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BlockEntry {
+  /// Position of the file data, relative to the header
+  pub offset: u64,
+  pub compressed_size: u64,
+  pub size: u64,
+  pub flags: u32,
+}
+
+/// Entry of the hash table, or of the HET table
+#[derive(Debug, Clone, Copy)]
+pub struct HashEntry {
+  pub hash_index: u32,
+  pub block_index: u32,
+  pub locale: u16,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct HashTableEntry {
+  name_a: u32,
+  name_b: u32,
+  locale: u16,
+  platform: u16,
+  block_index: u32,
+}
+
+impl HashTableEntry {
+  const FREE: HashTableEntry = HashTableEntry {
+    name_a: HASH_ENTRY_FREE,
+    name_b: HASH_ENTRY_FREE,
+    locale: 0xFFFF,
+    platform: 0xFFFF,
+    block_index: HASH_ENTRY_FREE,
+  };
+
+  fn is_used(&self) -> bool {
+    self.block_index != HASH_ENTRY_FREE && self.block_index != HASH_ENTRY_DELETED
+  }
+}
+
+#[derive(Debug)]
+struct HetTable {
+  hash_bits: u32,
+  name_hashes: Vec<u8>,
+  index_size_total: u32,
+  index_size: u32,
+  indexes: Vec<u8>,
+  /// `NameHash2` of every BET entry
+  bet_hashes: Vec<u64>,
+}
+
+/// Maps names to file entries
+#[derive(Debug)]
+enum Lookup {
+  Hash(Vec<HashTableEntry>),
+  Het(HetTable),
+}
+
+/// Loaded header and tables of an archive
+#[derive(Debug)]
+pub struct Tables {
+  pub header: Header,
+  pub blocks: Vec<BlockEntry>,
+  lookup: Lookup,
+}
+
+impl Tables {
+  pub fn load<R: Read + Seek>(reader: &mut R, flags: OpenArchiveFlags) -> Result<Self> {
+    let header = read_header(reader, flags)?;
+
+    // The classic tables are preferred when both are present, they are what older
+    // readers use and what StormLib keeps up to date
+    if header.hash_table_size != 0 && header.hash_table_pos != 0 {
+      let hashes = read_hash_table(reader, &header)?;
+      let blocks = read_block_table(reader, &header)?;
+      Ok(Tables {
+        header,
+        blocks,
+        lookup: Lookup::Hash(hashes),
+      })
+    } else if header.het_table_pos != 0 && header.bet_table_pos != 0 {
+      let (blocks, bet_hashes) = read_bet_table(reader, &header)?;
+      let het = read_het_table(reader, &header, bet_hashes)?;
+      Ok(Tables {
+        header,
+        blocks,
+        lookup: Lookup::Het(het),
+      })
+    } else {
+      Err(StormError::BadFormat)
+    }
+  }
+
+  /// Finds the entry of a file, preferring the neutral locale
+  pub fn find(&self, name: &[u8]) -> Option<HashEntry> {
+    match &self.lookup {
+      Lookup::Hash(hashes) => {
+        let mut found = None;
+        for entry in self.hash_matches(hashes, name) {
+          if entry.locale == 0 {
+            return Some(entry);
+          }
+          found.get_or_insert(entry);
+        }
+        found
+      }
+      Lookup::Het(het) => {
+        let total = het.name_hashes.len();
+        if total == 0 {
+          return None;
+        }
+        let (and_mask, or_mask) = het_masks(het.hash_bits);
+        let hash = (jenkins_hash(name) & and_mask) | or_mask;
+        let name_hash1 = (hash >> (het.hash_bits - 8)) as u8;
+        let name_hash2 = hash & (and_mask >> 8);
+        let start = (hash % total as u64) as usize;
+        for i in (start..total).chain(0..start) {
+          match het.name_hashes[i] {
+            HET_ENTRY_FREE => break,
+            h if h == name_hash1 => {
+              let index = het.bet_index(i);
+              if het.bet_hashes.get(index as usize) == Some(&name_hash2)
+                && self.is_valid_block(index)
+              {
+                return Some(HashEntry {
+                  hash_index: i as u32,
+                  block_index: index,
+                  locale: 0,
+                });
+              }
+            }
+            _ => {}
+          }
+        }
+        None
+      }
+    }
+  }
+
+  /// Finds the entry of a file in exactly `locale`
+  pub fn find_locale(&self, name: &[u8], locale: u16) -> Option<HashEntry> {
+    match &self.lookup {
+      Lookup::Hash(hashes) => self
+        .hash_matches(hashes, name)
+        .find(|entry| entry.locale == locale),
+      // The HET table has no locales
+      Lookup::Het(_) => self.find(name).filter(|entry| entry.locale == locale),
+    }
+  }
+
+  /// Entries of the hash table for `name`, in probe order
+  fn hash_matches<'a>(
+    &'a self,
+    hashes: &'a [HashTableEntry],
+    name: &[u8],
+  ) -> impl Iterator<Item = HashEntry> + 'a {
+    let name_a = crypto::hash_string(name, crypto::HashType::NameA);
+    let name_b = crypto::hash_string(name, crypto::HashType::NameB);
+    let start = match hashes.len() {
+      0 => 0,
+      len => crypto::hash_string(name, crypto::HashType::TableOffset) as usize % len,
+    };
+    (start..hashes.len())
+      .chain(0..start)
+      .map(move |i| (i, &hashes[i]))
+      .take_while(|(_, entry)| entry.block_index != HASH_ENTRY_FREE)
+      .filter(move |(_, entry)| {
+        entry.name_a == name_a && entry.name_b == name_b && self.is_valid_block(entry.block_index)
+      })
+      .map(|(i, entry)| HashEntry {
+        hash_index: i as u32,
+        block_index: entry.block_index,
+        locale: entry.locale,
+      })
+  }
+
+  /// All entries pointing to existing files, ordered by hash index
+  pub fn entries(&self) -> Vec<HashEntry> {
+    match &self.lookup {
+      Lookup::Hash(hashes) => hashes
+        .iter()
+        .enumerate()
+        .filter(|(_, entry)| self.is_valid_block(entry.block_index))
+        .map(|(i, entry)| HashEntry {
+          hash_index: i as u32,
+          block_index: entry.block_index,
+          locale: entry.locale,
+        })
+        .collect(),
+      Lookup::Het(het) => het
+        .name_hashes
+        .iter()
+        .enumerate()
+        .filter(|(_, &h)| h != HET_ENTRY_FREE && h != HET_ENTRY_DELETED)
+        .map(|(i, _)| HashEntry {
+          hash_index: i as u32,
+          block_index: het.bet_index(i),
+          locale: 0,
+        })
+        .filter(|entry| self.is_valid_block(entry.block_index))
+        .collect(),
+    }
+  }
+
+  fn is_valid_block(&self, index: u32) -> bool {
+    index != HASH_ENTRY_DELETED
+      && self
+        .blocks
+        .get(index as usize)
+        .is_some_and(|block| block.flags & stormlib_sys::MPQ_FILE_EXISTS != 0)
+  }
+
+  /// Number of entries in the hash table, or in the HET table
+  pub fn hash_table_size(&self) -> u32 {
+    match &self.lookup {
+      Lookup::Hash(hashes) => hashes.len() as u32,
+      Lookup::Het(het) => het.name_hashes.len() as u32,
+    }
+  }
+
+  /// Empty tables of a new archive
+  pub fn create(header: Header, hash_table_size: u32) -> Self {
+    Tables {
+      header,
+      blocks: vec![],
+      lookup: Lookup::Hash(vec![HashTableEntry::FREE; hash_table_size as usize]),
+    }
+  }
+
+  /// Fails unless the archive can be modified, which needs the hash and block tables of a
+  /// format 1 or 2 archive
+  pub fn writable(&self) -> Result<()> {
+    match self.lookup {
+      Lookup::Hash(_) if self.header.format_version <= 1 => Ok(()),
+      _ => Err(StormError::NotSupported),
+    }
+  }
+
+  fn hashes_mut(&mut self) -> Result<&mut Vec<HashTableEntry>> {
+    match &mut self.lookup {
+      Lookup::Hash(hashes) => Ok(hashes),
+      Lookup::Het(_) => Err(StormError::NotSupported),
+    }
+  }
+
+  /// Index of the first free or deleted hash entry on the probe path of `name`
+  pub fn free_slot(&self, name: &[u8]) -> Result<u32> {
+    let hashes = match &self.lookup {
+      Lookup::Hash(hashes) if !hashes.is_empty() => hashes,
+      Lookup::Hash(_) => return Err(StormError::DiskFull),
+      Lookup::Het(_) => return Err(StormError::NotSupported),
+    };
+    free_slot(hashes, name).ok_or(StormError::DiskFull)
+  }
+
+  /// Points the hash entry at `hash_index` to a new file in the neutral locale
+  pub fn set_entry(&mut self, hash_index: u32, name: &[u8], block_index: u32) -> Result<()> {
+    self.hashes_mut()?[hash_index as usize] = HashTableEntry {
+      name_a: crypto::hash_string(name, crypto::HashType::NameA),
+      name_b: crypto::hash_string(name, crypto::HashType::NameB),
+      locale: 0,
+      platform: 0,
+      block_index,
+    };
+    Ok(())
+  }
+
+  /// Points an existing hash entry to another block
+  pub fn set_block(&mut self, hash_index: u32, block_index: u32) -> Result<()> {
+    self.hashes_mut()?[hash_index as usize].block_index = block_index;
+    Ok(())
+  }
+
+  /// Marks a hash entry as deleted, keeping the probe chains through it intact
+  pub fn remove(&mut self, hash_index: u32) -> Result<()> {
+    self.hashes_mut()?[hash_index as usize] = HashTableEntry {
+      block_index: HASH_ENTRY_DELETED,
+      ..HashTableEntry::FREE
+    };
+    Ok(())
+  }
+
+  /// Moves a hash entry to the probe path of `name`, keeping its locale and platform.
+  /// Returns the new hash index.
+  pub fn rename(&mut self, hash_index: u32, name: &[u8]) -> Result<u32> {
+    let hashes = self.hashes_mut()?;
+    let entry = hashes[hash_index as usize];
+    hashes[hash_index as usize] = HashTableEntry {
+      block_index: HASH_ENTRY_DELETED,
+      ..HashTableEntry::FREE
+    };
+    let Some(new_index) = free_slot(hashes, name) else {
+      hashes[hash_index as usize] = entry;
+      return Err(StormError::DiskFull);
+    };
+    hashes[new_index as usize] = HashTableEntry {
+      name_a: crypto::hash_string(name, crypto::HashType::NameA),
+      name_b: crypto::hash_string(name, crypto::HashType::NameB),
+      ..entry
+    };
+    Ok(new_index)
+  }
+
+  /// Returns the index of an unused block entry, appending one if there is none. Blocks
+  /// of removed files are reused once no hash entry points to them.
+  pub fn allocate_block(&mut self) -> u32 {
+    let mut used = vec![false; self.blocks.len()];
+    if let Lookup::Hash(hashes) = &self.lookup {
+      for entry in hashes {
+        if let Some(used) = used.get_mut(entry.block_index as usize) {
+          *used = true;
+        }
+      }
+    }
+    match (0..self.blocks.len()).find(|&i| !used[i] && self.blocks[i].flags == 0) {
+      Some(index) => index as u32,
+      None => {
+        self.blocks.push(BlockEntry::default());
+        (self.blocks.len() - 1) as u32
+      }
+    }
+  }
+
+  /// Replaces the block table, `map` gives the new index of every old block
+  pub fn remap_blocks(&mut self, blocks: Vec<BlockEntry>, map: &[Option<u32>]) -> Result<()> {
+    for entry in self
+      .hashes_mut()?
+      .iter_mut()
+      .filter(|entry| entry.is_used())
+    {
+      match map.get(entry.block_index as usize).copied().flatten() {
+        Some(index) => entry.block_index = index,
+        None => {
+          *entry = HashTableEntry {
+            block_index: HASH_ENTRY_DELETED,
+            ..HashTableEntry::FREE
+          }
+        }
+      }
+    }
+    self.blocks = blocks;
+    Ok(())
+  }
+
+  /// Rebuilds the hash table with a new size, which needs the name of every file. Returns
+  /// the names by their new hash index.
+  pub fn resize(
+    &mut self,
+    size: u32,
+    names: &HashMap<u32, Vec<u8>>,
+  ) -> Result<HashMap<u32, Vec<u8>>> {
+    let valid: Vec<bool> = match &self.lookup {
+      Lookup::Hash(hashes) => hashes
+        .iter()
+        .map(|entry| self.is_valid_block(entry.block_index))
+        .collect(),
+      Lookup::Het(_) => return Err(StormError::NotSupported),
+    };
+    let hashes = self.hashes_mut()?;
+    let mut resized = vec![HashTableEntry::FREE; size as usize];
+    let mut resized_names = HashMap::new();
+    for (i, entry) in hashes.iter().enumerate() {
+      if !valid[i] {
+        continue;
+      }
+      let name = names.get(&(i as u32)).ok_or(StormError::CanNotComplete)?;
+      let index = free_slot(&resized, name).ok_or(StormError::DiskFull)?;
+      resized[index as usize] = *entry;
+      resized_names.insert(index, name.clone());
+    }
+    *hashes = resized;
+    Ok(resized_names)
+  }
+
+  /// Writes the hash, block and hi-block tables at `pos` followed by the header, and
+  /// returns the end of the archive. Positions are relative to the header.
+  pub fn save<W: Write + Seek>(&mut self, writer: &mut W, pos: u64) -> Result<u64> {
+    let Lookup::Hash(hashes) = &self.lookup else {
+      return Err(StormError::NotSupported);
+    };
+    let mut hash_table: Vec<u8> = hashes
+      .iter()
+      .flat_map(|entry| {
+        let mut buf = [0u8; HASH_ENTRY_SIZE];
+        buf[0..4].copy_from_slice(&entry.name_a.to_le_bytes());
+        buf[4..8].copy_from_slice(&entry.name_b.to_le_bytes());
+        buf[8..10].copy_from_slice(&entry.locale.to_le_bytes());
+        buf[10..12].copy_from_slice(&entry.platform.to_le_bytes());
+        buf[12..16].copy_from_slice(&entry.block_index.to_le_bytes());
+        buf
+      })
+      .collect();
+    crypto::encrypt_block(&mut hash_table, MPQ_KEY_HASH_TABLE);
+
+    let mut block_table: Vec<u8> = self
+      .blocks
+      .iter()
+      .flat_map(|block| {
+        let mut buf = [0u8; BLOCK_ENTRY_SIZE];
+        buf[0..4].copy_from_slice(&(block.offset as u32).to_le_bytes());
+        buf[4..8].copy_from_slice(&(block.compressed_size as u32).to_le_bytes());
+        buf[8..12].copy_from_slice(&(block.size as u32).to_le_bytes());
+        buf[12..16].copy_from_slice(&block.flags.to_le_bytes());
+        buf
+      })
+      .collect();
+    crypto::encrypt_block(&mut block_table, MPQ_KEY_BLOCK_TABLE);
+
+    // Positions above 4 GiB need the hi-block table of format 2
+    let hi_block_table: Vec<u8> = if self.blocks.iter().any(|b| b.offset > u32::MAX as u64) {
+      if self.header.format_version == 0 {
+        return Err(StormError::DiskFull);
+      }
+      self
+        .blocks
+        .iter()
+        .flat_map(|block| ((block.offset >> 32) as u16).to_le_bytes())
+        .collect()
+    } else {
+      vec![]
+    };
+
+    let header = &mut self.header;
+    header.hash_table_pos = pos;
+    header.hash_table_size = hashes.len() as u32;
+    header.block_table_pos = pos + hash_table.len() as u64;
+    header.block_table_size = self.blocks.len() as u32;
+    let mut end = header.block_table_pos + block_table.len() as u64;
+    header.hi_block_table_pos = if hi_block_table.is_empty() { 0 } else { end };
+    end += hi_block_table.len() as u64;
+    if header.format_version == 0 && end > u32::MAX as u64 {
+      return Err(StormError::DiskFull);
+    }
+    header.archive_size = end;
+
+    let mut data = hash_table;
+    data.extend_from_slice(&block_table);
+    data.extend_from_slice(&hi_block_table);
+    write_at(writer, header.offset + pos, &data)?;
+    write_at(writer, header.offset, &header.to_bytes())?;
+    Ok(end)
+  }
+}
+
+impl Header {
+  /// Size of the header as written, format 1 or 2
+  pub fn size(&self) -> u64 {
+    if self.format_version == 0 {
+      HEADER_SIZE_V1 as u64
+    } else {
+      HEADER_SIZE_V2 as u64
+    }
+  }
+
+  fn to_bytes(&self) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(HEADER_SIZE_V2);
+    buf.extend_from_slice(&ID_MPQ.to_le_bytes());
+    buf.extend_from_slice(&(self.size() as u32).to_le_bytes());
+    buf.extend_from_slice(&(self.archive_size as u32).to_le_bytes());
+    buf.extend_from_slice(&self.format_version.to_le_bytes());
+    let sector_shift = (self.sector_size / 0x200).trailing_zeros() as u16;
+    buf.extend_from_slice(&sector_shift.to_le_bytes());
+    buf.extend_from_slice(&(self.hash_table_pos as u32).to_le_bytes());
+    buf.extend_from_slice(&(self.block_table_pos as u32).to_le_bytes());
+    buf.extend_from_slice(&self.hash_table_size.to_le_bytes());
+    buf.extend_from_slice(&self.block_table_size.to_le_bytes());
+    if self.format_version >= 1 {
+      buf.extend_from_slice(&self.hi_block_table_pos.to_le_bytes());
+      buf.extend_from_slice(&((self.hash_table_pos >> 32) as u16).to_le_bytes());
+      buf.extend_from_slice(&((self.block_table_pos >> 32) as u16).to_le_bytes());
+    }
+    buf
+  }
+}
+
+fn free_slot(hashes: &[HashTableEntry], name: &[u8]) -> Option<u32> {
+  if hashes.is_empty() {
+    return None;
+  }
+  let start = crypto::hash_string(name, crypto::HashType::TableOffset) as usize % hashes.len();
+  (start..hashes.len())
+    .chain(0..start)
+    .find(|&i| !hashes[i].is_used())
+    .map(|i| i as u32)
+}
+
+/// Writes `data` at `pos`
+pub fn write_at<W: Write + Seek>(writer: &mut W, pos: u64, data: &[u8]) -> Result<()> {
+  writer
+    .seek(SeekFrom::Start(pos))
+    .and_then(|_| writer.write_all(data))
+    .map_err(|_| StormError::CanNotComplete)
+}
+
+impl HetTable {
+  fn bet_index(&self, i: usize) -> u32 {
+    read_bits(
+      &self.indexes,
+      i as u64 * self.index_size_total as u64,
+      self.index_size,
+    ) as u32
+  }
+}
+
+fn het_masks(bits: u32) -> (u64, u64) {
+  let and_mask = if bits >= 64 {
+    u64::MAX
+  } else {
+    (1u64 << bits) - 1
+  };
+  (and_mask, 1u64 << (bits - 1))
+}
+
+/// Reads up to `len` bytes at `pos`. Tables of malformed archives may be cut at the end of
+/// the file, the missing part is filled with zeros.
+pub fn read_at<R: Read + Seek>(reader: &mut R, pos: u64, len: usize) -> Result<Vec<u8>> {
+  reader
+    .seek(SeekFrom::Start(pos))
+    .map_err(|_| StormError::FileCorrupt)?;
+  let mut buf = Vec::with_capacity(len);
+  reader
+    .take(len as u64)
+    .read_to_end(&mut buf)
+    .map_err(|_| StormError::FileCorrupt)?;
+  buf.resize(len, 0);
+  Ok(buf)
+}
+
+fn u16_at(data: &[u8], pos: usize) -> u16 {
+  u16::from_le_bytes([data[pos], data[pos + 1]])
+}
+
+pub fn u32_at(data: &[u8], pos: usize) -> u32 {
+  u32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]])
+}
+
+fn u64_at(data: &[u8], pos: usize) -> u64 {
+  u32_at(data, pos) as u64 | (u32_at(data, pos + 4) as u64) << 32
+}
+
+/// Reads a little endian bit field of up to 64 bits
+fn read_bits(data: &[u8], pos: u64, count: u32) -> u64 {
+  let mut value = 0u64;
+  for i in 0..count as u64 {
+    let bit = pos + i;
+    let byte = data.get((bit / 8) as usize).copied().unwrap_or(0);
+    value |= (((byte >> (bit % 8)) & 1) as u64) << i;
+  }
+  value
+}
+
+/// Searches for the MPQ header at 512 byte boundaries, following the user data header
+fn read_header<R: Read + Seek>(reader: &mut R, flags: OpenArchiveFlags) -> Result<Header> {
+  let file_size = reader
+    .seek(SeekFrom::End(0))
+    .map_err(|_| StormError::FileCorrupt)?;
+  let war3_map = file_size >= 4 && u32_at(&read_at(reader, 0, 4)?, 0) == ID_W3X;
+
+  let mut offset = 0u64;
+  while offset + HEADER_SIZE_V1 as u64 <= file_size {
+    let data = read_at(reader, offset, HEADER_SIZE_V4)?;
+    match u32_at(&data, 0) {
+      ID_MPQ_USERDATA if !war3_map => {
+        let header_offset = offset + u32_at(&data, 8) as u64;
+        let data = read_at(reader, header_offset, HEADER_SIZE_V4)?;
+        if u32_at(&data, 0) == ID_MPQ {
+          return Ok(parse_header(&data, header_offset, flags, war3_map));
+        }
+      }
+      ID_MPQ => return Ok(parse_header(&data, offset, flags, war3_map)),
+      _ => {}
+    }
+    if flags.contains(OpenArchiveFlags::MPQ_OPEN_NO_HEADER_SEARCH) {
+      break;
+    }
+    offset += 0x200;
+  }
+  Err(StormError::BadFormat)
+}
+
+fn parse_header(data: &[u8], offset: u64, flags: OpenArchiveFlags, war3_map: bool) -> Header {
+  let header_size = u32_at(data, 4) as usize;
+  let format_version = if war3_map || flags.contains(OpenArchiveFlags::MPQ_OPEN_FORCE_MPQ_V1) {
+    0
+  } else {
+    u16_at(data, 0x0C).min(3)
+  };
+  let has = |version: u16, size: usize| format_version >= version && header_size >= size;
+
+  let mut header = Header {
+    offset,
+    format_version,
+    archive_size: u32_at(data, 0x08) as u64,
+    sector_size: 0x200 << (u16_at(data, 0x0E) & 0x1F),
+    hash_table_pos: u32_at(data, 0x10) as u64,
+    block_table_pos: u32_at(data, 0x14) as u64,
+    hash_table_size: u32_at(data, 0x18),
+    block_table_size: u32_at(data, 0x1C),
+    war3_map,
+    ..Header::default()
+  };
+  if war3_map {
+    // Protectors put garbage in the high bits of the table sizes
+    header.hash_table_size &= 0x0FFF_FFFF;
+    header.block_table_size &= 0x0FFF_FFFF;
+  }
+  if has(1, 0x2C) {
+    header.hi_block_table_pos = u64_at(data, 0x20);
+    header.hash_table_pos |= (u16_at(data, 0x28) as u64) << 32;
+    header.block_table_pos |= (u16_at(data, 0x2A) as u64) << 32;
+  }
+  if has(2, 0x44) {
+    header.archive_size = u64_at(data, 0x2C);
+    header.bet_table_pos = u64_at(data, 0x34);
+    header.het_table_pos = u64_at(data, 0x3C);
+  }
+  if has(3, 0x6C) {
+    header.hash_table_stored_size = Some(u64_at(data, 0x44));
+    header.block_table_stored_size = Some(u64_at(data, 0x4C));
+    header.het_table_stored_size = Some(u64_at(data, 0x5C));
+    header.bet_table_stored_size = Some(u64_at(data, 0x64));
+  }
+  header
+}
+
+/// Reads an encrypted table, which is compressed when its stored size is smaller
+fn read_table<R: Read + Seek>(
+  reader: &mut R,
+  pos: u64,
+  size: usize,
+  stored_size: Option<u64>,
+  key: u32,
+) -> Result<Vec<u8>> {
+  let stored_size = stored_size
+    .map(|s| s as usize)
+    .filter(|&s| s != 0)
+    .unwrap_or(size)
+    .min(size);
+  let mut data = read_at(reader, pos, stored_size)?;
+  crypto::decrypt_block(&mut data, key);
+  if stored_size < size {
+    data = compression::decompress(&data, size)?;
+  }
+  Ok(data)
+}
+
+fn read_hash_table<R: Read + Seek>(reader: &mut R, header: &Header) -> Result<Vec<HashTableEntry>> {
+  let count = header.hash_table_size as usize;
+  let data = read_table(
+    reader,
+    header.offset + header.hash_table_pos,
+    count * HASH_ENTRY_SIZE,
+    header.hash_table_stored_size,
+    MPQ_KEY_HASH_TABLE,
+  )?;
+  Ok(
+    data
+      .chunks_exact(HASH_ENTRY_SIZE)
+      .map(|entry| HashTableEntry {
+        name_a: u32_at(entry, 0),
+        name_b: u32_at(entry, 4),
+        locale: u16_at(entry, 8),
+        platform: u16_at(entry, 10),
+        block_index: u32_at(entry, 12),
+      })
+      .collect(),
+  )
+}
+
+fn read_block_table<R: Read + Seek>(reader: &mut R, header: &Header) -> Result<Vec<BlockEntry>> {
+  let count = header.block_table_size as usize;
+  let data = read_table(
+    reader,
+    header.offset + header.block_table_pos,
+    count * BLOCK_ENTRY_SIZE,
+    header.block_table_stored_size,
+    MPQ_KEY_BLOCK_TABLE,
+  )?;
+  let hi = if header.hi_block_table_pos != 0 {
+    read_at(reader, header.offset + header.hi_block_table_pos, count * 2)?
+  } else {
+    vec![0; count * 2]
+  };
+  Ok(
+    data
+      .chunks_exact(BLOCK_ENTRY_SIZE)
+      .enumerate()
+      .map(|(i, entry)| BlockEntry {
+        offset: u32_at(entry, 0) as u64 | (u16_at(&hi, i * 2) as u64) << 32,
+        compressed_size: u32_at(entry, 4) as u64,
+        size: u32_at(entry, 8) as u64,
+        flags: u32_at(entry, 12),
+      })
+      .collect(),
+  )
+}
+
+/// Reads a HET or BET table, which starts with a plain signature, version and data size
+fn read_ext_table<R: Read + Seek>(
+  reader: &mut R,
+  pos: u64,
+  stored_size: Option<u64>,
+  signature: u32,
+  key: u32,
+) -> Result<Vec<u8>> {
+  let ext = read_at(reader, pos, 12)?;
+  if u32_at(&ext, 0) != signature || u32_at(&ext, 4) != 1 {
+    return Err(StormError::FileCorrupt);
+  }
+  let size = u32_at(&ext, 8) as usize;
+  let stored_size = stored_size.map(|s| s.saturating_sub(12));
+  read_table(reader, pos + 12, size, stored_size, key)
+}
+
+fn read_het_table<R: Read + Seek>(
+  reader: &mut R,
+  header: &Header,
+  bet_hashes: Vec<u64>,
+) -> Result<HetTable> {
+  let data = read_ext_table(
+    reader,
+    header.offset + header.het_table_pos,
+    header.het_table_stored_size,
+    ID_HET,
+    MPQ_KEY_HASH_TABLE,
+  )?;
+  if data.len() < 32 {
+    return Err(StormError::FileCorrupt);
+  }
+  let total_count = u32_at(&data, 8) as usize;
+  let hash_bits = u32_at(&data, 12);
+  let index_size_total = u32_at(&data, 16);
+  let index_size = u32_at(&data, 24);
+  let index_table_size = u32_at(&data, 28) as usize;
+  if !(8..=64).contains(&hash_bits)
+    || index_size > 32
+    || data.len() < 32 + total_count + index_table_size
+  {
+    return Err(StormError::FileCorrupt);
+  }
+  Ok(HetTable {
+    hash_bits,
+    name_hashes: data[32..32 + total_count].to_vec(),
+    index_size_total,
+    index_size,
+    indexes: data[32 + total_count..32 + total_count + index_table_size].to_vec(),
+    bet_hashes,
+  })
+}
+
+fn read_bet_table<R: Read + Seek>(
+  reader: &mut R,
+  header: &Header,
+) -> Result<(Vec<BlockEntry>, Vec<u64>)> {
+  let data = read_ext_table(
+    reader,
+    header.offset + header.bet_table_pos,
+    header.bet_table_stored_size,
+    ID_BET,
+    MPQ_KEY_BLOCK_TABLE,
+  )?;
+  if data.len() < 76 {
+    return Err(StormError::FileCorrupt);
+  }
+  let field = |i: usize| u32_at(&data, i * 4);
+  let entry_count = field(1) as usize;
+  let entry_size = field(3) as u64;
+  let (pos_index, size_index, csize_index, flag_index) = (field(4), field(5), field(6), field(7));
+  let (pos_bits, size_bits, csize_bits, flag_bits) = (field(9), field(10), field(11), field(12));
+  let hash_total_bits = field(14) as u64;
+  let hash_bits = field(16);
+  let hash_array_size = field(17) as usize;
+  let flag_count = field(18) as usize;
+
+  let flags_end = 76 + flag_count * 4;
+  let table_end = flags_end + (entry_size * entry_count as u64).div_ceil(8) as usize;
+  if data.len() < table_end + hash_array_size
+    || [pos_bits, size_bits, csize_bits, flag_bits, hash_bits]
+      .iter()
+      .any(|&bits| bits > 64)
+  {
+    return Err(StormError::FileCorrupt);
+  }
+  let flags: Vec<u32> = data[76..flags_end]
+    .chunks_exact(4)
+    .map(|f| u32_at(f, 0))
+    .collect();
+  let table = &data[flags_end..table_end];
+  let hashes = &data[table_end..table_end + hash_array_size];
+
+  let blocks = (0..entry_count as u64)
+    .map(|i| {
+      let base = i * entry_size;
+      let flag = read_bits(table, base + flag_index as u64, flag_bits) as usize;
+      BlockEntry {
+        offset: read_bits(table, base + pos_index as u64, pos_bits),
+        size: read_bits(table, base + size_index as u64, size_bits),
+        compressed_size: read_bits(table, base + csize_index as u64, csize_bits),
+        flags: flags.get(flag).copied().unwrap_or(0),
+      }
+    })
+    .collect();
+  let bet_hashes = (0..entry_count as u64)
+    .map(|i| read_bits(hashes, i * hash_total_bits, hash_bits))
+    .collect();
+  Ok((blocks, bet_hashes))
+}
+
+/// Bob Jenkins' `hashlittle2` of the normalized name, used by the HET and BET tables
+pub fn jenkins_hash(name: &[u8]) -> u64 {
+  let name: Vec<u8> = name
+    .iter()
+    .map(|&c| match c.to_ascii_lowercase() {
+      b'/' => b'\\',
+      c => c,
+    })
+    .collect();
+  let (c, b) = hashlittle2(&name, 2, 1);
+  (b as u64) << 32 | c as u64
+}
+
+fn hashlittle2(key: &[u8], pc: u32, pb: u32) -> (u32, u32) {
+  let init = 0xDEAD_BEEFu32
+    .wrapping_add(key.len() as u32)
+    .wrapping_add(pc);
+  let (mut a, mut b, mut c) = (init, init, init.wrapping_add(pb));
+  let word = |k: &[u8]| -> u32 {
+    k.iter()
+      .enumerate()
+      .fold(0u32, |w, (i, &byte)| w | (byte as u32) << (8 * i))
+  };
+
+  let mut k = key;
+  while k.len() > 12 {
+    a = a.wrapping_add(word(&k[0..4]));
+    b = b.wrapping_add(word(&k[4..8]));
+    c = c.wrapping_add(word(&k[8..12]));
+    // mix
+    a = a.wrapping_sub(c) ^ c.rotate_left(4);
+    c = c.wrapping_add(b);
+    b = b.wrapping_sub(a) ^ a.rotate_left(6);
+    a = a.wrapping_add(c);
+    c = c.wrapping_sub(b) ^ b.rotate_left(8);
+    b = b.wrapping_add(a);
+    a = a.wrapping_sub(c) ^ c.rotate_left(16);
+    c = c.wrapping_add(b);
+    b = b.wrapping_sub(a) ^ a.rotate_left(19);
+    a = a.wrapping_add(c);
+    c = c.wrapping_sub(b) ^ b.rotate_left(4);
+    b = b.wrapping_add(a);
+    k = &k[12..];
+  }
+  if k.is_empty() {
+    return (c, b);
+  }
+  a = a.wrapping_add(word(&k[..k.len().min(4)]));
+  if k.len() > 4 {
+    b = b.wrapping_add(word(&k[4..k.len().min(8)]));
+  }
+  if k.len() > 8 {
+    c = c.wrapping_add(word(&k[8..]));
+  }
+  // final
+  c = (c ^ b).wrapping_sub(b.rotate_left(14));
+  a = (a ^ c).wrapping_sub(c.rotate_left(11));
+  b = (b ^ a).wrapping_sub(a.rotate_left(25));
+  c = (c ^ b).wrapping_sub(b.rotate_left(16));
+  a = (a ^ c).wrapping_sub(c.rotate_left(4));
+  b = (b ^ a).wrapping_sub(a.rotate_left(14));
+  c = (c ^ b).wrapping_sub(b.rotate_left(24));
+  (c, b)
+}
+
+#[test]
+fn test_hashlittle2() {
+  assert_eq!(hashlittle2(b"", 0, 0), (0xDEAD_BEEF, 0xDEAD_BEEF));
+  assert_eq!(hashlittle2(b"", 0, 0xDEAD_BEEF), (0xBD5B_7DDE, 0xDEAD_BEEF));
+  assert_eq!(
+    hashlittle2(b"Four score and seven years ago", 0, 0),
+    (0x1777_0551, 0xCE72_26E6)
+  );
+  assert_eq!(
+    hashlittle2(b"Four score and seven years ago", 0, 1),
+    (0xE360_7CAE, 0xBD37_1DE4)
+  );
+  assert_eq!(
+    hashlittle2(b"Four score and seven years ago", 1, 0).0,
+    0xCD62_8161
+  );
+}
+
+#[test]
+fn test_read_bits() {
+  let data = [0b1010_1100, 0b0000_0011];
+  assert_eq!(read_bits(&data, 2, 3), 0b011);
+  assert_eq!(read_bits(&data, 6, 4), 0b1110);
+  assert_eq!(read_bits(&data, 0, 16), 0x03AC);
+}
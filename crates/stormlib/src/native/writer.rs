@@ -0,0 +1,397 @@
+//! Modification of archives
+//!
+//! New files are written after the last file of the archive, and the tables follow them on
+//! flush together with the `(listfile)` and `(attributes)`. The space of removed and replaced
+//! files is only reclaimed by compacting.
+
+use std::collections::HashMap;
+
+use stormlib_sys::*;
+
+use super::attributes::{Attributes, FileAttributes};
+use super::tables::{self, BlockEntry};
+use super::{io_error, sectors, State, ATTRIBUTES_NAME, INTERNAL_NAMES, LISTFILE_NAME};
+use crate::crypto;
+use crate::error::*;
+use crate::raw::RawFile;
+use crate::MpqPath;
+
+/// Flags StormLib uses for the `(listfile)` and `(attributes)`
+const INTERNAL_FILE_FLAGS: u32 = MPQ_FILE_COMPRESS | MPQ_FILE_ENCRYPTED | MPQ_FILE_FIX_KEY;
+
+impl State {
+  /// Fails like StormLib for archives opened read-only, and for formats that can't be written
+  fn writable(&self) -> Result<()> {
+    if self.read_only {
+      return Err(StormError::AccessDenied);
+    }
+    self.tables.writable()
+  }
+
+  /// Adds a file, or replaces the file in the neutral locale with `MPQ_FILE_REPLACEEXISTING`
+  pub(super) fn write_file(
+    &mut self,
+    name: &[u8],
+    data: &[u8],
+    flags: u32,
+    compression: u32,
+    file_time: u64,
+  ) -> Result<()> {
+    self.writable()?;
+    let replace = flags & MPQ_FILE_REPLACEEXISTING != 0;
+    let flags = normalize_flags(flags, data.len())?;
+    let attributes = FileAttributes::new(data, file_time);
+    self.insert(name, replace, attributes, |state, block_index| {
+      state.store(name, data, flags, compression, block_index)
+    })
+  }
+
+  /// Adds a file from its stored data, which must use the sector size of this archive.
+  /// Encrypted files are re-encrypted for their new name and position.
+  pub(super) fn write_raw_file(
+    &mut self,
+    name: &[u8],
+    raw: RawFile,
+    attributes: FileAttributes,
+    replace: bool,
+  ) -> Result<()> {
+    self.writable()?;
+    self.insert(name, replace, attributes, |state, block_index| {
+      state.store_raw(name, raw, block_index)
+    })
+  }
+
+  /// Adds the file in the neutral locale or replaces it, `store` writes the data of the block
+  fn insert<F>(
+    &mut self,
+    name: &[u8],
+    replace: bool,
+    attributes: FileAttributes,
+    store: F,
+  ) -> Result<()>
+  where
+    F: FnOnce(&mut Self, u32) -> Result<()>,
+  {
+    let existing = self.tables.find(name).filter(|entry| entry.locale == 0);
+    let hash_index = match existing {
+      Some(_) if !replace => return Err(StormError::AlreadyExists),
+      Some(entry) => entry.hash_index,
+      None => self.tables.free_slot(name)?,
+    };
+
+    let block_index = self.tables.allocate_block();
+    store(self, block_index)?;
+    match existing {
+      Some(entry) => {
+        self.free_block(entry.block_index);
+        self.tables.set_block(hash_index, block_index)?;
+      }
+      None => self.tables.set_entry(hash_index, name, block_index)?,
+    }
+    self.names.insert(hash_index, name.to_vec());
+    if let Some(all) = &mut self.attributes {
+      all.set(block_index, attributes);
+    }
+    self.changed = true;
+    Ok(())
+  }
+
+  /// Encodes a file after the existing data into the block at `block_index`
+  fn store(
+    &mut self,
+    name: &[u8],
+    data: &[u8],
+    flags: u32,
+    compression: u32,
+    block_index: u32,
+  ) -> Result<()> {
+    if data.len() > u32::MAX as usize {
+      return Err(StormError::DiskFull);
+    }
+    let offset = self.data_end();
+    let key = (flags & MPQ_FILE_ENCRYPTED != 0).then(|| {
+      crypto::file_key(
+        name,
+        offset,
+        data.len() as u32,
+        flags & MPQ_FILE_FIX_KEY != 0,
+      )
+    });
+    let header = &self.tables.header;
+    let raw = sectors::encode(data, flags, compression, key, header.sector_size)?;
+    tables::write_at(&mut self.file, header.offset + offset, &raw)?;
+    self.tables.blocks[block_index as usize] = BlockEntry {
+      offset,
+      compressed_size: raw.len() as u64,
+      size: data.len() as u64,
+      flags,
+    };
+    Ok(())
+  }
+
+  /// Writes stored data after the existing data into the block at `block_index`
+  fn store_raw(&mut self, name: &[u8], mut raw: RawFile, block_index: u32) -> Result<()> {
+    let offset = self.data_end();
+    let block = BlockEntry {
+      offset,
+      compressed_size: raw.data.len() as u64,
+      size: raw.size,
+      flags: raw.flags,
+    };
+    if raw.flags & MPQ_FILE_ENCRYPTED != 0 {
+      let fix_key = raw.flags & MPQ_FILE_FIX_KEY != 0;
+      let new_key = crypto::file_key(name, offset, raw.size as u32, fix_key);
+      if raw.key != Some(new_key) {
+        raw.rekey(new_key)?;
+      }
+    }
+    let header = &self.tables.header;
+    tables::write_at(&mut self.file, header.offset + offset, &raw.data)?;
+    self.tables.blocks[block_index as usize] = block;
+    Ok(())
+  }
+
+  /// Marks a block as unused, its data stays in place until the archive is compacted
+  fn free_block(&mut self, block_index: u32) {
+    self.tables.blocks[block_index as usize] = BlockEntry::default();
+    if let Some(attributes) = &mut self.attributes {
+      attributes.set(block_index, FileAttributes::default());
+    }
+  }
+
+  /// End of the data of the existing files, relative to the header
+  fn data_end(&self) -> u64 {
+    self
+      .tables
+      .blocks
+      .iter()
+      .filter(|block| block.flags & MPQ_FILE_EXISTS != 0)
+      .map(|block| block.offset + sectors::stored_size(block))
+      .max()
+      .unwrap_or(0)
+      .max(self.tables.header.size())
+  }
+
+  /// Removes a file, returning `false` if it doesn't exist
+  pub(super) fn remove_file(&mut self, name: &[u8]) -> Result<bool> {
+    self.writable()?;
+    let entry = match self.tables.find(name) {
+      Some(entry) => entry,
+      None => return Ok(false),
+    };
+    self.tables.remove(entry.hash_index)?;
+    self.free_block(entry.block_index);
+    self.names.remove(&entry.hash_index);
+    self.changed = true;
+    Ok(true)
+  }
+
+  /// Renames a file. Encrypted files are re-encrypted in place with the key of the new name.
+  pub(super) fn rename_file(&mut self, old_name: &[u8], new_name: &[u8]) -> Result<()> {
+    self.writable()?;
+    let entry = self.tables.find(old_name).ok_or(StormError::FileNotFound)?;
+    if self.tables.find(new_name).is_some() {
+      return Err(StormError::AlreadyExists);
+    }
+    let hash_index = self.tables.rename(entry.hash_index, new_name)?;
+    if let Err(err) = self.rekey(entry.block_index, old_name, new_name) {
+      self.tables.rename(hash_index, old_name)?;
+      return Err(err);
+    }
+    self.names.remove(&entry.hash_index);
+    self.names.insert(hash_index, new_name.to_vec());
+    self.changed = true;
+    Ok(())
+  }
+
+  fn rekey(&mut self, block_index: u32, old_name: &[u8], new_name: &[u8]) -> Result<()> {
+    let block = self.tables.blocks[block_index as usize];
+    if block.flags & MPQ_FILE_ENCRYPTED == 0 {
+      return Ok(());
+    }
+    let fix_key = block.flags & MPQ_FILE_FIX_KEY != 0;
+    let old_key = crypto::file_key(old_name, block.offset, block.size as u32, fix_key);
+    let new_key = crypto::file_key(new_name, block.offset, block.size as u32, fix_key);
+    if old_key == new_key {
+      return Ok(());
+    }
+    let mut raw = self.read_raw(&block)?;
+    let header = &self.tables.header;
+    sectors::reencrypt(&mut raw, &block, header.sector_size, old_key, new_key)?;
+    tables::write_at(&mut self.file, header.offset + block.offset, &raw)
+  }
+
+  /// Rebuilds the hash table for at least `count` files, which needs the names of all files
+  pub(super) fn set_max_file_count(&mut self, count: u32) -> Result<()> {
+    self.writable()?;
+    let size = count
+      .clamp(HASH_TABLE_SIZE_MIN, HASH_TABLE_SIZE_MAX)
+      .next_power_of_two();
+    if size != self.tables.hash_table_size() {
+      self.names = self.tables.resize(size, &self.names)?;
+      self.changed = true;
+    }
+    Ok(())
+  }
+
+  /// Writes the internal files, tables and header
+  pub(super) fn flush(&mut self) -> Result<()> {
+    if !self.changed {
+      return Ok(());
+    }
+    self.writable()?;
+    self.remove_internal_files()?;
+
+    if self.listfile {
+      let listfile = self.listfile_data();
+      self.write_file(
+        LISTFILE_NAME,
+        &listfile,
+        INTERNAL_FILE_FLAGS,
+        MPQ_COMPRESSION_ZLIB,
+        0,
+      )?;
+    }
+    if self.attributes.is_some() {
+      // The block is allocated first, the arrays cover every block including its own
+      let hash_index = self.tables.free_slot(ATTRIBUTES_NAME)?;
+      let block_index = self.tables.allocate_block();
+      let data = match &self.attributes {
+        Some(attributes) => attributes.to_bytes(self.tables.blocks.len()),
+        None => vec![],
+      };
+      let flags = normalize_flags(INTERNAL_FILE_FLAGS, data.len())?;
+      self.store(
+        ATTRIBUTES_NAME,
+        &data,
+        flags,
+        MPQ_COMPRESSION_ZLIB,
+        block_index,
+      )?;
+      self
+        .tables
+        .set_entry(hash_index, ATTRIBUTES_NAME, block_index)?;
+      self.names.insert(hash_index, ATTRIBUTES_NAME.to_vec());
+    }
+
+    let data_end = self.data_end();
+    let end = self.tables.save(&mut self.file, data_end)?;
+    self
+      .file
+      .set_len(self.tables.header.offset + end)
+      .map_err(io_error)?;
+    self.changed = false;
+    Ok(())
+  }
+
+  /// Removes the internal files that are written again on flush
+  fn remove_internal_files(&mut self) -> Result<()> {
+    if self.listfile {
+      self.remove_file(LISTFILE_NAME)?;
+    }
+    if self.attributes.is_some() {
+      self.remove_file(ATTRIBUTES_NAME)?;
+    }
+    Ok(())
+  }
+
+  /// Known names without the internal files, sorted and separated by CRLF. Names that only
+  /// differ in case are ordered by their bytes, so the order doesn't depend on the hash map.
+  fn listfile_data(&self) -> Vec<u8> {
+    let mut names: Vec<&MpqPath> = self
+      .names
+      .values()
+      .filter(|name| {
+        !INTERNAL_NAMES
+          .iter()
+          .any(|internal| name.eq_ignore_ascii_case(internal))
+      })
+      .map(MpqPath::new)
+      .collect();
+    names.sort_by(|a, b| a.cmp(b).then_with(|| a.as_bytes().cmp(b.as_bytes())));
+    names.dedup_by(|a, b| a.as_bytes() == b.as_bytes());
+    names
+      .into_iter()
+      .flat_map(|name| [name.as_bytes(), b"\r\n"].concat())
+      .collect()
+  }
+
+  /// Moves all files to the start of the archive, dropping the space of removed files.
+  /// Files encrypted with `MPQ_FILE_FIX_KEY` are re-encrypted for their new position, which
+  /// needs their name unless they are compressed.
+  pub(super) fn compact(&mut self) -> Result<()> {
+    self.writable()?;
+    self.remove_internal_files()?;
+    let header = self.tables.header.clone();
+
+    let block_names: HashMap<u32, Vec<u8>> = self
+      .tables
+      .entries()
+      .into_iter()
+      .filter_map(|entry| {
+        let name = self.names.get(&entry.hash_index)?;
+        Some((entry.block_index, name.clone()))
+      })
+      .collect();
+    let mut order: Vec<u32> = (0..self.tables.blocks.len() as u32)
+      .filter(|&i| self.tables.blocks[i as usize].flags & MPQ_FILE_EXISTS != 0)
+      .collect();
+    order.sort_by_key(|&i| self.tables.blocks[i as usize].offset);
+
+    // Everything is read and re-encrypted before anything is written, files may overlap
+    let mut blocks = Vec::with_capacity(order.len());
+    let mut files = Vec::with_capacity(order.len());
+    let mut map = vec![None; self.tables.blocks.len()];
+    let mut attributes = self.attributes.as_ref().map(|a| Attributes::new(a.flags));
+    let mut offset = header.size();
+    for index in order {
+      let mut block = self.tables.blocks[index as usize];
+      let mut raw = self.read_raw(&block)?;
+      if block.flags & MPQ_FILE_FIX_KEY != 0 && block.offset != offset {
+        let name = block_names.get(&index).map(Vec::as_slice);
+        let key = sectors::file_key(&block, name, &raw, header.sector_size)
+          .ok_or_else(sectors::unknown_key)?;
+        let new_key = sectors::move_key(key, &block, offset);
+        sectors::reencrypt(&mut raw, &block, header.sector_size, key, new_key)?;
+      }
+      block.offset = offset;
+      offset += raw.len() as u64;
+      map[index as usize] = Some(blocks.len() as u32);
+      if let (Some(attributes), Some(old)) = (&mut attributes, &self.attributes) {
+        attributes.set(blocks.len() as u32, old.get(index));
+      }
+      blocks.push(block);
+      files.push(raw);
+    }
+
+    for (block, raw) in blocks.iter().zip(&files) {
+      tables::write_at(&mut self.file, header.offset + block.offset, raw)?;
+    }
+    self.tables.remap_blocks(blocks, &map)?;
+    self.attributes = attributes;
+    self.changed = true;
+    self.flush()
+  }
+}
+
+/// Checks and completes the flags of a new file the way StormLib does
+fn normalize_flags(flags: u32, size: usize) -> Result<u32> {
+  let mut flags = flags & !MPQ_FILE_REPLACEEXISTING;
+  if flags & MPQ_FILE_IMPLODE != 0 {
+    return Err(StormError::NotSupported);
+  }
+  if size == 0 {
+    flags &= !(MPQ_FILE_COMPRESS_MASK
+      | MPQ_FILE_SINGLE_UNIT
+      | MPQ_FILE_SECTOR_CRC
+      | MPQ_FILE_ENCRYPTED
+      | MPQ_FILE_FIX_KEY);
+  }
+  if flags & MPQ_FILE_COMPRESS_MASK == 0 || flags & MPQ_FILE_SINGLE_UNIT != 0 {
+    flags &= !MPQ_FILE_SECTOR_CRC;
+  }
+  if flags & MPQ_FILE_ENCRYPTED == 0 {
+    flags &= !MPQ_FILE_FIX_KEY;
+  }
+  Ok(flags | MPQ_FILE_EXISTS)
+}
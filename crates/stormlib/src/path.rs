@@ -0,0 +1,502 @@
+//! Paths of files inside archives
+//!
+//! MPQ archives are flat, a path is just a file name that uses backslashes as separators by
+//! convention. StormLib hashes names case-insensitively and treats `/` the same as `\`, so
+//! [`MpqPath`] compares the same way the archive looks files up.
+
+use std::borrow::{Borrow, Cow};
+use std::cmp::Ordering;
+use std::ffi::CString;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+
+use crate::error::*;
+use crate::Codepage;
+
+/// Path separator used inside archives
+pub const SEPARATOR: u8 = b'\\';
+
+/// Longest name StormLib can store, not counting the terminating nul
+#[cfg(target_os = "windows")]
+pub const MAX_NAME_LEN: usize = 259;
+#[cfg(not(target_os = "windows"))]
+pub const MAX_NAME_LEN: usize = 1023;
+
+fn is_separator(c: u8) -> bool {
+  c == b'\\' || c == b'/'
+}
+
+/// Maps a byte the same way the MPQ name hash does
+fn normalize_byte(c: u8) -> u8 {
+  if c == b'/' {
+    SEPARATOR
+  } else {
+    c.to_ascii_uppercase()
+  }
+}
+
+/// Borrowed path of a file inside an archive, the `Path` of MPQ archives
+///
+/// The path is a sequence of bytes, which doesn't have to be valid UTF-8 (see [`Codepage`]).
+/// Both `\` and `/` are treated as separators, and comparison ignores ASCII case.
+#[repr(transparent)]
+pub struct MpqPath {
+  inner: [u8],
+}
+
+impl MpqPath {
+  /// Wraps a string or a byte slice as a path without copying it
+  pub fn new<S: AsRef<[u8]> + ?Sized>(s: &S) -> &MpqPath {
+    let bytes: &[u8] = s.as_ref();
+    unsafe { &*(bytes as *const [u8] as *const MpqPath) }
+  }
+
+  /// Returns the raw bytes of the path
+  pub fn as_bytes(&self) -> &[u8] {
+    &self.inner
+  }
+
+  /// Returns the path as a string slice if it's valid UTF-8
+  pub fn to_str(&self) -> Option<&str> {
+    std::str::from_utf8(&self.inner).ok()
+  }
+
+  /// Converts the path to a string, replacing invalid UTF-8 sequences with U+FFFD
+  pub fn to_string_lossy(&self) -> Cow<'_, str> {
+    String::from_utf8_lossy(&self.inner)
+  }
+
+  /// Decodes the path for display using the given codepage
+  pub fn decode(&self, codepage: Codepage) -> Cow<'_, str> {
+    codepage.decode(&self.inner)
+  }
+
+  /// Returns `true` if the path is empty
+  pub fn is_empty(&self) -> bool {
+    self.inner.is_empty()
+  }
+
+  /// Copies the path to an owned [`MpqPathBuf`] with normalized separators
+  pub fn to_mpq_path_buf(&self) -> MpqPathBuf {
+    MpqPathBuf::from(self.inner.to_vec())
+  }
+
+  /// Checks that the path can be stored in an archive
+  ///
+  /// The path must not be empty or longer than [`MAX_NAME_LEN`], and must not contain nul
+  /// bytes or line breaks, which would break the `(listfile)`.
+  pub fn validate(&self) -> Result<()> {
+    if self.inner.is_empty() || self.inner.len() > MAX_NAME_LEN {
+      return Err(StormError::InvalidName);
+    }
+    if self
+      .inner
+      .iter()
+      .any(|&c| c == 0 || c == b'\r' || c == b'\n')
+    {
+      return Err(StormError::InvalidName);
+    }
+    Ok(())
+  }
+
+  /// Returns the path without its final component, or `None` if the path has only one
+  pub fn parent(&self) -> Option<&MpqPath> {
+    self
+      .inner
+      .iter()
+      .rposition(|&c| is_separator(c))
+      .map(|pos| MpqPath::new(&self.inner[..pos]))
+  }
+
+  /// Returns the final component of the path, or `None` if the path ends with a separator
+  pub fn file_name(&self) -> Option<&MpqPath> {
+    let start = self
+      .inner
+      .iter()
+      .rposition(|&c| is_separator(c))
+      .map(|pos| pos + 1)
+      .unwrap_or(0);
+    let name = &self.inner[start..];
+    if name.is_empty() {
+      None
+    } else {
+      Some(MpqPath::new(name))
+    }
+  }
+
+  /// Returns the file name without its extension
+  pub fn file_stem(&self) -> Option<&MpqPath> {
+    self.split_file_name().map(|(stem, _)| stem)
+  }
+
+  /// Returns the extension of the file name, without the leading dot
+  ///
+  /// Compare it with another [`MpqPath`] to ignore case, e.g. `ext == MpqPath::new("mdx")`.
+  pub fn extension(&self) -> Option<&MpqPath> {
+    self.split_file_name().and_then(|(_, ext)| ext)
+  }
+
+  fn split_file_name(&self) -> Option<(&MpqPath, Option<&MpqPath>)> {
+    let name = self.file_name()?.as_bytes();
+    Some(match name.iter().rposition(|&c| c == b'.') {
+      Some(pos) if pos > 0 => (
+        MpqPath::new(&name[..pos]),
+        Some(MpqPath::new(&name[pos + 1..])),
+      ),
+      _ => (MpqPath::new(name), None),
+    })
+  }
+
+  /// Iterates over the components of the path. Empty components are skipped.
+  pub fn components(&self) -> impl Iterator<Item = &MpqPath> + '_ {
+    self
+      .inner
+      .split(|&c| is_separator(c))
+      .filter(|c| !c.is_empty())
+      .map(MpqPath::new)
+  }
+
+  /// Returns `true` if `base` is a prefix of the path, comparing whole components
+  pub fn starts_with<P: AsRef<MpqPath>>(&self, base: P) -> bool {
+    let mut components = self.components();
+    base
+      .as_ref()
+      .components()
+      .all(|b| components.next() == Some(b))
+  }
+
+  /// Creates an owned path with `path` appended to this one
+  pub fn join<P: AsRef<MpqPath>>(&self, path: P) -> MpqPathBuf {
+    let mut buf = self.to_mpq_path_buf();
+    buf.push(path);
+    buf
+  }
+
+  fn normalized(&self) -> impl Iterator<Item = u8> + '_ {
+    self.inner.iter().map(|&c| normalize_byte(c))
+  }
+
+  /// Converts the path to the nul terminated string passed to StormLib
+  pub(crate) fn to_cstring(&self) -> Result<CString> {
+    self.validate()?;
+    let bytes: Vec<u8> = self
+      .inner
+      .iter()
+      .map(|&c| if c == b'/' { SEPARATOR } else { c })
+      .collect();
+    Ok(CString::new(bytes)?)
+  }
+}
+
+impl PartialEq for MpqPath {
+  fn eq(&self, other: &MpqPath) -> bool {
+    self.inner.len() == other.inner.len() && self.normalized().eq(other.normalized())
+  }
+}
+
+impl Eq for MpqPath {}
+
+impl PartialOrd for MpqPath {
+  fn partial_cmp(&self, other: &MpqPath) -> Option<Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+impl Ord for MpqPath {
+  fn cmp(&self, other: &MpqPath) -> Ordering {
+    self.normalized().cmp(other.normalized())
+  }
+}
+
+impl Hash for MpqPath {
+  fn hash<H: Hasher>(&self, state: &mut H) {
+    state.write_usize(self.inner.len());
+    for c in self.normalized() {
+      state.write_u8(c);
+    }
+  }
+}
+
+impl fmt::Debug for MpqPath {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    fmt::Debug::fmt(&self.to_string_lossy(), f)
+  }
+}
+
+impl ToOwned for MpqPath {
+  type Owned = MpqPathBuf;
+
+  fn to_owned(&self) -> MpqPathBuf {
+    self.to_mpq_path_buf()
+  }
+}
+
+impl AsRef<MpqPath> for MpqPath {
+  fn as_ref(&self) -> &MpqPath {
+    self
+  }
+}
+
+impl AsRef<MpqPath> for str {
+  fn as_ref(&self) -> &MpqPath {
+    MpqPath::new(self)
+  }
+}
+
+impl AsRef<MpqPath> for String {
+  fn as_ref(&self) -> &MpqPath {
+    MpqPath::new(self)
+  }
+}
+
+impl AsRef<MpqPath> for [u8] {
+  fn as_ref(&self) -> &MpqPath {
+    MpqPath::new(self)
+  }
+}
+
+impl<const N: usize> AsRef<MpqPath> for [u8; N] {
+  fn as_ref(&self) -> &MpqPath {
+    MpqPath::new(&self[..])
+  }
+}
+
+impl AsRef<MpqPath> for Vec<u8> {
+  fn as_ref(&self) -> &MpqPath {
+    MpqPath::new(self)
+  }
+}
+
+impl AsRef<[u8]> for MpqPath {
+  fn as_ref(&self) -> &[u8] {
+    &self.inner
+  }
+}
+
+/// Owned path of a file inside an archive, the `PathBuf` of MPQ archives
+///
+/// Separators are normalized to `\` when the path is created.
+#[derive(Clone, Default)]
+pub struct MpqPathBuf {
+  inner: Vec<u8>,
+}
+
+impl MpqPathBuf {
+  /// Creates an empty path
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Appends `path` to this path, adding a separator if needed
+  pub fn push<P: AsRef<MpqPath>>(&mut self, path: P) {
+    let path = path.as_ref().as_bytes();
+    if !self.inner.is_empty() && !self.inner.ends_with(&[SEPARATOR]) {
+      self.inner.push(SEPARATOR);
+    }
+    self.inner.extend(
+      path
+        .iter()
+        .map(|&c| if is_separator(c) { SEPARATOR } else { c }),
+    );
+  }
+
+  /// Removes the final component, returns `false` if the path has only one
+  pub fn pop(&mut self) -> bool {
+    match self.parent().map(|p| p.inner.len()) {
+      Some(len) => {
+        self.inner.truncate(len);
+        true
+      }
+      None => false,
+    }
+  }
+
+  /// Returns the raw bytes of the path
+  pub fn into_bytes(self) -> Vec<u8> {
+    self.inner
+  }
+}
+
+impl Deref for MpqPathBuf {
+  type Target = MpqPath;
+
+  fn deref(&self) -> &MpqPath {
+    MpqPath::new(&self.inner)
+  }
+}
+
+impl AsRef<MpqPath> for MpqPathBuf {
+  fn as_ref(&self) -> &MpqPath {
+    self
+  }
+}
+
+impl Borrow<MpqPath> for MpqPathBuf {
+  fn borrow(&self) -> &MpqPath {
+    self
+  }
+}
+
+impl From<Vec<u8>> for MpqPathBuf {
+  fn from(bytes: Vec<u8>) -> Self {
+    let mut buf = MpqPathBuf::new();
+    buf.push(&bytes);
+    buf
+  }
+}
+
+impl From<&[u8]> for MpqPathBuf {
+  fn from(bytes: &[u8]) -> Self {
+    MpqPath::new(bytes).to_mpq_path_buf()
+  }
+}
+
+impl From<String> for MpqPathBuf {
+  fn from(s: String) -> Self {
+    s.into_bytes().into()
+  }
+}
+
+impl From<&str> for MpqPathBuf {
+  fn from(s: &str) -> Self {
+    MpqPath::new(s).to_mpq_path_buf()
+  }
+}
+
+impl From<&MpqPath> for MpqPathBuf {
+  fn from(path: &MpqPath) -> Self {
+    path.to_mpq_path_buf()
+  }
+}
+
+impl PartialEq for MpqPathBuf {
+  fn eq(&self, other: &MpqPathBuf) -> bool {
+    **self == **other
+  }
+}
+
+impl PartialEq<MpqPath> for MpqPathBuf {
+  fn eq(&self, other: &MpqPath) -> bool {
+    **self == *other
+  }
+}
+
+impl PartialEq<MpqPathBuf> for MpqPath {
+  fn eq(&self, other: &MpqPathBuf) -> bool {
+    *self == **other
+  }
+}
+
+impl Eq for MpqPathBuf {}
+
+impl PartialOrd for MpqPathBuf {
+  fn partial_cmp(&self, other: &MpqPathBuf) -> Option<Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+impl Ord for MpqPathBuf {
+  fn cmp(&self, other: &MpqPathBuf) -> Ordering {
+    (**self).cmp(&**other)
+  }
+}
+
+impl Hash for MpqPathBuf {
+  fn hash<H: Hasher>(&self, state: &mut H) {
+    (**self).hash(state)
+  }
+}
+
+impl fmt::Debug for MpqPathBuf {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    fmt::Debug::fmt(&**self, f)
+  }
+}
+
+/// Names are serialized as strings, or as bytes if they are not valid UTF-8
+#[cfg(feature = "serde")]
+impl serde::Serialize for MpqPathBuf {
+  fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    match self.to_str() {
+      Some(s) => serializer.serialize_str(s),
+      None => serializer.serialize_bytes(self.as_bytes()),
+    }
+  }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for MpqPathBuf {
+  fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    struct Visitor;
+
+    impl<'de> serde::de::Visitor<'de> for Visitor {
+      type Value = MpqPathBuf;
+
+      fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a string or bytes")
+      }
+
+      fn visit_str<E>(self, s: &str) -> Result<MpqPathBuf, E> {
+        Ok(s.into())
+      }
+
+      fn visit_bytes<E>(self, bytes: &[u8]) -> Result<MpqPathBuf, E> {
+        Ok(bytes.into())
+      }
+
+      fn visit_seq<A: serde::de::SeqAccess<'de>>(self, mut seq: A) -> Result<MpqPathBuf, A::Error> {
+        let mut bytes = vec![];
+        while let Some(byte) = seq.next_element()? {
+          bytes.push(byte);
+        }
+        Ok(bytes.into())
+      }
+    }
+
+    deserializer.deserialize_any(Visitor)
+  }
+}
+
+#[test]
+fn test_mpq_path() {
+  use std::collections::HashSet;
+
+  let path = MpqPath::new("Units/Human\\Footman.MDX");
+  assert_eq!(path, MpqPath::new("units\\human\\footman.mdx"));
+  assert_eq!(path.parent(), Some(MpqPath::new("UNITS\\HUMAN")));
+  assert_eq!(path.file_name(), Some(MpqPath::new("Footman.MDX")));
+  assert_eq!(path.file_stem(), Some(MpqPath::new("footman")));
+  assert_eq!(path.extension(), Some(MpqPath::new("mdx")));
+  assert_eq!(path.components().count(), 3);
+  assert!(path.starts_with("units"));
+  assert!(!path.starts_with("unit"));
+  assert_eq!(
+    path.to_mpq_path_buf().as_bytes(),
+    b"Units\\Human\\Footman.MDX"
+  );
+
+  assert_eq!(MpqPath::new("war3map.j").parent(), None);
+  assert_eq!(MpqPath::new(".hidden").extension(), None);
+  assert_eq!(MpqPath::new("dir\\").file_name(), None);
+
+  let mut buf = MpqPath::new("Units").join("Human/Footman.mdx");
+  assert_eq!(buf.as_bytes(), b"Units\\Human\\Footman.mdx");
+  assert!(buf.pop());
+  assert_eq!(buf.as_bytes(), b"Units\\Human");
+
+  let set: HashSet<MpqPathBuf> = ["a/B.txt", "A\\b.TXT", "c.txt"]
+    .iter()
+    .map(|&s| MpqPathBuf::from(s))
+    .collect();
+  assert_eq!(set.len(), 2);
+  assert!(set.contains(MpqPath::new("a\\b.txt")));
+
+  assert!(MpqPath::new("").validate().is_err());
+  assert!(MpqPath::new("a\nb").validate().is_err());
+  assert!(MpqPath::new(&[b'a'; MAX_NAME_LEN + 1]).validate().is_err());
+  assert_eq!(
+    MpqPath::new("a/b").to_cstring().unwrap().as_bytes(),
+    b"a\\b"
+  );
+}
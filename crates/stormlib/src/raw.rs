@@ -0,0 +1,278 @@
+//! Stored data of files
+//!
+//! Files are stored as a single unit or split into sectors. Compressed files start with a
+//! table of sector offsets, and every sector that got smaller when compressed starts with a
+//! byte naming the compressions applied to it.
+
+use stormlib_sys::*;
+
+use crate::crypto;
+use crate::error::*;
+
+/// Data of a file as it is stored in the archive, before decryption and decompression
+#[derive(Debug, Clone)]
+pub struct RawFile {
+  pub(crate) data: Vec<u8>,
+  /// `MPQ_FILE_*` flags of the block
+  pub(crate) flags: u32,
+  /// Uncompressed size of the file
+  pub(crate) size: u64,
+  pub(crate) sector_size: u32,
+  /// Key of the first sector, `None` if the file is not encrypted or the key is unknown
+  pub(crate) key: Option<u32>,
+}
+
+/// Sector of a [`RawFile`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RawSector<'a> {
+  /// Index of the sector in the file, `0` for single unit files
+  pub index: u32,
+  /// Offset of the sector in the stored data
+  pub offset: usize,
+  /// Stored bytes of the sector, still encrypted
+  pub data: &'a [u8],
+  /// Size of the sector after decompression
+  pub size: usize,
+  /// Key the sector is encrypted with
+  pub key: Option<u32>,
+}
+
+impl RawSector<'_> {
+  /// Whether the sector got smaller when compressed, in which case it's stored compressed
+  pub fn is_compressed(&self) -> bool {
+    self.data.len() < self.size
+  }
+}
+
+/// Iterator over the sectors of a [`RawFile`]
+#[derive(Debug, Clone)]
+pub struct RawSectors<'a> {
+  file: &'a RawFile,
+  offsets: std::vec::IntoIter<(usize, usize)>,
+  index: u32,
+}
+
+impl<'a> Iterator for RawSectors<'a> {
+  type Item = RawSector<'a>;
+
+  fn next(&mut self) -> Option<RawSector<'a>> {
+    let (start, end) = self.offsets.next()?;
+    let index = self.index;
+    self.index += 1;
+    let sector_size = self.file.sector_size as u64;
+    let size = if self.file.is_single_unit() {
+      self.file.size
+    } else {
+      (self.file.size - index as u64 * sector_size).min(sector_size)
+    };
+    Some(RawSector {
+      index,
+      offset: start,
+      data: &self.file.data[start..end],
+      size: size as usize,
+      key: self.file.key.map(|key| key.wrapping_add(index)),
+    })
+  }
+
+  fn size_hint(&self) -> (usize, Option<usize>) {
+    self.offsets.size_hint()
+  }
+}
+
+impl ExactSizeIterator for RawSectors<'_> {}
+
+impl RawFile {
+  /// Stored bytes of the file, including the sector offset table and sector checksums
+  pub fn data(&self) -> &[u8] {
+    &self.data
+  }
+
+  /// Converts the file into its stored bytes
+  pub fn into_data(self) -> Vec<u8> {
+    self.data
+  }
+
+  /// `MPQ_FILE_*` flags of the file
+  pub fn flags(&self) -> u32 {
+    self.flags
+  }
+
+  /// Size of the file after decompression
+  pub fn size(&self) -> u64 {
+    self.size
+  }
+
+  /// Size of the stored data
+  pub fn compressed_size(&self) -> u64 {
+    self.data.len() as u64
+  }
+
+  /// Sector size of the archive the file was read from
+  pub fn sector_size(&self) -> u32 {
+    self.sector_size
+  }
+
+  /// Key of the first sector, `None` if the file is not encrypted or the key is unknown
+  pub fn key(&self) -> Option<u32> {
+    self.key
+  }
+
+  /// Decrypted sector offset table, `None` for files stored without one. Files with sector
+  /// checksums have an extra entry for the end of the checksums.
+  pub fn offset_table(&self) -> Result<Option<Vec<u32>>> {
+    if !self.has_offset_table() {
+      return Ok(None);
+    }
+    if self.flags & MPQ_FILE_ENCRYPTED != 0 && self.key.is_none() {
+      return Err(ErrorCode(ERROR_UNKNOWN_FILE_KEY).into());
+    }
+    let len = self.sector_count() + 1 + (self.flags & MPQ_FILE_SECTOR_CRC != 0) as usize;
+    let mut table = self
+      .data
+      .get(..len * 4)
+      .ok_or(StormError::FileCorrupt)?
+      .to_vec();
+    if let Some(key) = self.key {
+      crypto::decrypt_block(&mut table, key.wrapping_sub(1));
+    }
+    Ok(Some(
+      table
+        .chunks_exact(4)
+        .map(|entry| u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]))
+        .collect(),
+    ))
+  }
+
+  /// Iterates over the stored sectors
+  pub fn sectors(&self) -> Result<RawSectors<'_>> {
+    let len = self.data.len();
+    let offsets: Vec<(usize, usize)> = match self.offset_table()? {
+      Some(table) => {
+        let offsets = &table[..self.sector_count() + 1];
+        offsets
+          .windows(2)
+          .map(|w| (w[0] as usize, w[1] as usize))
+          .map(|(start, end)| {
+            if start <= end && end <= len {
+              Ok((start, end))
+            } else {
+              Err(StormError::FileCorrupt)
+            }
+          })
+          .collect::<Result<_>>()?
+      }
+      None if self.size == 0 => vec![],
+      None if self.is_single_unit() => vec![(0, len)],
+      None => {
+        let sector_size = self.sector_size as usize;
+        (0..self.sector_count())
+          .map(|i| ((i * sector_size).min(len), ((i + 1) * sector_size).min(len)))
+          .collect()
+      }
+    };
+    Ok(RawSectors {
+      file: self,
+      offsets: offsets.into_iter(),
+      index: 0,
+    })
+  }
+
+  /// `MPQ_COMPRESSION_*` mask of the first compressed sector, `0` if the file is not
+  /// compressed or no sector got smaller
+  pub fn compression(&self) -> Result<u32> {
+    if self.flags & MPQ_FILE_IMPLODE != 0 {
+      return Ok(MPQ_COMPRESSION_PKWARE);
+    }
+    if self.flags & MPQ_FILE_COMPRESS == 0 {
+      return Ok(0);
+    }
+    if self.flags & MPQ_FILE_ENCRYPTED != 0 && self.key.is_none() {
+      return Err(ErrorCode(ERROR_UNKNOWN_FILE_KEY).into());
+    }
+    for sector in self.sectors()? {
+      if sector.is_compressed() {
+        return Ok(first_byte(&sector));
+      }
+    }
+    Ok(0)
+  }
+
+  /// Encrypts the stored data with `key` instead of its current key, without decompressing it.
+  /// The sector checksums are not encrypted and stay as they are.
+  pub(crate) fn rekey(&mut self, key: u32) -> Result<()> {
+    let old_key = self.key.ok_or(ErrorCode(ERROR_UNKNOWN_FILE_KEY))?;
+    let sectors: Vec<(usize, usize, u32)> = self
+      .sectors()?
+      .map(|sector| (sector.offset, sector.data.len(), sector.index))
+      .collect();
+    for (offset, len, index) in sectors {
+      let sector = &mut self.data[offset..offset + len];
+      crypto::decrypt_block(sector, old_key.wrapping_add(index));
+      crypto::encrypt_block(sector, key.wrapping_add(index));
+    }
+    if self.has_offset_table() {
+      let len = self.sector_count() + 1 + (self.flags & MPQ_FILE_SECTOR_CRC != 0) as usize;
+      let table = &mut self.data[..len * 4];
+      crypto::decrypt_block(table, old_key.wrapping_sub(1));
+      crypto::encrypt_block(table, key.wrapping_sub(1));
+    }
+    self.key = Some(key);
+    Ok(())
+  }
+
+  fn is_single_unit(&self) -> bool {
+    self.flags & MPQ_FILE_SINGLE_UNIT != 0
+  }
+
+  fn has_offset_table(&self) -> bool {
+    !self.is_single_unit() && self.size != 0 && self.flags & MPQ_FILE_COMPRESS_MASK != 0
+  }
+
+  fn sector_count(&self) -> usize {
+    (self.size as usize).div_ceil(self.sector_size as usize)
+  }
+}
+
+/// First byte of a sector, decrypted. Trailing bytes are never encrypted.
+fn first_byte(sector: &RawSector) -> u32 {
+  let mut word = [0u8; 4];
+  let len = sector.data.len().min(4);
+  word[..len].copy_from_slice(&sector.data[..len]);
+  if let (Some(key), 4) = (sector.key, len) {
+    crypto::decrypt_block(&mut word, key);
+  }
+  word[0] as u32
+}
+
+#[test]
+fn test_read_raw() {
+  use crate::{Archive, OpenArchiveFlags};
+
+  let archive = Archive::open_read_only(
+    "../../samples/test_tft.w3x",
+    OpenArchiveFlags::MPQ_OPEN_NO_LISTFILE | OpenArchiveFlags::MPQ_OPEN_NO_ATTRIBUTES,
+  )
+  .unwrap();
+  let mut file = archive.open_file("war3map.j").unwrap();
+  let raw = file.read_raw().unwrap();
+  assert_eq!(raw.size(), 14115);
+  assert!(raw.compressed_size() < raw.size());
+  assert_eq!(raw.compression().unwrap(), MPQ_COMPRESSION_ZLIB);
+
+  let table = raw.offset_table().unwrap().unwrap();
+  let sectors: Vec<_> = raw.sectors().unwrap().collect();
+  assert_eq!(
+    sectors.len() as u64,
+    14115u64.div_ceil(raw.sector_size() as u64)
+  );
+  assert_eq!(sectors[0].offset, table[0] as usize);
+  assert_eq!(
+    sectors.iter().map(|sector| sector.size).sum::<usize>(),
+    14115
+  );
+  for (sector, window) in sectors.iter().zip(table.windows(2)) {
+    assert_eq!(sector.data.len(), (window[1] - window[0]) as usize);
+    assert!(sector.is_compressed());
+    assert_eq!(first_byte(sector), MPQ_COMPRESSION_ZLIB);
+  }
+}
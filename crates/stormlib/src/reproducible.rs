@@ -0,0 +1,237 @@
+//! Reproducible creation of archives
+//!
+//! Archives built with a [`ReproducibleBuilder`] don't depend on the order files were added
+//! in, the modification times of local files or the current time, so the same files always
+//! give the same archive.
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::path::Path;
+
+use crate::error::*;
+use crate::{Archive, CompressionFlags, CreateArchiveFlags, CreateFileFlags, CreateFileOptions};
+use crate::{MpqPath, MpqPathBuf};
+
+/// Seconds between 1601, the `FILETIME` epoch, and 1970
+const FILETIME_UNIX_OFFSET: u64 = 11_644_473_600;
+
+/// Collects files and creates an archive from them reproducibly
+///
+/// Files are written sorted by name, all with the same file time, and the hash table is sized
+/// for the number of files.
+#[derive(Debug, Clone)]
+pub struct ReproducibleBuilder {
+  flags: CreateArchiveFlags,
+  file_time: u64,
+  files: BTreeMap<MpqPathBuf, PendingFile>,
+}
+
+#[derive(Debug, Clone)]
+struct PendingFile {
+  data: Vec<u8>,
+  flags: CreateFileFlags,
+  compression: CompressionFlags,
+}
+
+impl ReproducibleBuilder {
+  /// Creates a builder for an archive created with `flags`. Files get no file time.
+  pub fn new(flags: CreateArchiveFlags) -> Self {
+    ReproducibleBuilder {
+      flags,
+      file_time: 0,
+      files: BTreeMap::new(),
+    }
+  }
+
+  /// Creates a builder that gives files the time in the `SOURCE_DATE_EPOCH` environment
+  /// variable, if it's set
+  pub fn from_env(flags: CreateArchiveFlags) -> Result<Self> {
+    let builder = Self::new(flags);
+    match std::env::var("SOURCE_DATE_EPOCH") {
+      Ok(value) => Ok(builder.source_date_epoch(parse_source_date_epoch(&value)?)),
+      Err(std::env::VarError::NotPresent) => Ok(builder),
+      Err(std::env::VarError::NotUnicode(value)) => Err(StormError::InvalidSourceDateEpoch(
+        value.to_string_lossy().into_owned(),
+      )),
+    }
+  }
+
+  /// Sets the `FILETIME` of all files, `0` for none
+  pub fn file_time(mut self, file_time: u64) -> Self {
+    self.file_time = file_time;
+    self
+  }
+
+  /// Sets the time of all files in seconds since 1970
+  pub fn source_date_epoch(self, seconds: u64) -> Self {
+    self.file_time(
+      seconds
+        .saturating_add(FILETIME_UNIX_OFFSET)
+        .saturating_mul(10_000_000),
+    )
+  }
+
+  /// Adds a file, replacing a file added before with the same name
+  pub fn create_file<N: AsRef<MpqPath>>(
+    &mut self,
+    path: N,
+    data: Vec<u8>,
+    flags: CreateFileFlags,
+    compression: CompressionFlags,
+  ) -> &mut Self {
+    // Names are compared ignoring case, removing the old entry keeps the new casing
+    let name = path.as_ref().to_mpq_path_buf();
+    self.files.remove(&name);
+    self.files.insert(
+      name,
+      PendingFile {
+        data,
+        flags: flags - CreateFileFlags::MPQ_FILE_REPLACEEXISTING,
+        compression,
+      },
+    );
+    self
+  }
+
+  /// Adds a file from the local filesystem. Its modification time is not used.
+  pub fn add_file<P: AsRef<Path>, N: AsRef<MpqPath>>(
+    &mut self,
+    local_path: P,
+    archived_name: N,
+    flags: CreateFileFlags,
+    compression: CompressionFlags,
+  ) -> Result<&mut Self> {
+    let data = std::fs::read(local_path).map_err(io_error)?;
+    Ok(self.create_file(archived_name, data, flags, compression))
+  }
+
+  /// Number of files added
+  pub fn len(&self) -> usize {
+    self.files.len()
+  }
+
+  /// Returns `true` if no file was added
+  pub fn is_empty(&self) -> bool {
+    self.files.is_empty()
+  }
+
+  /// Creates the archive at `path` and flushes it
+  pub fn build<P: AsRef<Path>>(&self, path: P) -> Result<Archive> {
+    // The hash table size is capped anyway, adding the files fails once it is full
+    let max_files_count = u32::try_from(self.files.len()).unwrap_or(u32::MAX);
+    let mut archive = Archive::create(path, self.flags, max_files_count)?;
+    for (name, file) in &self.files {
+      archive.create_file(CreateFileOptions {
+        path: name,
+        data: &file.data,
+        flags: file.flags,
+        mtime: self.file_time,
+        compression: file.compression,
+      })?;
+    }
+    archive.flush()?;
+    Ok(archive)
+  }
+}
+
+/// Parses `SOURCE_DATE_EPOCH`, a decimal number of seconds since 1970
+fn parse_source_date_epoch(value: &str) -> Result<u64> {
+  value
+    .trim()
+    .parse()
+    .map_err(|_| StormError::InvalidSourceDateEpoch(value.to_string()))
+}
+
+#[test]
+fn test_source_date_epoch() {
+  assert_eq!(
+    parse_source_date_epoch("1700000000\n").unwrap(),
+    1_700_000_000
+  );
+  assert!(matches!(
+    parse_source_date_epoch("yesterday"),
+    Err(StormError::InvalidSourceDateEpoch(_))
+  ));
+  assert!(parse_source_date_epoch("-1").is_err());
+
+  // 2000-01-01 00:00:00 UTC
+  let builder =
+    ReproducibleBuilder::new(CreateArchiveFlags::empty()).source_date_epoch(946_684_800);
+  assert_eq!(builder.file_time, 0x01BF_53EB_256D_4000);
+}
+
+#[test]
+fn test_reproducible_build() {
+  use crate::FindDataExt;
+
+  let paths = [
+    "../../samples/test_reproducible_a.mpq",
+    "../../samples/test_reproducible_b.mpq",
+  ];
+  let script = std::fs::read("../../samples/war3map.j").unwrap();
+  let files: [(&str, &[u8]); 4] = [
+    ("war3map.j", &script),
+    ("scripts\\common.j", &script[..4096]),
+    ("Units\\unit.txt", b"[hpea]"),
+    ("a.txt", b"Hello, MPQ!"),
+  ];
+
+  let result = std::panic::catch_unwind(|| {
+    let flags = CreateArchiveFlags::MPQ_CREATE_LISTFILE | CreateArchiveFlags::MPQ_CREATE_ATTRIBUTES;
+    for (path, order) in paths.iter().zip([[0, 1, 2, 3], [3, 1, 0, 2]]) {
+      let mut builder = ReproducibleBuilder::new(flags).source_date_epoch(1_700_000_000);
+      for i in order {
+        builder.create_file(
+          files[i].0,
+          files[i].1.to_vec(),
+          CreateFileFlags::MPQ_FILE_COMPRESS | CreateFileFlags::MPQ_FILE_ENCRYPTED,
+          CompressionFlags::MPQ_COMPRESSION_ZLIB,
+        );
+      }
+      builder.create_file(
+        "A.TXT",
+        b"Replaced".to_vec(),
+        CreateFileFlags::empty(),
+        CompressionFlags::empty(),
+      );
+      builder.create_file(
+        "a.txt",
+        files[3].1.to_vec(),
+        CreateFileFlags::MPQ_FILE_COMPRESS | CreateFileFlags::MPQ_FILE_ENCRYPTED,
+        CompressionFlags::MPQ_COMPRESSION_ZLIB,
+      );
+      assert_eq!(builder.len(), 4);
+      builder.build(path).unwrap().close().unwrap();
+    }
+    assert_eq!(
+      std::fs::read(paths[0]).unwrap(),
+      std::fs::read(paths[1]).unwrap()
+    );
+
+    let archive = Archive::open(paths[0], crate::OpenArchiveFlags::empty()).unwrap();
+    let file_time = (1_700_000_000 + FILETIME_UNIX_OFFSET) * 10_000_000;
+    for data in archive.search(None).unwrap() {
+      if !data.path().as_bytes().starts_with(b"(") {
+        assert_eq!(
+          (data.dwFileTimeHi as u64) << 32 | data.dwFileTimeLo as u64,
+          file_time
+        );
+      }
+    }
+    let listfile = archive.open_file("(listfile)").unwrap().read_all().unwrap();
+    assert_eq!(
+      listfile,
+      b"a.txt\r\nscripts\\common.j\r\nUnits\\unit.txt\r\nwar3map.j\r\n"
+    );
+    assert_eq!(archive.info().unwrap().hash_table_size, 8);
+    archive.close().unwrap();
+  });
+
+  // Clean up
+  for path in paths {
+    let _ = std::fs::remove_file(path);
+  }
+
+  // Propagate any panic that occurred during the test
+  result.unwrap();
+}
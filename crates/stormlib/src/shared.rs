@@ -0,0 +1,121 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::error::*;
+use crate::{Archive, FileHandle, MpqPath, OpenArchiveFlags, RawFile, ReadOnly, Search};
+
+/// Thread-safe, reference counted, read-only MPQ archive
+///
+/// Cloning is cheap and every clone refers to the same opened archive. Files can be opened
+/// and read from any number of threads; the underlying StormLib calls are serialized.
+#[derive(Debug, Clone)]
+pub struct SharedArchive {
+  archive: Arc<Archive<ReadOnly>>,
+}
+
+impl SharedArchive {
+  /// Opens a MPQ archive read-only for sharing between threads
+  pub fn open<P: AsRef<Path>>(path: P, flags: OpenArchiveFlags) -> Result<Self> {
+    Archive::open_read_only(path, flags).map(Self::from)
+  }
+
+  /// Quick check if the file exists within MPQ archive, without opening it
+  pub fn has_file<N: AsRef<MpqPath>>(&self, path: N) -> Result<bool> {
+    self.archive.has_file(path)
+  }
+
+  /// Opens a file from MPQ archive. The returned file keeps the archive open.
+  pub fn open_file<N: AsRef<MpqPath>>(&self, path: N) -> Result<SharedFile> {
+    Ok(SharedFile {
+      inner: FileHandle::open(&self.archive, path.as_ref())?,
+      _archive: self.clone(),
+    })
+  }
+
+  /// Searches for files within the archive. If `filter` is `None`, all files will be returned
+  pub fn search(&self, filter: Option<&str>) -> Result<Search<'_>> {
+    self.archive.search(filter)
+  }
+
+  /// Returns the archive if this is the only remaining reference to it
+  pub fn try_unwrap(self) -> Result<Archive<ReadOnly>, Self> {
+    Arc::try_unwrap(self.archive).map_err(|archive| SharedArchive { archive })
+  }
+}
+
+impl From<Archive<ReadOnly>> for SharedArchive {
+  fn from(archive: Archive<ReadOnly>) -> Self {
+    SharedArchive {
+      archive: Arc::new(archive),
+    }
+  }
+}
+
+impl From<Archive> for SharedArchive {
+  fn from(archive: Archive) -> Self {
+    archive.into_read_only().into()
+  }
+}
+
+/// Opened file of a [`SharedArchive`]
+#[derive(Debug)]
+pub struct SharedFile {
+  // Declared first so the file is closed before the last archive reference is dropped
+  inner: FileHandle,
+  _archive: SharedArchive,
+}
+
+// Every StormLib call made through the file or its archive is serialized by the global lock
+unsafe impl Send for SharedFile {}
+unsafe impl Sync for SharedFile {}
+
+impl SharedFile {
+  /// Retrieves a size of the file within archive
+  pub fn get_size(&mut self) -> Result<u64> {
+    self.inner.get_size()
+  }
+
+  /// Reads all data from the file
+  pub fn read_all(&mut self) -> Result<Vec<u8>> {
+    self.inner.read_all()
+  }
+
+  /// Reads the data of the file as it is stored in the archive, without decrypting and
+  /// decompressing it
+  pub fn read_raw(&mut self) -> Result<RawFile> {
+    self.inner.read_raw()
+  }
+
+  /// Closes the file
+  pub fn close(self) -> Result<()> {
+    self.inner.close()
+  }
+}
+
+#[test]
+fn test_shared_read() {
+  let archive = SharedArchive::open(
+    "../../samples/test_tft.w3x",
+    OpenArchiveFlags::MPQ_OPEN_NO_LISTFILE | OpenArchiveFlags::MPQ_OPEN_NO_ATTRIBUTES,
+  )
+  .unwrap();
+  let expected = std::fs::read("../../samples/war3map.j").unwrap();
+
+  let threads: Vec<_> = (0..8)
+    .map(|_| {
+      let archive = archive.clone();
+      std::thread::spawn(move || {
+        let mut f = archive.open_file("war3map.j").unwrap();
+        (f.get_size().unwrap(), f.read_all().unwrap())
+      })
+    })
+    .collect();
+
+  for t in threads {
+    let (size, data) = t.join().unwrap();
+    assert_eq!(size, expected.len() as u64);
+    assert_eq!(data, expected);
+  }
+
+  assert!(archive.try_unwrap().is_ok());
+}
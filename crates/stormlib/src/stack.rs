@@ -0,0 +1,179 @@
+use std::collections::HashSet;
+
+use stormlib_sys::SFILE_FIND_DATA;
+
+use crate::error::*;
+use crate::{Archive, ArchiveMode, File, FindDataExt, MpqPath, MpqPathBuf, ReadOnly};
+
+/// Archives searched in priority order, like the game resolves `war3.mpq`, `war3x.mpq`,
+/// `war3patch.mpq` and the map
+///
+/// Archives pushed later take priority over the ones pushed before them.
+#[derive(Debug)]
+pub struct ArchiveStack<M: ArchiveMode = ReadOnly> {
+  layers: Vec<Archive<M>>,
+}
+
+/// Search result of an [`ArchiveStack`]
+#[derive(Debug, Clone, Copy)]
+pub struct StackEntry {
+  /// Index of the layer the file was found in, `0` is the bottom layer
+  pub layer: usize,
+  pub data: SFILE_FIND_DATA,
+}
+
+impl FindDataExt for StackEntry {
+  fn name_bytes(&self) -> &[u8] {
+    self.data.name_bytes()
+  }
+}
+
+impl<M: ArchiveMode> Default for ArchiveStack<M> {
+  fn default() -> Self {
+    ArchiveStack { layers: vec![] }
+  }
+}
+
+impl<M: ArchiveMode> ArchiveStack<M> {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Adds an archive on top of the stack and returns its layer index
+  pub fn push(&mut self, archive: Archive<M>) -> usize {
+    self.layers.push(archive);
+    self.layers.len() - 1
+  }
+
+  /// Removes the top archive
+  pub fn pop(&mut self) -> Option<Archive<M>> {
+    self.layers.pop()
+  }
+
+  pub fn len(&self) -> usize {
+    self.layers.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.layers.is_empty()
+  }
+
+  /// Returns the archive of a layer
+  pub fn layer(&self, index: usize) -> Option<&Archive<M>> {
+    self.layers.get(index)
+  }
+
+  /// Returns the archives from the bottom to the top layer
+  pub fn layers(&self) -> &[Archive<M>] {
+    &self.layers
+  }
+
+  /// Returns the index of the topmost layer containing the file
+  pub fn find_layer<N: AsRef<MpqPath>>(&self, path: N) -> Result<Option<usize>> {
+    let path = path.as_ref();
+    for (index, archive) in self.layers.iter().enumerate().rev() {
+      if archive.has_file(path)? {
+        return Ok(Some(index));
+      }
+    }
+    Ok(None)
+  }
+
+  /// Quick check if any layer contains the file
+  pub fn has_file<N: AsRef<MpqPath>>(&self, path: N) -> Result<bool> {
+    Ok(self.find_layer(path)?.is_some())
+  }
+
+  /// Opens the file from the topmost layer containing it
+  pub fn open_file<N: AsRef<MpqPath>>(&self, path: N) -> Result<File<'_>> {
+    let path = path.as_ref();
+    match self.find_layer(path)? {
+      Some(index) => self.layers[index].open_file(path),
+      None => Err(StormError::FileNotFound),
+    }
+  }
+
+  /// Searches all layers. Each name is reported once, from the topmost layer containing it,
+  /// and the results are ordered from the top layer down.
+  pub fn search(&self, filter: Option<&str>) -> Result<Vec<StackEntry>> {
+    let mut seen = HashSet::new();
+    let mut entries = vec![];
+    for (layer, archive) in self.layers.iter().enumerate().rev() {
+      for data in archive.search(filter)? {
+        if seen.insert(MpqPathBuf::from(data.name_bytes())) {
+          entries.push(StackEntry { layer, data });
+        }
+      }
+    }
+    Ok(entries)
+  }
+}
+
+impl<M: ArchiveMode> From<Vec<Archive<M>>> for ArchiveStack<M> {
+  /// Creates a stack from archives ordered from the bottom to the top layer
+  fn from(layers: Vec<Archive<M>>) -> Self {
+    ArchiveStack { layers }
+  }
+}
+
+#[test]
+fn test_archive_stack() {
+  use crate::OpenArchiveFlags;
+  use crate::{CompressionFlags, CreateArchiveFlags, CreateFileFlags, CreateFileOptions};
+
+  let patch_path = "../../samples/test_archive_stack.mpq";
+  let patched = b"// patched".to_vec();
+
+  let result = std::panic::catch_unwind(|| {
+    let mut patch =
+      Archive::create(patch_path, CreateArchiveFlags::MPQ_CREATE_LISTFILE, 16).unwrap();
+    for name in ["WAR3MAP.J", "patch.txt"] {
+      patch
+        .create_file(CreateFileOptions {
+          path: MpqPath::new(name),
+          data: &patched,
+          flags: CreateFileFlags::MPQ_FILE_COMPRESS,
+          mtime: 0,
+          compression: CompressionFlags::MPQ_COMPRESSION_ZLIB,
+        })
+        .unwrap();
+    }
+    patch.close().unwrap();
+
+    let mut stack = ArchiveStack::new();
+    stack.push(
+      Archive::open_read_only("../../samples/test_tft.w3x", OpenArchiveFlags::empty()).unwrap(),
+    );
+    assert_eq!(
+      stack.push(Archive::open_read_only(patch_path, OpenArchiveFlags::empty()).unwrap()),
+      1
+    );
+
+    assert_eq!(stack.find_layer("war3map.j").unwrap(), Some(1));
+    assert_eq!(stack.find_layer("war3map.w3i").unwrap(), Some(0));
+    assert!(!stack.has_file("missing").unwrap());
+    assert!(matches!(
+      stack.open_file("missing"),
+      Err(StormError::FileNotFound)
+    ));
+    assert_eq!(
+      stack.open_file("war3map.j").unwrap().read_all().unwrap(),
+      patched
+    );
+
+    let entries = stack.search(None).unwrap();
+    let layers_of = |name: &str| -> Vec<usize> {
+      entries
+        .iter()
+        .filter(|e| e.path() == MpqPath::new(name))
+        .map(|e| e.layer)
+        .collect()
+    };
+    assert_eq!(layers_of("war3map.j"), [1]);
+    assert_eq!(layers_of("war3map.w3i"), [0]);
+    assert_eq!(layers_of("patch.txt"), [1]);
+  });
+
+  std::fs::remove_file(patch_path).unwrap();
+  result.unwrap();
+}
@@ -0,0 +1,233 @@
+//! Directory view of archive contents
+//!
+//! MPQ archives store a flat list of names, directories only exist as `\` separated name
+//! prefixes. [`ArchiveTree`] groups the names into directories. Names are compared case
+//! insensitively like StormLib does, so `Units\a.mdx` and `UNITS\b.mdx` end up in the same
+//! directory, named after the first spelling that was inserted.
+
+use std::collections::btree_map::{self, BTreeMap};
+
+use crate::error::*;
+use crate::{Archive, ArchiveMode, FindDataExt, MpqPath, MpqPathBuf};
+
+/// Directory tree built from the names of the files in an archive
+#[derive(Debug, Clone, Default)]
+pub struct ArchiveTree {
+  root: TreeNode,
+}
+
+/// File or directory of an [`ArchiveTree`]
+///
+/// A name can be both a file and a directory, e.g. when an archive contains `a` and `a\b`.
+#[derive(Debug, Clone, Default)]
+pub struct TreeNode {
+  name: MpqPathBuf,
+  path: MpqPathBuf,
+  file: Option<FileSizes>,
+  children: BTreeMap<MpqPathBuf, TreeNode>,
+  total: FileSizes,
+  file_count: usize,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct FileSizes {
+  size: u64,
+  compressed_size: u64,
+}
+
+/// Iterator over the immediate children of a directory, ordered by name
+pub type ReadDir<'a> = btree_map::Values<'a, MpqPathBuf, TreeNode>;
+
+impl ArchiveTree {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Adds a file. Returns `false` if a file with the same name was already added or the
+  /// name is empty.
+  pub fn insert<N: AsRef<MpqPath>>(&mut self, path: N, size: u64, compressed_size: u64) -> bool {
+    let sizes = FileSizes {
+      size,
+      compressed_size,
+    };
+    let path = path.as_ref();
+    if path.components().next().is_none() || self.root.get(path).and_then(|n| n.file).is_some() {
+      return false;
+    }
+
+    let mut node = &mut self.root;
+    node.add(sizes);
+    for component in path.components() {
+      let parent_path = node.path.clone();
+      node = node
+        .children
+        .entry(component.to_mpq_path_buf())
+        .or_insert_with(|| TreeNode {
+          name: component.to_mpq_path_buf(),
+          path: parent_path.join(component),
+          ..TreeNode::default()
+        });
+      node.add(sizes);
+    }
+    node.file = Some(sizes);
+    true
+  }
+
+  /// The root directory
+  pub fn root(&self) -> &TreeNode {
+    &self.root
+  }
+
+  /// Returns the file or directory at `path`, an empty path is the root directory
+  pub fn get<N: AsRef<MpqPath>>(&self, path: N) -> Option<&TreeNode> {
+    self.root.get(path.as_ref())
+  }
+
+  /// Lists the immediate children of the directory at `path`
+  pub fn read_dir<N: AsRef<MpqPath>>(&self, path: N) -> Option<ReadDir<'_>> {
+    self.get(path).map(TreeNode::children)
+  }
+}
+
+impl TreeNode {
+  fn add(&mut self, sizes: FileSizes) {
+    self.total.size += sizes.size;
+    self.total.compressed_size += sizes.compressed_size;
+    self.file_count += 1;
+  }
+
+  fn get(&self, path: &MpqPath) -> Option<&TreeNode> {
+    path
+      .components()
+      .try_fold(self, |node, component| node.children.get(component))
+  }
+
+  /// Last component of the path, empty for the root directory
+  pub fn name(&self) -> &MpqPath {
+    &self.name
+  }
+
+  /// Full path within the archive
+  pub fn path(&self) -> &MpqPath {
+    &self.path
+  }
+
+  /// Returns `true` if a file with this name exists
+  pub fn is_file(&self) -> bool {
+    self.file.is_some()
+  }
+
+  /// Returns `true` if this is the root or has children
+  pub fn is_dir(&self) -> bool {
+    self.path.is_empty() || !self.children.is_empty()
+  }
+
+  /// Size of the file with this name
+  pub fn file_size(&self) -> Option<u64> {
+    self.file.map(|f| f.size)
+  }
+
+  /// Compressed size of the file with this name
+  pub fn file_compressed_size(&self) -> Option<u64> {
+    self.file.map(|f| f.compressed_size)
+  }
+
+  /// Total size of this file and every file below it
+  pub fn total_size(&self) -> u64 {
+    self.total.size
+  }
+
+  /// Total compressed size of this file and every file below it
+  pub fn total_compressed_size(&self) -> u64 {
+    self.total.compressed_size
+  }
+
+  /// Number of files, including this one and every file below it
+  pub fn file_count(&self) -> usize {
+    self.file_count
+  }
+
+  /// Lists the immediate children, ordered by name
+  pub fn children(&self) -> ReadDir<'_> {
+    self.children.values()
+  }
+
+  /// Returns a child by name
+  pub fn child<N: AsRef<MpqPath>>(&self, name: N) -> Option<&TreeNode> {
+    self.children.get(name.as_ref())
+  }
+}
+
+impl<M: ArchiveMode> Archive<M> {
+  /// Builds a directory tree of the files in the archive
+  ///
+  /// Names come from the search, so files not named in the `(listfile)` show up in the root
+  /// directory with the `FileXXXXXXXX.xxx` names StormLib generates for them.
+  pub fn tree(&self) -> Result<ArchiveTree> {
+    let mut tree = ArchiveTree::new();
+    for data in self.search(None)? {
+      tree.insert(data.path(), data.dwFileSize as u64, data.dwCompSize as u64);
+    }
+    Ok(tree)
+  }
+}
+
+#[test]
+fn test_tree_insert() {
+  let mut tree = ArchiveTree::new();
+  assert!(tree.insert("Units\\Human\\Footman.mdx", 100, 50));
+  assert!(tree.insert("UNITS/human/Knight.mdx", 200, 80));
+  assert!(tree.insert("war3map.j", 10, 5));
+  assert!(tree.insert("Units", 1, 1));
+  assert!(!tree.insert("units\\HUMAN\\footman.mdx", 100, 50));
+
+  let root = tree.root();
+  assert!(root.is_dir());
+  assert_eq!(root.file_count(), 4);
+  assert_eq!(root.total_size(), 311);
+  assert_eq!(root.total_compressed_size(), 136);
+
+  let names: Vec<_> = tree
+    .read_dir("")
+    .unwrap()
+    .map(|n| n.name().to_string_lossy().into_owned())
+    .collect();
+  assert_eq!(names, ["Units", "war3map.j"]);
+
+  let units = tree.get("units").unwrap();
+  assert!(units.is_file() && units.is_dir());
+  assert_eq!(units.file_size(), Some(1));
+  assert_eq!(units.file_count(), 3);
+
+  let human = tree.get("units/human").unwrap();
+  assert_eq!(human.path(), MpqPath::new("Units\\Human"));
+  assert_eq!(human.total_size(), 300);
+  assert_eq!(human.children().count(), 2);
+  assert_eq!(
+    human.child("KNIGHT.MDX").unwrap().path().as_bytes(),
+    b"Units\\Human\\Knight.mdx"
+  );
+
+  assert!(tree.read_dir("war3map.j").unwrap().next().is_none());
+  assert!(tree.read_dir("missing").is_none());
+}
+
+#[test]
+fn test_tree() {
+  use crate::OpenArchiveFlags;
+
+  let archive =
+    Archive::open_read_only("../../samples/test_tft.w3x", OpenArchiveFlags::empty()).unwrap();
+  let tree = archive.tree().unwrap();
+  assert_eq!(tree.get("war3map.j").unwrap().file_size(), Some(14115));
+  assert!(tree.root().file_count() > 1);
+
+  let archive = Archive::open_read_only(
+    "../../samples/test_tft.w3x",
+    OpenArchiveFlags::MPQ_OPEN_NO_LISTFILE,
+  )
+  .unwrap();
+  let tree = archive.tree().unwrap();
+  assert!(tree.root().file_count() > 1);
+  assert!(tree.root().children().all(|n| !n.is_dir()));
+}
@@ -1,11 +1,91 @@
+use std::sync::{Mutex, MutexGuard};
+
+/// StormLib keeps its last error code in a process-wide variable on non-Windows platforms,
+/// and its handles are not safe to use from multiple threads at once, so every call into
+/// the library is serialized through this lock.
+static STORM_LOCK: Mutex<()> = Mutex::new(());
+
+/// Acquires the global StormLib lock
+pub(crate) fn lock() -> MutexGuard<'static, ()> {
+  STORM_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
 /// Check ffi function call result, propagates the error
+///
+/// The call and the `SErrGetLastError` read happen under the same lock, so the error code
+/// can't be overwritten by a call made from another thread in between.
 macro_rules! unsafe_try_call {
   ($r:expr) => {
     #[allow(unused_unsafe)]
     unsafe {
+      let _guard = $crate::util::lock();
       if !$r {
         return Err($crate::error::ErrorCode(stormlib_sys::SErrGetLastError()).into());
       }
     }
   };
 }
+
+/// Path in the platform representation StormLib expects for `TCHAR` strings
+#[cfg(target_os = "windows")]
+pub(crate) type TPath = Vec<u16>;
+#[cfg(not(target_os = "windows"))]
+pub(crate) type TPath = std::ffi::CString;
+
+/// Converts a filesystem path to a nul terminated `TCHAR` string
+///
+/// On Unix the raw bytes of the path are passed through, so paths that are not valid UTF-8
+/// can be used as long as the filesystem accepts them.
+pub(crate) fn to_tpath(path: &std::path::Path) -> crate::error::Result<TPath> {
+  #[cfg(target_os = "windows")]
+  {
+    use widestring::U16CString;
+    Ok(
+      U16CString::from_os_str(path)
+        .map_err(|_| crate::error::StormError::InteriorNul)?
+        .into_vec_with_nul(),
+    )
+  }
+  #[cfg(unix)]
+  {
+    use std::os::unix::ffi::OsStrExt;
+    Ok(std::ffi::CString::new(path.as_os_str().as_bytes())?)
+  }
+  #[cfg(not(any(unix, target_os = "windows")))]
+  {
+    let pathstr = path.to_str().ok_or(crate::error::StormError::NonUtf8)?;
+    Ok(std::ffi::CString::new(pathstr)?)
+  }
+}
+
+/// Converts a `TCHAR` string returned by StormLib to a path, up to the first nul
+pub(crate) fn from_tchars(
+  mut buf: Vec<stormlib_sys::TCHAR>,
+) -> crate::error::Result<std::path::PathBuf> {
+  if let Some(nul) = buf.iter().position(|&c| c == 0) {
+    buf.truncate(nul);
+  }
+
+  #[cfg(target_os = "windows")]
+  {
+    use std::os::windows::ffi::OsStringExt;
+    Ok(std::ffi::OsString::from_wide(&buf).into())
+  }
+  #[cfg(not(target_os = "windows"))]
+  {
+    let bytes: Vec<u8> = buf.into_iter().map(|c| c as u8).collect();
+    #[cfg(unix)]
+    {
+      use std::os::unix::ffi::OsStringExt;
+      Ok(std::ffi::OsString::from_vec(bytes).into())
+    }
+    #[cfg(not(unix))]
+    {
+      Ok(
+        String::from_utf8(bytes)
+          .map_err(|_| crate::error::StormError::NonUtf8)?
+          .into(),
+      )
+    }
+  }
+}
@@ -0,0 +1,348 @@
+//! Warcraft III maps
+//!
+//! A `.w3m` or `.w3x` map starts with a 512 byte `HM3W` header with the name, flags and
+//! player count the game lists the map with, and the MPQ follows it at offset `0x200`. The
+//! header isn't part of the MPQ, so flushing and compacting a map keep it as it is.
+//!
+//! The [`w3i`] module reads the `war3map.w3i` map info inside the MPQ, and [`wts`] the
+//! `war3map.wts` trigger strings other map files reference.
+
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use stormlib_sys::*;
+
+use crate::error::*;
+use crate::{Archive, ArchiveMode, CreateArchiveFlags, OpenArchiveFlags};
+
+pub mod w3i;
+pub use w3i::MapInfo;
+
+pub mod wts;
+pub use wts::TriggerStrings;
+
+/// Size of the map header, the MPQ starts right after it
+pub const MAP_HEADER_SIZE: usize = 0x200;
+
+const MAP_HEADER_ID: &[u8; 4] = b"HM3W";
+
+/// The `HM3W` header of a Warcraft III map
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MapHeader {
+  /// Name of the map, usually UTF-8 or a `TRIGSTR_` reference to the `war3map.wts`
+  pub name: Vec<u8>,
+  /// Map flags, the same as in the `war3map.w3i`
+  pub flags: u32,
+  /// Number of players the game lists the map for
+  pub max_players: u32,
+}
+
+impl MapHeader {
+  /// Creates a header with the name, flags and player count the game lists the map with
+  pub fn new<N: Into<Vec<u8>>>(name: N, flags: u32, max_players: u32) -> Self {
+    MapHeader {
+      name: name.into(),
+      flags,
+      max_players,
+    }
+  }
+
+  /// Parses the header at the start of `data`
+  pub fn parse(data: &[u8]) -> Result<Self> {
+    if data.len() < MAP_HEADER_SIZE || &data[..4] != MAP_HEADER_ID {
+      return Err(StormError::BadFormat);
+    }
+    let data = &data[..MAP_HEADER_SIZE];
+    let name_len = data[8..]
+      .iter()
+      .position(|&c| c == 0)
+      .ok_or(StormError::BadFormat)?;
+    let fields = &data[8 + name_len + 1..];
+    if fields.len() < 8 {
+      return Err(StormError::BadFormat);
+    }
+    Ok(MapHeader {
+      name: data[8..8 + name_len].to_vec(),
+      flags: u32_at(fields),
+      max_players: u32_at(&fields[4..]),
+    })
+  }
+
+  /// Encodes the header. Fails with [`StormError::InvalidParameter`] if the name contains
+  /// a nul byte or doesn't fit.
+  pub fn to_bytes(&self) -> Result<Vec<u8>> {
+    if self.name.contains(&0) || 8 + self.name.len() + 1 + 8 > MAP_HEADER_SIZE {
+      return Err(StormError::InvalidParameter);
+    }
+    let mut buf = Vec::with_capacity(MAP_HEADER_SIZE);
+    buf.extend_from_slice(MAP_HEADER_ID);
+    buf.extend_from_slice(&0u32.to_le_bytes());
+    buf.extend_from_slice(&self.name);
+    buf.push(0);
+    buf.extend_from_slice(&self.flags.to_le_bytes());
+    buf.extend_from_slice(&self.max_players.to_le_bytes());
+    buf.resize(MAP_HEADER_SIZE, 0);
+    Ok(buf)
+  }
+
+  /// Reads the header of the map at `path`, `None` if the file doesn't start with one
+  pub fn read<P: AsRef<Path>>(path: P) -> Result<Option<Self>> {
+    let mut data = Vec::with_capacity(MAP_HEADER_SIZE);
+    fs::File::open(path)
+      .and_then(|file| file.take(MAP_HEADER_SIZE as u64).read_to_end(&mut data))
+      .map_err(io_error)?;
+    Ok(MapHeader::parse(&data).ok())
+  }
+
+  /// Writes the header to the closed archive at `path`. A header is inserted in front of an
+  /// archive that starts with the MPQ, which rewrites the whole file.
+  pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+    let header = self.to_bytes()?;
+    let mut file = fs::OpenOptions::new()
+      .read(true)
+      .write(true)
+      .open(path)
+      .map_err(io_error)?;
+    let mut id = [0u8; 4];
+    file.read_exact(&mut id).map_err(io_error)?;
+    let mut data = vec![];
+    if &id != MAP_HEADER_ID {
+      if ![ID_MPQ, ID_MPQ_USERDATA].contains(&u32_at(&id)) {
+        return Err(StormError::BadFormat);
+      }
+      data.extend_from_slice(&id);
+      file.read_to_end(&mut data).map_err(io_error)?;
+    }
+    file
+      .seek(SeekFrom::Start(0))
+      .and_then(|_| file.write_all(&header))
+      .and_then(|_| file.write_all(&data))
+      .map_err(io_error)
+  }
+}
+
+fn u32_at(data: &[u8]) -> u32 {
+  u32::from_le_bytes([data[0], data[1], data[2], data[3]])
+}
+
+/// Reads the little endian fields of the map files, failing with [`StormError::BadFormat`]
+/// at the end of the data
+struct Reader<'a> {
+  data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+  fn new(data: &'a [u8]) -> Self {
+    Reader { data }
+  }
+
+  fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+    if self.data.len() < len {
+      return Err(StormError::BadFormat);
+    }
+    let (bytes, rest) = self.data.split_at(len);
+    self.data = rest;
+    Ok(bytes)
+  }
+
+  fn u8(&mut self) -> Result<u8> {
+    Ok(self.bytes(1)?[0])
+  }
+
+  fn u32(&mut self) -> Result<u32> {
+    self.bytes(4).map(u32_at)
+  }
+
+  fn i32(&mut self) -> Result<i32> {
+    self.u32().map(|value| value as i32)
+  }
+
+  fn f32(&mut self) -> Result<f32> {
+    self.u32().map(f32::from_bits)
+  }
+
+  /// Four character id such as `hfoo`
+  fn id(&mut self) -> Result<[u8; 4]> {
+    self.array()
+  }
+
+  fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+    let mut array = [0u8; N];
+    array.copy_from_slice(self.bytes(N)?);
+    Ok(array)
+  }
+
+  /// Nul terminated string
+  fn string(&mut self) -> Result<Vec<u8>> {
+    let len = self
+      .data
+      .iter()
+      .position(|&c| c == 0)
+      .ok_or(StormError::BadFormat)?;
+    let string = self.bytes(len)?.to_vec();
+    self.data = &self.data[1..];
+    Ok(string)
+  }
+
+  /// Reads `count` items, a `u32` that comes first
+  fn list<T>(&mut self, mut item: impl FnMut(&mut Self) -> Result<T>) -> Result<Vec<T>> {
+    let count = self.u32()?;
+    let mut items = vec![];
+    for _ in 0..count {
+      items.push(item(self)?);
+    }
+    Ok(items)
+  }
+
+  fn rest(&mut self) -> &'a [u8] {
+    std::mem::take(&mut self.data)
+  }
+}
+
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+  buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_i32(buf: &mut Vec<u8>, value: i32) {
+  buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_f32(buf: &mut Vec<u8>, value: f32) {
+  buf.extend_from_slice(&value.to_le_bytes());
+}
+
+/// Writes a nul terminated string, fails with [`StormError::InvalidParameter`] if it
+/// contains a nul byte
+fn put_string(buf: &mut Vec<u8>, string: &[u8]) -> Result<()> {
+  if string.contains(&0) {
+    return Err(StormError::InvalidParameter);
+  }
+  buf.extend_from_slice(string);
+  buf.push(0);
+  Ok(())
+}
+
+/// Writes the length of `items` followed by every item
+fn put_list<T>(
+  buf: &mut Vec<u8>,
+  items: &[T],
+  mut item: impl FnMut(&mut Vec<u8>, &T) -> Result<()>,
+) -> Result<()> {
+  put_u32(buf, items.len() as u32);
+  for value in items {
+    item(buf, value)?;
+  }
+  Ok(())
+}
+
+impl Archive {
+  /// Creates a Warcraft III map, an archive preceded by `header`
+  ///
+  /// The archive is created and closed, the header is written in front of it, and the map is
+  /// opened again with no [`OpenArchiveFlags`]. Use [`Archive::open`] with the map's path to
+  /// open it with other flags.
+  pub fn create_map<P: AsRef<Path>>(
+    path: P,
+    header: &MapHeader,
+    flags: CreateArchiveFlags,
+    max_files_count: u32,
+  ) -> Result<Archive> {
+    let path = path.as_ref();
+    // Checked before anything is created
+    header.to_bytes()?;
+    Archive::create(path, flags, max_files_count)?.close()?;
+    header.write(path)?;
+    Archive::open(path, OpenArchiveFlags::empty())
+  }
+}
+
+impl<M: ArchiveMode> Archive<M> {
+  /// Reads the header of a Warcraft III map, `None` if the archive doesn't have one. With
+  /// StormLib, archives that are not a plain local file fail with [`StormError::NotSupported`].
+  pub fn map_header(&self) -> Result<Option<MapHeader>> {
+    let data = self.read_prefix(MAP_HEADER_SIZE as u64)?;
+    Ok(MapHeader::parse(&data).ok())
+  }
+}
+
+#[test]
+fn test_map_header() {
+  let archive = Archive::open(
+    "../../samples/test_tft.w3x",
+    OpenArchiveFlags::MPQ_OPEN_NO_LISTFILE | OpenArchiveFlags::MPQ_OPEN_NO_ATTRIBUTES,
+  )
+  .unwrap();
+  let header = archive.map_header().unwrap().unwrap();
+  assert_eq!(header, MapHeader::new("Small Wars", 0xDC10, 1));
+  assert_eq!(
+    MapHeader::read("../../samples/test_tft.w3x").unwrap(),
+    Some(header.clone())
+  );
+  let data = std::fs::read("../../samples/test_tft.w3x").unwrap();
+  assert_eq!(header.to_bytes().unwrap(), &data[..MAP_HEADER_SIZE]);
+
+  assert!(matches!(
+    MapHeader::new(vec![b'a'; 496], 0, 1).to_bytes(),
+    Err(StormError::InvalidParameter)
+  ));
+  assert!(MapHeader::new(vec![b'a'; 495], 0, 1).to_bytes().is_ok());
+}
+
+#[test]
+fn test_create_map() {
+  use crate::{CompressionFlags, CreateFileFlags, CreateFileOptions};
+
+  let map_path = "../../samples/test_create_map.w3x";
+  let bare_path = "../../samples/test_create_map_bare.mpq";
+  let script = std::fs::read("../../samples/war3map.j").unwrap();
+  let header = MapHeader::new("Test Map", 0x4000, 4);
+
+  let result = std::panic::catch_unwind(|| {
+    let flags = CreateArchiveFlags::MPQ_CREATE_LISTFILE | CreateArchiveFlags::MPQ_CREATE_ATTRIBUTES;
+    let mut archive = Archive::create_map(map_path, &header, flags, 16).unwrap();
+    let mut bare = Archive::create(bare_path, flags, 16).unwrap();
+    for archive in [&mut archive, &mut bare] {
+      archive
+        .create_file(CreateFileOptions {
+          path: crate::MpqPath::new("war3map.j"),
+          data: &script,
+          flags: CreateFileFlags::MPQ_FILE_COMPRESS
+            | CreateFileFlags::MPQ_FILE_ENCRYPTED
+            | CreateFileFlags::MPQ_FILE_FIX_KEY,
+          mtime: 0,
+          compression: CompressionFlags::MPQ_COMPRESSION_ZLIB,
+        })
+        .unwrap();
+    }
+    archive.compact().unwrap();
+    assert_eq!(archive.map_header().unwrap(), Some(header.clone()));
+    assert_eq!(
+      archive.info().unwrap().header_offset,
+      MAP_HEADER_SIZE as u64
+    );
+    archive.close().unwrap();
+    bare.close().unwrap();
+    assert_eq!(MapHeader::read(map_path).unwrap(), Some(header.clone()));
+
+    // Inserting a header moves the MPQ, its offsets are relative to the MPQ header
+    assert_eq!(MapHeader::read(bare_path).unwrap(), None);
+    header.write(bare_path).unwrap();
+    let renamed = MapHeader::new("Renamed", 0x4000, 2);
+    renamed.write(bare_path).unwrap();
+    let bare = Archive::open(bare_path, OpenArchiveFlags::empty()).unwrap();
+    assert_eq!(bare.map_header().unwrap(), Some(renamed));
+    assert_eq!(
+      bare.open_file("war3map.j").unwrap().read_all().unwrap(),
+      script
+    );
+    bare.close().unwrap();
+  });
+
+  // Clean up
+  let _ = std::fs::remove_file(map_path);
+  let _ = std::fs::remove_file(bare_path);
+
+  // Propagate any panic that occurred during the test
+  result.unwrap();
+}
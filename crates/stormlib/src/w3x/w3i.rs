@@ -0,0 +1,598 @@
+//! The `war3map.w3i` map info
+//!
+//! Holds what the game and the editor show about a map before loading it: the name, author,
+//! description, player slots and forces, loading screen and game data settings. Strings are
+//! usually UTF-8 or `TRIGSTR_` references to the `war3map.wts`.
+
+use super::*;
+
+/// Name of the map info file in the map archive
+pub const MAP_INFO_FILE: &str = "war3map.w3i";
+
+/// Format version of Reign of Chaos maps
+pub const W3I_VERSION_ROC: u32 = 18;
+/// Format version of The Frozen Throne maps
+pub const W3I_VERSION_TFT: u32 = 25;
+/// Format version of maps saved by patch 1.31, adds the game version and script language
+pub const W3I_VERSION_131: u32 = 28;
+/// Format version of Reforged maps, adds supported modes and enemy priorities
+pub const W3I_VERSION_REFORGED: u32 = 31;
+
+/// Parsed `war3map.w3i`
+///
+/// Fields a format version doesn't have are left at their defaults and ignored when writing.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MapInfo {
+  /// Format version, one of the `W3I_VERSION_*` constants
+  pub version: u32,
+  /// Number of times the map was saved
+  pub saves: u32,
+  pub editor_version: u32,
+  /// Major, minor, patch and build of the game that saved the map, since 1.31
+  pub game_version: [u32; 4],
+  pub name: Vec<u8>,
+  pub author: Vec<u8>,
+  pub description: Vec<u8>,
+  pub players_recommended: Vec<u8>,
+  /// Corners of the camera bounds, `x` and `y` of the bottom left, top right, top left and
+  /// bottom right corner
+  pub camera_bounds: [f32; 8],
+  /// Margins of the camera bounds to the left, right, bottom and top edge of the map
+  pub camera_complements: [u32; 4],
+  pub playable_width: u32,
+  pub playable_height: u32,
+  /// Map flags, the same as in the [`MapHeader`]
+  pub flags: u32,
+  /// Tileset character, such as `L` for Lordaeron Summer
+  pub tileset: u8,
+  /// Campaign background of Reign of Chaos maps, `-1` for none
+  pub campaign_background: i32,
+  pub loading_screen: LoadingScreen,
+  pub prologue: Prologue,
+  /// Game data set, `0` for the standard one, since The Frozen Throne
+  pub game_data_set: u32,
+  /// Game data of Reign of Chaos (`0`) or The Frozen Throne (`1`), since Reforged
+  pub game_data_version: u32,
+  /// Since The Frozen Throne
+  pub fog: Fog,
+  /// Global weather id, all zeros for none
+  pub weather: [u8; 4],
+  pub sound_environment: Vec<u8>,
+  /// Tileset character of a custom light environment, `0` for the default
+  pub light_environment: u8,
+  /// Water tint, red, green, blue and alpha
+  pub water_color: [u8; 4],
+  /// `0` for JASS and `1` for Lua, since 1.31
+  pub script_language: u32,
+  /// `1` for SD, `2` for HD and `3` for both, since Reforged
+  pub supported_modes: u32,
+  pub players: Vec<Player>,
+  pub forces: Vec<Force>,
+  pub upgrades: Vec<UpgradeAvailability>,
+  pub tech: Vec<TechAvailability>,
+  pub random_unit_tables: Vec<RandomUnitTable>,
+  /// Since The Frozen Throne
+  pub random_item_tables: Vec<RandomItemTable>,
+  /// Data after the known fields, written back as it is
+  pub extra: Vec<u8>,
+}
+
+/// Loading screen of a map
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LoadingScreen {
+  /// Index of a preset loading screen, `-1` for none or a custom one
+  pub number: i32,
+  /// Path of a custom loading screen model, since The Frozen Throne
+  pub model: Vec<u8>,
+  pub text: Vec<u8>,
+  pub title: Vec<u8>,
+  pub subtitle: Vec<u8>,
+}
+
+/// Prologue screen of a map
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Prologue {
+  /// Path of the prologue screen model, since The Frozen Throne
+  pub model: Vec<u8>,
+  pub text: Vec<u8>,
+  pub title: Vec<u8>,
+  pub subtitle: Vec<u8>,
+}
+
+/// Terrain fog of a map
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Fog {
+  /// Fog style, `0` if the map doesn't use fog
+  pub style: u32,
+  pub z_start: f32,
+  pub z_end: f32,
+  pub density: f32,
+  /// Red, green, blue and alpha
+  pub color: [u8; 4],
+}
+
+/// Player slot
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Player {
+  pub number: u32,
+  /// `1` for human, `2` for computer, `3` for neutral and `4` for rescuable
+  pub controller: u32,
+  /// `1` for human, `2` for orc, `3` for undead and `4` for night elf
+  pub race: u32,
+  pub fixed_start: bool,
+  pub name: Vec<u8>,
+  pub start_x: f32,
+  pub start_y: f32,
+  /// Bit mask of the players with low ally priority
+  pub ally_low_priorities: u32,
+  pub ally_high_priorities: u32,
+  /// Since Reforged
+  pub enemy_low_priorities: u32,
+  pub enemy_high_priorities: u32,
+}
+
+/// Team of players
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Force {
+  /// `FORCE_*` flags
+  pub flags: u32,
+  /// Bit mask of the players in the force
+  pub players: u32,
+  pub name: Vec<u8>,
+}
+
+/// Force is allied
+pub const FORCE_ALLIED: u32 = 0x01;
+/// Force wins together
+pub const FORCE_ALLIED_VICTORY: u32 = 0x02;
+pub const FORCE_SHARE_VISION: u32 = 0x04;
+pub const FORCE_SHARE_UNIT_CONTROL: u32 = 0x10;
+pub const FORCE_SHARE_ADVANCED_UNIT_CONTROL: u32 = 0x20;
+
+/// Changed availability of an upgrade level
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UpgradeAvailability {
+  /// Bit mask of the players the change applies to
+  pub players: u32,
+  pub id: [u8; 4],
+  /// Level of the upgrade, starting at `0`
+  pub level: u32,
+  /// `0` for unavailable, `1` for available and `2` for researched
+  pub availability: u32,
+}
+
+/// Unit, item or ability made unavailable
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TechAvailability {
+  /// Bit mask of the players the change applies to
+  pub players: u32,
+  pub id: [u8; 4],
+}
+
+/// Random unit group
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RandomUnitTable {
+  pub number: i32,
+  pub name: Vec<u8>,
+  /// Kind of every column, `0` for units, `1` for buildings and `2` for items
+  pub columns: Vec<u32>,
+  pub rows: Vec<RandomUnitRow>,
+}
+
+/// Row of a [`RandomUnitTable`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RandomUnitRow {
+  /// Chance of the row in percent
+  pub chance: u32,
+  /// Id of every column
+  pub ids: Vec<[u8; 4]>,
+}
+
+/// Random item table, one item is dropped from every set
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RandomItemTable {
+  pub number: i32,
+  pub name: Vec<u8>,
+  pub sets: Vec<Vec<RandomItem>>,
+}
+
+/// Item of a [`RandomItemTable`] set
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RandomItem {
+  /// Chance of the item in percent
+  pub chance: u32,
+  pub id: [u8; 4],
+}
+
+impl MapInfo {
+  /// Parses a `war3map.w3i`. Fails with [`StormError::NotSupported`] for unknown format
+  /// versions and [`StormError::BadFormat`] if the data ends early.
+  pub fn parse(data: &[u8]) -> Result<Self> {
+    let mut r = Reader::new(data);
+    let mut info = MapInfo {
+      version: r.u32()?,
+      ..Default::default()
+    };
+    let version = info.version;
+    check_version(version)?;
+    let tft = version >= W3I_VERSION_TFT;
+
+    info.saves = r.u32()?;
+    info.editor_version = r.u32()?;
+    if version >= W3I_VERSION_131 {
+      for part in &mut info.game_version {
+        *part = r.u32()?;
+      }
+    }
+    info.name = r.string()?;
+    info.author = r.string()?;
+    info.description = r.string()?;
+    info.players_recommended = r.string()?;
+    for bound in &mut info.camera_bounds {
+      *bound = r.f32()?;
+    }
+    for complement in &mut info.camera_complements {
+      *complement = r.u32()?;
+    }
+    info.playable_width = r.u32()?;
+    info.playable_height = r.u32()?;
+    info.flags = r.u32()?;
+    info.tileset = r.u8()?;
+
+    if tft {
+      info.loading_screen.number = r.i32()?;
+      info.loading_screen.model = r.string()?;
+    } else {
+      info.campaign_background = r.i32()?;
+    }
+    info.loading_screen.text = r.string()?;
+    info.loading_screen.title = r.string()?;
+    info.loading_screen.subtitle = r.string()?;
+    if tft {
+      info.game_data_set = r.u32()?;
+      info.prologue.model = r.string()?;
+    } else {
+      info.loading_screen.number = r.i32()?;
+    }
+    info.prologue.text = r.string()?;
+    info.prologue.title = r.string()?;
+    info.prologue.subtitle = r.string()?;
+
+    if tft {
+      info.fog = Fog {
+        style: r.u32()?,
+        z_start: r.f32()?,
+        z_end: r.f32()?,
+        density: r.f32()?,
+        color: r.array()?,
+      };
+      info.weather = r.id()?;
+      info.sound_environment = r.string()?;
+      info.light_environment = r.u8()?;
+      info.water_color = r.array()?;
+    }
+    if version >= W3I_VERSION_131 {
+      info.script_language = r.u32()?;
+    }
+    if version >= W3I_VERSION_REFORGED {
+      info.supported_modes = r.u32()?;
+      info.game_data_version = r.u32()?;
+    }
+
+    info.players = r.list(|r| {
+      let mut player = Player {
+        number: r.u32()?,
+        controller: r.u32()?,
+        race: r.u32()?,
+        fixed_start: r.u32()? != 0,
+        name: r.string()?,
+        start_x: r.f32()?,
+        start_y: r.f32()?,
+        ally_low_priorities: r.u32()?,
+        ally_high_priorities: r.u32()?,
+        ..Default::default()
+      };
+      if version >= W3I_VERSION_REFORGED {
+        player.enemy_low_priorities = r.u32()?;
+        player.enemy_high_priorities = r.u32()?;
+      }
+      Ok(player)
+    })?;
+    info.forces = r.list(|r| {
+      Ok(Force {
+        flags: r.u32()?,
+        players: r.u32()?,
+        name: r.string()?,
+      })
+    })?;
+    info.upgrades = r.list(|r| {
+      Ok(UpgradeAvailability {
+        players: r.u32()?,
+        id: r.id()?,
+        level: r.u32()?,
+        availability: r.u32()?,
+      })
+    })?;
+    info.tech = r.list(|r| {
+      Ok(TechAvailability {
+        players: r.u32()?,
+        id: r.id()?,
+      })
+    })?;
+    info.random_unit_tables = r.list(|r| {
+      let number = r.i32()?;
+      let name = r.string()?;
+      let columns = r.list(|r| r.u32())?;
+      let rows = r.list(|r| {
+        Ok(RandomUnitRow {
+          chance: r.u32()?,
+          ids: (0..columns.len()).map(|_| r.id()).collect::<Result<_>>()?,
+        })
+      })?;
+      Ok(RandomUnitTable {
+        number,
+        name,
+        columns,
+        rows,
+      })
+    })?;
+    if tft {
+      info.random_item_tables = r.list(|r| {
+        Ok(RandomItemTable {
+          number: r.i32()?,
+          name: r.string()?,
+          sets: r.list(|r| {
+            r.list(|r| {
+              Ok(RandomItem {
+                chance: r.u32()?,
+                id: r.id()?,
+              })
+            })
+          })?,
+        })
+      })?;
+    }
+    info.extra = r.rest().to_vec();
+    Ok(info)
+  }
+
+  /// Encodes the map info in its format version. Fails with [`StormError::NotSupported`] for
+  /// unknown format versions and [`StormError::InvalidParameter`] if a string contains a nul
+  /// byte or a row of a random unit table doesn't have an id for every column.
+  pub fn to_bytes(&self) -> Result<Vec<u8>> {
+    let version = self.version;
+    check_version(version)?;
+    let tft = version >= W3I_VERSION_TFT;
+    let mut buf = vec![];
+    let b = &mut buf;
+
+    put_u32(b, version);
+    put_u32(b, self.saves);
+    put_u32(b, self.editor_version);
+    if version >= W3I_VERSION_131 {
+      for &part in &self.game_version {
+        put_u32(b, part);
+      }
+    }
+    put_string(b, &self.name)?;
+    put_string(b, &self.author)?;
+    put_string(b, &self.description)?;
+    put_string(b, &self.players_recommended)?;
+    for &bound in &self.camera_bounds {
+      put_f32(b, bound);
+    }
+    for &complement in &self.camera_complements {
+      put_u32(b, complement);
+    }
+    put_u32(b, self.playable_width);
+    put_u32(b, self.playable_height);
+    put_u32(b, self.flags);
+    b.push(self.tileset);
+
+    let loading_screen = &self.loading_screen;
+    if tft {
+      put_i32(b, loading_screen.number);
+      put_string(b, &loading_screen.model)?;
+    } else {
+      put_i32(b, self.campaign_background);
+    }
+    put_string(b, &loading_screen.text)?;
+    put_string(b, &loading_screen.title)?;
+    put_string(b, &loading_screen.subtitle)?;
+    if tft {
+      put_u32(b, self.game_data_set);
+      put_string(b, &self.prologue.model)?;
+    } else {
+      put_i32(b, loading_screen.number);
+    }
+    put_string(b, &self.prologue.text)?;
+    put_string(b, &self.prologue.title)?;
+    put_string(b, &self.prologue.subtitle)?;
+
+    if tft {
+      put_u32(b, self.fog.style);
+      put_f32(b, self.fog.z_start);
+      put_f32(b, self.fog.z_end);
+      put_f32(b, self.fog.density);
+      b.extend_from_slice(&self.fog.color);
+      b.extend_from_slice(&self.weather);
+      put_string(b, &self.sound_environment)?;
+      b.push(self.light_environment);
+      b.extend_from_slice(&self.water_color);
+    }
+    if version >= W3I_VERSION_131 {
+      put_u32(b, self.script_language);
+    }
+    if version >= W3I_VERSION_REFORGED {
+      put_u32(b, self.supported_modes);
+      put_u32(b, self.game_data_version);
+    }
+
+    put_list(b, &self.players, |b, player| {
+      put_u32(b, player.number);
+      put_u32(b, player.controller);
+      put_u32(b, player.race);
+      put_u32(b, player.fixed_start as u32);
+      put_string(b, &player.name)?;
+      put_f32(b, player.start_x);
+      put_f32(b, player.start_y);
+      put_u32(b, player.ally_low_priorities);
+      put_u32(b, player.ally_high_priorities);
+      if version >= W3I_VERSION_REFORGED {
+        put_u32(b, player.enemy_low_priorities);
+        put_u32(b, player.enemy_high_priorities);
+      }
+      Ok(())
+    })?;
+    put_list(b, &self.forces, |b, force| {
+      put_u32(b, force.flags);
+      put_u32(b, force.players);
+      put_string(b, &force.name)
+    })?;
+    put_list(b, &self.upgrades, |b, upgrade| {
+      put_u32(b, upgrade.players);
+      b.extend_from_slice(&upgrade.id);
+      put_u32(b, upgrade.level);
+      put_u32(b, upgrade.availability);
+      Ok(())
+    })?;
+    put_list(b, &self.tech, |b, tech| {
+      put_u32(b, tech.players);
+      b.extend_from_slice(&tech.id);
+      Ok(())
+    })?;
+    put_list(b, &self.random_unit_tables, |b, table| {
+      put_i32(b, table.number);
+      put_string(b, &table.name)?;
+      put_list(b, &table.columns, |b, &column| {
+        put_u32(b, column);
+        Ok(())
+      })?;
+      put_list(b, &table.rows, |b, row| {
+        if row.ids.len() != table.columns.len() {
+          return Err(StormError::InvalidParameter);
+        }
+        put_u32(b, row.chance);
+        row.ids.iter().for_each(|id| b.extend_from_slice(id));
+        Ok(())
+      })
+    })?;
+    if tft {
+      put_list(b, &self.random_item_tables, |b, table| {
+        put_i32(b, table.number);
+        put_string(b, &table.name)?;
+        put_list(b, &table.sets, |b, set| {
+          put_list(b, set, |b, item| {
+            put_u32(b, item.chance);
+            b.extend_from_slice(&item.id);
+            Ok(())
+          })
+        })
+      })?;
+    }
+    b.extend_from_slice(&self.extra);
+    Ok(buf)
+  }
+}
+
+impl MapInfo {
+  /// Text fields that can be translated
+  pub(crate) fn texts_mut(&mut self) -> Vec<&mut Vec<u8>> {
+    let mut texts = vec![
+      &mut self.name,
+      &mut self.author,
+      &mut self.description,
+      &mut self.players_recommended,
+      &mut self.loading_screen.text,
+      &mut self.loading_screen.title,
+      &mut self.loading_screen.subtitle,
+      &mut self.prologue.text,
+      &mut self.prologue.title,
+      &mut self.prologue.subtitle,
+    ];
+    texts.extend(self.players.iter_mut().map(|player| &mut player.name));
+    texts.extend(self.forces.iter_mut().map(|force| &mut force.name));
+    texts
+  }
+}
+
+fn check_version(version: u32) -> Result<()> {
+  match version {
+    W3I_VERSION_ROC | W3I_VERSION_TFT | W3I_VERSION_131 | W3I_VERSION_REFORGED => Ok(()),
+    _ => Err(StormError::NotSupported),
+  }
+}
+
+impl<M: ArchiveMode> Archive<M> {
+  /// Reads and parses the `war3map.w3i` of a Warcraft III map
+  pub fn map_info(&self) -> Result<MapInfo> {
+    let data = self.open_file(MAP_INFO_FILE)?.read_all()?;
+    MapInfo::parse(&data)
+  }
+}
+
+#[test]
+fn test_map_info() {
+  let archive = Archive::open(
+    "../../samples/test_tft.w3x",
+    OpenArchiveFlags::MPQ_OPEN_NO_LISTFILE | OpenArchiveFlags::MPQ_OPEN_NO_ATTRIBUTES,
+  )
+  .unwrap();
+  let data = archive
+    .open_file(MAP_INFO_FILE)
+    .unwrap()
+    .read_all()
+    .unwrap();
+  let info = archive.map_info().unwrap();
+  assert_eq!(info.version, W3I_VERSION_TFT);
+  assert_eq!(info.saves, 14);
+  assert_eq!(info.name, b"TRIGSTR_004");
+  assert_eq!(info.author, b"TRIGSTR_007");
+  assert_eq!(info.flags, archive.map_header().unwrap().unwrap().flags);
+  assert_eq!(
+    (info.playable_width, info.playable_height, info.tileset),
+    (30, 26, b'L')
+  );
+  assert_eq!(info.loading_screen.number, -1);
+  assert_eq!((info.fog.z_start, info.fog.z_end), (3000.0, 5000.0));
+  assert_eq!(info.players.len(), 2);
+  assert_eq!((info.players[1].controller, info.players[1].race), (2, 4));
+  assert_eq!(info.players[1].name, b"TRIGSTR_003");
+  assert_eq!(info.forces.len(), 1);
+  assert_eq!(info.forces[0].players, u32::MAX);
+  assert!(info.extra.is_empty());
+  assert_eq!(info.to_bytes().unwrap(), data);
+
+  // The same map in the other format versions
+  for version in [W3I_VERSION_ROC, W3I_VERSION_131, W3I_VERSION_REFORGED] {
+    let mut info = info.clone();
+    info.version = version;
+    info.game_version = [1, 32, 10, 18820];
+    info.players[0].enemy_high_priorities = 2;
+    info.random_unit_tables.push(RandomUnitTable {
+      number: 0,
+      name: b"Creeps".to_vec(),
+      columns: vec![0, 2],
+      rows: vec![RandomUnitRow {
+        chance: 100,
+        ids: vec![*b"hfoo", *b"ratc"],
+      }],
+    });
+    let parsed = MapInfo::parse(&info.to_bytes().unwrap()).unwrap();
+    assert_eq!(parsed.to_bytes().unwrap(), info.to_bytes().unwrap());
+    assert_eq!(parsed.random_unit_tables, info.random_unit_tables);
+    assert_eq!(parsed.name, info.name);
+  }
+
+  let mut info = info;
+  info.random_unit_tables.push(RandomUnitTable {
+    columns: vec![0],
+    rows: vec![RandomUnitRow::default()],
+    ..Default::default()
+  });
+  assert!(matches!(info.to_bytes(), Err(StormError::InvalidParameter)));
+  assert!(matches!(
+    MapInfo::parse(&data[..data.len() - 1]),
+    Err(StormError::BadFormat)
+  ));
+}
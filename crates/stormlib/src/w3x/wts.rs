@@ -0,0 +1,591 @@
+//! The `war3map.wts` trigger strings
+//!
+//! Strings of the map info, object data and script can be stored as `TRIGSTR_004` references
+//! to the numbered strings of the `war3map.wts`, which is what gets translated:
+//!
+//! ```text
+//! STRING 4
+//! // optional comment
+//! {
+//! Small Wars
+//! }
+//! ```
+
+use super::w3i::MAP_INFO_FILE;
+use super::*;
+use crate::{CompressionFlags, CreateFileFlags, CreateFileOptions, MpqPath, MpqPathBuf};
+
+/// Name of the trigger strings file in the map archive
+pub const STRINGS_FILE: &str = "war3map.wts";
+
+const REFERENCE_PREFIX: &[u8] = b"TRIGSTR_";
+const BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// Map files binary references are read from, the map info and object data
+const BINARY_FILES: &[&str] = &[
+  "war3map.w3i",
+  "war3map.w3u",
+  "war3map.w3t",
+  "war3map.w3b",
+  "war3map.w3d",
+  "war3map.w3a",
+  "war3map.w3h",
+  "war3map.w3q",
+  "war3mapSkin.w3u",
+  "war3mapSkin.w3t",
+  "war3mapSkin.w3b",
+  "war3mapSkin.w3d",
+  "war3mapSkin.w3a",
+  "war3mapSkin.w3h",
+  "war3mapSkin.w3q",
+];
+
+const SCRIPT_FILES: &[&str] = &["war3map.j", "scripts\\war3map.j", "war3map.lua"];
+
+/// Trigger editor files, they are only checked for references and never rewritten
+const TRIGGER_FILES: &[&str] = &["war3map.wtg", "war3map.wct"];
+
+/// Parsed `war3map.wts`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TriggerStrings {
+  /// Strings in the order of the file
+  pub strings: Vec<TriggerString>,
+}
+
+/// Numbered string of a [`TriggerStrings`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TriggerString {
+  pub id: u32,
+  /// Comment lines between the `STRING` line and the text, with the leading `//`
+  pub comments: Vec<Vec<u8>>,
+  pub text: Vec<u8>,
+}
+
+/// How a map file stores references
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReferenceKind {
+  /// Nul terminated strings of the map info and object data
+  Binary,
+  /// String literals of a JASS or Lua script
+  Script,
+}
+
+impl ReferenceKind {
+  /// Kind of references in map file `name`, `None` for files that don't have any
+  pub fn of<N: AsRef<MpqPath>>(name: N) -> Option<Self> {
+    let name = name.as_ref();
+    let is_any = |files: &[&str]| files.iter().any(|file| MpqPath::new(file) == name);
+    if is_any(BINARY_FILES) {
+      Some(ReferenceKind::Binary)
+    } else if is_any(SCRIPT_FILES) {
+      Some(ReferenceKind::Script)
+    } else {
+      None
+    }
+  }
+}
+
+impl TriggerStrings {
+  pub fn new() -> Self {
+    Default::default()
+  }
+
+  /// Parses a `war3map.wts`, with or without a byte order mark. Fails with
+  /// [`StormError::BadFormat`] on anything but strings and blank lines.
+  pub fn parse(data: &[u8]) -> Result<Self> {
+    let data = data.strip_prefix(BOM).unwrap_or(data);
+    let mut lines = Lines { data };
+    let mut strings = vec![];
+    while let Some(line) = lines.next() {
+      let line = trim(line);
+      if line.is_empty() {
+        continue;
+      }
+      let id = line
+        .strip_prefix(b"STRING")
+        .and_then(|id| std::str::from_utf8(id).ok())
+        .and_then(|id| id.trim().parse().ok())
+        .ok_or(StormError::BadFormat)?;
+      let mut comments = vec![];
+      loop {
+        let line = trim(lines.next().ok_or(StormError::BadFormat)?);
+        if line == b"{" {
+          break;
+        }
+        if !line.starts_with(b"//") {
+          return Err(StormError::BadFormat);
+        }
+        comments.push(line.to_vec());
+      }
+      let start = lines.data;
+      let end = loop {
+        let rest = lines.data;
+        let line = lines.next().ok_or(StormError::BadFormat)?;
+        if trim(line) == b"}" {
+          break start.len() - rest.len();
+        }
+      };
+      strings.push(TriggerString {
+        id,
+        comments,
+        // The line ending before the `}` isn't part of the text
+        text: trim(&start[..end]).to_vec(),
+      });
+    }
+    Ok(TriggerStrings { strings })
+  }
+
+  /// Encodes the strings the way the editor does, with a byte order mark and `\r\n` line
+  /// endings
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let mut buf = BOM.to_vec();
+    for string in &self.strings {
+      buf.extend_from_slice(format!("STRING {}\r\n", string.id).as_bytes());
+      for comment in &string.comments {
+        buf.extend_from_slice(comment);
+        buf.extend_from_slice(b"\r\n");
+      }
+      buf.extend_from_slice(b"{\r\n");
+      buf.extend_from_slice(&string.text);
+      buf.extend_from_slice(b"\r\n}\r\n\r\n");
+    }
+    buf
+  }
+
+  /// Text of string `id`
+  pub fn get(&self, id: u32) -> Option<&[u8]> {
+    self
+      .strings
+      .iter()
+      .find(|string| string.id == id)
+      .map(|string| string.text.as_slice())
+  }
+
+  /// Adds `text` as a new string and returns its id, one past the highest id
+  pub fn insert<T: Into<Vec<u8>>>(&mut self, text: T) -> u32 {
+    let id = self.strings.iter().map(|string| string.id + 1).max();
+    let id = id.unwrap_or(0);
+    self.strings.push(TriggerString {
+      id,
+      comments: vec![],
+      text: text.into(),
+    });
+    id
+  }
+
+  /// Replaces the references in `data` with their text. References to missing strings are
+  /// kept. Returns the new data and the number of replaced references.
+  pub fn resolve(&self, data: &[u8], kind: ReferenceKind) -> (Vec<u8>, usize) {
+    let mut count = 0;
+    let data = replace_references(data, kind, |id| {
+      let text = self.get(id)?;
+      count += 1;
+      Some(text.to_vec())
+    });
+    (data, count)
+  }
+}
+
+/// `TRIGSTR_` reference to string `id`, numbered with at least 3 digits like the editor does
+pub fn reference(id: u32) -> Vec<u8> {
+  format!("TRIGSTR_{:03}", id).into_bytes()
+}
+
+/// Ids of the references in `data`, in order
+pub fn references(data: &[u8], kind: ReferenceKind) -> Vec<u32> {
+  let mut ids = vec![];
+  replace_references(data, kind, |id| {
+    ids.push(id);
+    None
+  });
+  ids
+}
+
+/// Replaces every reference in `data` with the text `replace` returns for its id, references
+/// it returns `None` for are kept. Text replacing a script reference is escaped for the string
+/// literal.
+pub fn replace_references(
+  data: &[u8],
+  kind: ReferenceKind,
+  mut replace: impl FnMut(u32) -> Option<Vec<u8>>,
+) -> Vec<u8> {
+  let mut buf = Vec::with_capacity(data.len());
+  let mut copied = 0;
+  let mut pos = 0;
+  while let Some(found) = find(&data[pos..], REFERENCE_PREFIX) {
+    let start = pos + found;
+    let digits = data[start + REFERENCE_PREFIX.len()..]
+      .iter()
+      .take_while(|c| c.is_ascii_digit())
+      .count();
+    let end = start + REFERENCE_PREFIX.len() + digits;
+    pos = end;
+    let id = std::str::from_utf8(&data[start + REFERENCE_PREFIX.len()..end])
+      .ok()
+      .and_then(|id| id.parse().ok());
+    let terminated = match kind {
+      ReferenceKind::Binary => data.get(end) == Some(&0),
+      ReferenceKind::Script => start > 0 && data[start - 1] == b'"' && data.get(end) == Some(&b'"'),
+    };
+    let text = match id {
+      Some(id) if terminated => replace(id),
+      _ => None,
+    };
+    if let Some(text) = text {
+      buf.extend_from_slice(&data[copied..start]);
+      match kind {
+        ReferenceKind::Binary => buf.extend_from_slice(&text),
+        ReferenceKind::Script => buf.extend_from_slice(&escape(&text)),
+      }
+      copied = end;
+    }
+  }
+  buf.extend_from_slice(&data[copied..]);
+  buf
+}
+
+/// Escapes `text` for a JASS or Lua string literal
+fn escape(text: &[u8]) -> Vec<u8> {
+  let mut buf = Vec::with_capacity(text.len());
+  for &c in text {
+    match c {
+      b'\\' => buf.extend_from_slice(b"\\\\"),
+      b'"' => buf.extend_from_slice(b"\\\""),
+      b'\r' => buf.extend_from_slice(b"\\r"),
+      b'\n' => buf.extend_from_slice(b"\\n"),
+      _ => buf.push(c),
+    }
+  }
+  buf
+}
+
+fn find(data: &[u8], needle: &[u8]) -> Option<usize> {
+  data
+    .windows(needle.len())
+    .position(|window| window == needle)
+}
+
+fn trim(line: &[u8]) -> &[u8] {
+  let line = line.strip_suffix(b"\n").unwrap_or(line);
+  line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+/// Lines including their line ending
+struct Lines<'a> {
+  data: &'a [u8],
+}
+
+impl<'a> Iterator for Lines<'a> {
+  type Item = &'a [u8];
+
+  fn next(&mut self) -> Option<&'a [u8]> {
+    if self.data.is_empty() {
+      return None;
+    }
+    let len = self
+      .data
+      .iter()
+      .position(|&c| c == b'\n')
+      .map_or(self.data.len(), |pos| pos + 1);
+    let (line, rest) = self.data.split_at(len);
+    self.data = rest;
+    Some(line)
+  }
+}
+
+impl<M: ArchiveMode> Archive<M> {
+  /// Reads and parses the `war3map.wts` of a Warcraft III map
+  pub fn trigger_strings(&self) -> Result<TriggerStrings> {
+    let data = self.open_file(STRINGS_FILE)?.read_all()?;
+    TriggerStrings::parse(&data)
+  }
+}
+
+impl Archive {
+  /// Replaces the references in the map info, object data and script with their text. The
+  /// `war3map.wts` is removed once none of these files and neither the `war3map.wtg` nor the
+  /// `war3map.wct` of the trigger editor reference it. Returns the number of replaced
+  /// references, fails with [`StormError::FileNotFound`] if the map has no `war3map.wts`.
+  pub fn inline_trigger_strings(&mut self) -> Result<usize> {
+    let strings = self.trigger_strings()?;
+    let mut count = 0;
+    let mut remaining = 0;
+    for (name, kind) in self.reference_files()? {
+      let data = self.open_file(&name)?.read_all()?;
+      let (resolved, replaced) = strings.resolve(&data, kind);
+      remaining += references(&resolved, kind).len();
+      if replaced > 0 {
+        self.replace_file(&name, &resolved)?;
+        count += replaced;
+      }
+    }
+    for name in TRIGGER_FILES {
+      if self.has_file(name)? && has_references(&self.open_file(name)?.read_all()?) {
+        remaining += 1;
+      }
+    }
+    if remaining == 0 {
+      self.remove_file(STRINGS_FILE)?;
+    }
+    Ok(count)
+  }
+
+  /// Moves the text of the map info, its name, author, description, loading screen, prologue,
+  /// player and force names, to new strings of the `war3map.wts` so it can be translated.
+  /// Script string literals with the same text are replaced with the same references. Only
+  /// the map info is extracted, the strings of the object data are left as they are. Returns
+  /// the number of added strings.
+  pub fn extract_map_info_strings(&mut self) -> Result<usize> {
+    let mut strings = if self.has_file(STRINGS_FILE)? {
+      self.trigger_strings()?
+    } else {
+      TriggerStrings::new()
+    };
+    let mut info = self.map_info()?;
+    let mut extracted: Vec<(Vec<u8>, u32)> = vec![];
+    for text in info.texts_mut() {
+      if text.is_empty() || is_reference(text) {
+        continue;
+      }
+      let id = match extracted.iter().find(|(extracted, _)| extracted == text) {
+        Some(&(_, id)) => id,
+        None => {
+          let id = strings.insert(text.clone());
+          extracted.push((text.clone(), id));
+          id
+        }
+      };
+      *text = reference(id);
+    }
+    if extracted.is_empty() {
+      return Ok(0);
+    }
+    self.replace_file(MpqPath::new(MAP_INFO_FILE), &info.to_bytes()?)?;
+
+    for (name, kind) in self.reference_files()? {
+      if kind != ReferenceKind::Script {
+        continue;
+      }
+      let mut data = self.open_file(&name)?.read_all()?;
+      for (text, id) in &extracted {
+        let literal = [&b"\""[..], &escape(text), b"\""].concat();
+        let replacement = [&b"\""[..], &reference(*id), b"\""].concat();
+        data = replace_all(&data, &literal, &replacement);
+      }
+      self.replace_file(&name, &data)?;
+    }
+    self.replace_file(MpqPath::new(STRINGS_FILE), &strings.to_bytes())?;
+    Ok(extracted.len())
+  }
+
+  /// Map files that can have references, with their kind. The files are looked up by name,
+  /// maps often have no listfile to find them with.
+  fn reference_files(&self) -> Result<Vec<(MpqPathBuf, ReferenceKind)>> {
+    let mut files = vec![];
+    for (names, kind) in [
+      (BINARY_FILES, ReferenceKind::Binary),
+      (SCRIPT_FILES, ReferenceKind::Script),
+    ] {
+      for name in names {
+        if self.has_file(name)? {
+          files.push((MpqPath::new(name).to_mpq_path_buf(), kind));
+        }
+      }
+    }
+    Ok(files)
+  }
+
+  /// Replaces file `name` with `data`, keeping its flags, compression and file time. New
+  /// files are compressed with zlib.
+  fn replace_file(&mut self, name: &MpqPath, data: &[u8]) -> Result<()> {
+    let file = match self.open_file(name) {
+      Ok(file) => Some(file),
+      Err(StormError::FileNotFound) => None,
+      Err(err) => return Err(err),
+    };
+    let (flags, compression, file_time) = match file {
+      Some(mut file) => {
+        let raw = file.read_raw()?;
+        (
+          CreateFileFlags::from_bits_truncate(raw.flags() & !MPQ_FILE_EXISTS),
+          CompressionFlags::from_bits_truncate(raw.compression()?),
+          file.file_time()?,
+        )
+      }
+      None => (
+        CreateFileFlags::MPQ_FILE_COMPRESS,
+        CompressionFlags::MPQ_COMPRESSION_ZLIB,
+        0,
+      ),
+    };
+    self.create_file(CreateFileOptions {
+      path: name,
+      data: &data.to_vec(),
+      flags: flags | CreateFileFlags::MPQ_FILE_REPLACEEXISTING,
+      mtime: file_time,
+      compression,
+    })
+  }
+}
+
+/// Whether `data` has anything that looks like a reference, whatever stores it
+fn has_references(data: &[u8]) -> bool {
+  let mut pos = 0;
+  while let Some(found) = find(&data[pos..], REFERENCE_PREFIX) {
+    pos += found + REFERENCE_PREFIX.len();
+    if data.get(pos).is_some_and(u8::is_ascii_digit) {
+      return true;
+    }
+  }
+  false
+}
+
+/// Whether `text` is a single reference
+fn is_reference(text: &[u8]) -> bool {
+  text
+    .strip_prefix(REFERENCE_PREFIX)
+    .is_some_and(|id| !id.is_empty() && id.iter().all(u8::is_ascii_digit))
+}
+
+fn replace_all(data: &[u8], from: &[u8], to: &[u8]) -> Vec<u8> {
+  let mut buf = Vec::with_capacity(data.len());
+  let mut pos = 0;
+  while let Some(found) = find(&data[pos..], from) {
+    buf.extend_from_slice(&data[pos..pos + found]);
+    buf.extend_from_slice(to);
+    pos += found + from.len();
+  }
+  buf.extend_from_slice(&data[pos..]);
+  buf
+}
+
+#[test]
+fn test_trigger_strings() {
+  let archive = Archive::open(
+    "../../samples/test_tft.w3x",
+    OpenArchiveFlags::MPQ_OPEN_NO_LISTFILE | OpenArchiveFlags::MPQ_OPEN_NO_ATTRIBUTES,
+  )
+  .unwrap();
+  let data = archive.open_file(STRINGS_FILE).unwrap().read_all().unwrap();
+  let strings = archive.trigger_strings().unwrap();
+  assert_eq!(strings.strings.len(), 7);
+  assert_eq!(strings.get(4), Some(&b"Small Wars"[..]));
+  assert_eq!(strings.get(6), None);
+  assert_eq!(strings.to_bytes(), data);
+
+  let script = std::fs::read("../../samples/war3map.j").unwrap();
+  assert_eq!(references(&script, ReferenceKind::Script), [4, 8]);
+  let (resolved, count) = strings.resolve(&script, ReferenceKind::Script);
+  assert_eq!(count, 2);
+  let resolved = String::from_utf8(resolved).unwrap();
+  assert!(resolved.contains("call SetMapName( \"Small Wars\" )"));
+  // Comments aren't string literals
+  assert!(resolved.contains("// Force: TRIGSTR_002"));
+
+  let mut edited = strings.clone();
+  let id = edited.insert("Say \"hi\"\r\nC:\\");
+  assert_eq!(id, 9);
+  let (resolved, _) = edited.resolve(b"call Print(\"TRIGSTR_009\")", ReferenceKind::Script);
+  assert_eq!(resolved, b"call Print(\"Say \\\"hi\\\"\\r\\nC:\\\\\")");
+  let (resolved, _) = edited.resolve(b"TRIGSTR_9\0TRIGSTR_0090", ReferenceKind::Binary);
+  assert_eq!(resolved, b"Say \"hi\"\r\nC:\\\0TRIGSTR_0090");
+  assert_eq!(TriggerStrings::parse(&edited.to_bytes()).unwrap(), edited);
+  assert!(matches!(
+    TriggerStrings::parse(b"STRING 1\r\n{\r\nunterminated"),
+    Err(StormError::BadFormat)
+  ));
+}
+
+#[test]
+fn test_inline_trigger_strings() {
+  let path = "../../samples/test_inline_trigger_strings.w3x";
+
+  let result = std::panic::catch_unwind(|| {
+    fs::copy("../../samples/test_tft.w3x", path).unwrap();
+    let mut archive = Archive::open(path, OpenArchiveFlags::empty()).unwrap();
+    let info = archive.map_info().unwrap();
+    assert_eq!(archive.inline_trigger_strings().unwrap(), 9);
+    assert!(!archive.has_file(STRINGS_FILE).unwrap());
+    let inlined = archive.map_info().unwrap();
+    assert_eq!(inlined.name, b"Small Wars");
+    assert_eq!(inlined.players[1].name, b"Player 2");
+    assert_eq!(inlined.forces[0].name, b"Force 1");
+    let script = archive.open_file("war3map.j").unwrap().read_all().unwrap();
+    assert!(references(&script, ReferenceKind::Script).is_empty());
+
+    // Every distinct text gets a string, the script uses the same references
+    assert_eq!(archive.extract_map_info_strings().unwrap(), 7);
+    assert_eq!(archive.extract_map_info_strings().unwrap(), 0);
+    archive.flush().unwrap();
+    let extracted = archive.map_info().unwrap();
+    let strings = archive.trigger_strings().unwrap();
+    assert_eq!(strings.get(0), Some(&b"Small Wars"[..]));
+    assert_eq!(extracted.name, b"TRIGSTR_000");
+    let script = archive.open_file("war3map.j").unwrap().read_all().unwrap();
+    assert_eq!(references(&script, ReferenceKind::Script), [0, 2]);
+
+    archive.inline_trigger_strings().unwrap();
+    assert_eq!(archive.map_info().unwrap(), inlined);
+    assert_ne!(archive.map_info().unwrap(), info);
+    archive.close().unwrap();
+  });
+
+  // Clean up
+  let _ = fs::remove_file(path);
+
+  // Propagate any panic that occurred during the test
+  result.unwrap();
+}
+
+#[test]
+fn test_inline_trigger_strings_without_listfile() {
+  let path = "../../samples/test_inline_trigger_strings_without_listfile.w3x";
+
+  let result = std::panic::catch_unwind(|| {
+    fs::copy("../../samples/test_tft.w3x", path).unwrap();
+    let mut archive = Archive::open(path, OpenArchiveFlags::MPQ_OPEN_NO_LISTFILE).unwrap();
+    let flags = archive
+      .open_file("war3map.j")
+      .unwrap()
+      .read_raw()
+      .unwrap()
+      .flags();
+
+    // The trigger editor still references the strings
+    let triggers = archive
+      .open_file("war3map.wtg")
+      .unwrap()
+      .read_all()
+      .unwrap();
+    let write_triggers = |archive: &mut Archive, data: Vec<u8>| {
+      archive
+        .create_file(CreateFileOptions {
+          path: MpqPath::new("war3map.wtg"),
+          data: &data,
+          flags: CreateFileFlags::MPQ_FILE_COMPRESS | CreateFileFlags::MPQ_FILE_REPLACEEXISTING,
+          mtime: 0,
+          compression: CompressionFlags::MPQ_COMPRESSION_ZLIB,
+        })
+        .unwrap()
+    };
+    write_triggers(&mut archive, [&triggers[..], b"TRIGSTR_004\0"].concat());
+    assert_eq!(archive.inline_trigger_strings().unwrap(), 9);
+    assert!(archive.has_file(STRINGS_FILE).unwrap());
+    assert_eq!(archive.map_info().unwrap().name, b"Small Wars");
+    let mut script = archive.open_file("war3map.j").unwrap();
+    assert!(references(&script.read_all().unwrap(), ReferenceKind::Script).is_empty());
+    assert_eq!(script.read_raw().unwrap().flags(), flags);
+    drop(script);
+
+    write_triggers(&mut archive, triggers);
+    assert_eq!(archive.inline_trigger_strings().unwrap(), 0);
+    assert!(!archive.has_file(STRINGS_FILE).unwrap());
+    archive.close().unwrap();
+  });
+
+  // Clean up
+  let _ = fs::remove_file(path);
+
+  // Propagate any panic that occurred during the test
+  result.unwrap();
+}